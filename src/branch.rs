@@ -0,0 +1,78 @@
+use crate::u64_from_be_slice;
+
+use sled::IVec;
+
+/// A named pointer to a version, like a git branch.
+///
+/// Whenever a child snapshot is created from the tip of the checked-out branch and becomes the current version, the branch
+/// advances to the child. If the tip is deleted, the branch falls back to the tip's parent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Branch {
+    pub name: String,
+    pub tip: u64,
+}
+
+/// Each branch is encoded as its tip, the length of its name in bytes, and then the bytes of the name.
+pub(crate) fn encode_branches(branches: &[Branch]) -> IVec {
+    let mut bytes = Vec::new();
+    for branch in branches {
+        bytes.extend_from_slice(&branch.tip.to_be_bytes());
+        bytes.extend_from_slice(&(branch.name.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(branch.name.as_bytes());
+    }
+    IVec::from(bytes)
+}
+
+/// A truncated record keeps the branches that can still be read, and a name whose length runs past the end is cut short.
+/// Invalid UTF-8 in a name is replaced rather than failing the read, so a corrupt branch can't hide the rest.
+pub(crate) fn decode_branches(mut bytes: &[u8]) -> Vec<Branch> {
+    let mut branches = Vec::new();
+    while bytes.len() >= 16 {
+        let tip = u64_from_be_slice(&bytes[..8]);
+        let len = (u64_from_be_slice(&bytes[8..16]) as usize).min(bytes.len() - 16);
+        let name = String::from_utf8_lossy(&bytes[16..16 + len]).into_owned();
+        branches.push(Branch { name, tip });
+        bytes = &bytes[16 + len..];
+    }
+    branches
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_branches_survives_corruption() {
+        let branches = vec![branch("a", 1), branch("bc", 2)];
+        let mut bytes = encode_branches(&branches).to_vec();
+        assert_eq!(decode_branches(&bytes), branches);
+
+        // The only byte of the first name.
+        bytes[16] = 0xff;
+        assert_eq!(
+            decode_branches(&bytes),
+            vec![branch("\u{fffd}", 1), branch("bc", 2)]
+        );
+
+        // The second name claims to be longer than the record.
+        bytes[25..33].copy_from_slice(&100u64.to_be_bytes());
+        assert_eq!(
+            decode_branches(&bytes[..bytes.len() - 1]),
+            vec![branch("\u{fffd}", 1), branch("b", 2)]
+        );
+        assert_eq!(decode_branches(&bytes[..30]), vec![branch("\u{fffd}", 1)]);
+    }
+
+    fn branch(name: &str, tip: u64) -> Branch {
+        Branch {
+            name: name.to_owned(),
+            tip,
+        }
+    }
+}
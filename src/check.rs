@@ -0,0 +1,633 @@
+use crate::{
+    delta_node::{RawDeltaNode, RawHeadDeltaNode},
+    u64_from_be_slice,
+    version_node::{RawVersionNode, VersionNode},
+    DeltaMap, VersionForest,
+};
+
+use sled::IVec;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem;
+
+/// A violation of one of the invariants that a snapshot forest relies on, found by [`check_forest`].
+///
+/// Delta list nodes are identified by their key in the [`DeltaMap`]. The head node of a version's delta list is keyed by the
+/// version itself, so `node == version` refers to the head.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Inconsistency {
+    /// The version node can't be decoded.
+    MalformedVersionNode(u64),
+    /// `parent` lists `child` as a child, but `child` doesn't exist.
+    DanglingChildPointer { parent: u64, child: u64 },
+    /// `parent` lists `child` as a child, but the parent pointer of `child` doesn't point back to `parent`.
+    UnmatchedChildPointer { parent: u64, child: u64 },
+    /// The parent pointer of `version` refers to a version that doesn't exist.
+    DanglingParentPointer { version: u64, parent: u64 },
+    /// The parent pointer of `version` refers to `parent`, but `parent` doesn't list `version` as a child.
+    UnmatchedParentPointer { version: u64, parent: u64 },
+    /// The version claims to be the root of its tree, but it has a parent.
+    RootHasParent { version: u64, parent: u64 },
+    /// The root stored in the version node is not the root found by following parent pointers.
+    WrongRoot {
+        version: u64,
+        root: u64,
+        expected: u64,
+    },
+    /// Following parent pointers from the version never reaches a root.
+    ParentCycle(u64),
+    /// The tree has no current version.
+    MissingCurrentVersion { root: u64 },
+    /// The current version record of the tree rooted at `root` can't be decoded.
+    MalformedCurrentVersion { root: u64 },
+    /// The current version of the tree rooted at `root` is not a version in that tree.
+    InvalidCurrentVersion { root: u64, current: u64 },
+    /// There is a current version record for a version that is not the root of a tree.
+    StaleCurrentVersion { root: u64 },
+    /// The tree has no valid current version record, and it doesn't have exactly one version without a delta list that could
+    /// take its place.
+    AmbiguousCurrentVersion { root: u64, candidates: Vec<u64> },
+    /// The version is not the current version of its tree, but it has no delta list.
+    MissingDeltaList(u64),
+    /// The version is the current version of its tree, but it has a delta list.
+    UnexpectedDeltaList(u64),
+    /// A node in the delta list of `version` can't be decoded.
+    MalformedDeltaNode { version: u64, node: u64 },
+    /// A node in the delta list of `version` points to `next_key`, which is not a delta list node.
+    DanglingNextKey {
+        version: u64,
+        node: u64,
+        next_key: u64,
+    },
+    /// A node in the delta list of `version` points back to `next_key`, which is already in the list.
+    DeltaListCycle {
+        version: u64,
+        node: u64,
+        next_key: u64,
+    },
+    /// A node in the delta list of `version` points to `next_key`, which is already in the delta list of `owner`.
+    SharedDeltaNode {
+        version: u64,
+        node: u64,
+        next_key: u64,
+        owner: u64,
+    },
+    /// The tail key in the head of the delta list of `version` is not the key of the last node.
+    WrongTailKey {
+        version: u64,
+        tail_key: Option<u64>,
+        last_node: Option<u64>,
+    },
+    /// The delta list node is not in the delta list of any version.
+    OrphanedDeltaNode(u64),
+    /// The key in the [`DeltaMap`] is neither a version nor a delta list node.
+    MalformedDeltaMapKey(IVec),
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedVersionNode(v) => write!(f, "version node {} is malformed", v),
+            Self::DanglingChildPointer { parent, child } => write!(
+                f,
+                "version {} lists child {}, which does not exist",
+                parent, child
+            ),
+            Self::UnmatchedChildPointer { parent, child } => write!(
+                f,
+                "version {} lists child {}, whose parent is a different version",
+                parent, child
+            ),
+            Self::DanglingParentPointer { version, parent } => write!(
+                f,
+                "version {} has parent {}, which does not exist",
+                version, parent
+            ),
+            Self::UnmatchedParentPointer { version, parent } => write!(
+                f,
+                "version {} has parent {}, which does not list it as a child",
+                version, parent
+            ),
+            Self::RootHasParent { version, parent } => write!(
+                f,
+                "root version {} has parent {}",
+                version, parent
+            ),
+            Self::WrongRoot {
+                version,
+                root,
+                expected,
+            } => write!(
+                f,
+                "version {} has root {}, but its tree is rooted at {}",
+                version, root, expected
+            ),
+            Self::ParentCycle(v) => write!(f, "the ancestors of version {} form a cycle", v),
+            Self::MissingCurrentVersion { root } => {
+                write!(f, "the tree rooted at {} has no current version", root)
+            }
+            Self::MalformedCurrentVersion { root } => write!(
+                f,
+                "the current version record of the tree rooted at {} is malformed",
+                root
+            ),
+            Self::InvalidCurrentVersion { root, current } => write!(
+                f,
+                "the current version {} of the tree rooted at {} is not in that tree",
+                current, root
+            ),
+            Self::StaleCurrentVersion { root } => write!(
+                f,
+                "version {} has a current version but is not a root",
+                root
+            ),
+            Self::AmbiguousCurrentVersion { root, candidates } => write!(
+                f,
+                "the tree rooted at {} has no current version, and versions {:?} have no delta list",
+                root, candidates
+            ),
+            Self::MissingDeltaList(v) => write!(f, "version {} has no delta list", v),
+            Self::UnexpectedDeltaList(v) => {
+                write!(f, "current version {} has a delta list", v)
+            }
+            Self::MalformedDeltaNode { version, node } => write!(
+                f,
+                "node {} in the delta list of version {} is malformed",
+                node, version
+            ),
+            Self::DanglingNextKey {
+                version,
+                node,
+                next_key,
+            } => write!(
+                f,
+                "node {} in the delta list of version {} points to missing node {}",
+                node, version, next_key
+            ),
+            Self::DeltaListCycle {
+                version,
+                node,
+                next_key,
+            } => write!(
+                f,
+                "node {} in the delta list of version {} points back to node {}",
+                node, version, next_key
+            ),
+            Self::SharedDeltaNode {
+                version,
+                node,
+                next_key,
+                owner,
+            } => write!(
+                f,
+                "node {} in the delta list of version {} points to node {} in the delta list of version {}",
+                node, version, next_key, owner
+            ),
+            Self::WrongTailKey {
+                version,
+                tail_key,
+                last_node,
+            } => write!(
+                f,
+                "the delta list of version {} has tail {:?}, but its last node is {:?}",
+                version, tail_key, last_node
+            ),
+            Self::OrphanedDeltaNode(k) => {
+                write!(f, "delta list node {} is not in any delta list", k)
+            }
+            Self::MalformedDeltaMapKey(k) => write!(f, "delta map key {:?} is malformed", k),
+        }
+    }
+}
+
+/// The result of [`check_forest`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ForestReport {
+    /// The number of versions in the forest.
+    pub num_versions: usize,
+    /// The number of delta list nodes reachable from the heads of the delta lists.
+    pub num_delta_nodes: usize,
+    /// Every problem that was found, ordered by the kind of check that found it, then by version.
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl ForestReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+/// Checks that `forest` and `delta_map` satisfy the invariants that all operations on a snapshot forest rely on, and reports
+/// every violation that it finds. Nothing is modified.
+///
+/// The version nodes must form trees with matching parent and child pointers, and each tree must have a current version which
+/// is the only version without a delta list. Each delta list must end without a cycle, its head must know its last node, and
+/// every other key in the `DeltaMap` must be a node of exactly one delta list. Operations that find one of these invariants
+/// broken will panic, so this is worth running on a forest that might have been corrupted, e.g. by a bug or by writing to
+/// the trees directly.
+///
+/// `sled` can't scan trees in a transaction, so the forest must not be modified while it is being checked, or the report may
+/// contain false alarms.
+pub fn check_forest(forest: &VersionForest, delta_map: &DeltaMap) -> sled::Result<ForestReport> {
+    let mut inconsistencies = Vec::new();
+
+    let mut nodes = BTreeMap::new();
+    let mut versions = BTreeSet::new();
+    for kv in forest.iter_version_nodes() {
+        let (version, bytes) = kv?;
+        versions.insert(version);
+        let raw_node = RawVersionNode::new(bytes);
+        if raw_node.is_well_formed() {
+            nodes.insert(version, VersionNode::from(raw_node));
+        } else {
+            inconsistencies.push(Inconsistency::MalformedVersionNode(version));
+        }
+    }
+
+    // Every pointer between a parent and a child needs to go both ways. Pointers to malformed nodes were already reported.
+    for (&version, node) in nodes.iter() {
+        for &child in node.children.iter() {
+            match nodes.get(&child) {
+                Some(child_node) if child_node.parent != Some(version) => {
+                    inconsistencies.push(Inconsistency::UnmatchedChildPointer {
+                        parent: version,
+                        child,
+                    });
+                }
+                None if !versions.contains(&child) => {
+                    inconsistencies.push(Inconsistency::DanglingChildPointer {
+                        parent: version,
+                        child,
+                    });
+                }
+                _ => (),
+            }
+        }
+        if let Some(parent) = node.parent {
+            match nodes.get(&parent) {
+                Some(parent_node) if !parent_node.children.contains(&version) => {
+                    inconsistencies.push(Inconsistency::UnmatchedParentPointer { version, parent });
+                }
+                None if !versions.contains(&parent) => {
+                    inconsistencies.push(Inconsistency::DanglingParentPointer { version, parent });
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let roots: BTreeSet<u64> = nodes
+        .iter()
+        .filter(|(_, node)| node.parent.is_none())
+        .map(|(&version, _)| version)
+        .collect();
+    let tree_roots = find_tree_roots(&nodes, &mut inconsistencies);
+
+    let mut current_versions = BTreeMap::new();
+    let mut recorded_roots = BTreeSet::new();
+    for record in forest.iter_current_versions() {
+        let (root, current) = record?;
+        recorded_roots.insert(root);
+        match current {
+            _ if !roots.contains(&root) => {
+                inconsistencies.push(Inconsistency::StaleCurrentVersion { root });
+            }
+            None => inconsistencies.push(Inconsistency::MalformedCurrentVersion { root }),
+            Some(current) if tree_roots.get(&current) != Some(&root) => {
+                inconsistencies.push(Inconsistency::InvalidCurrentVersion { root, current });
+            }
+            Some(current) => {
+                current_versions.insert(root, current);
+            }
+        }
+    }
+    for &root in roots.difference(&recorded_roots) {
+        inconsistencies.push(Inconsistency::MissingCurrentVersion { root });
+    }
+
+    let mut owners = BTreeMap::new();
+    let mut versions_without_deltas: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    for &version in versions.iter() {
+        let head = if let Some(head) = delta_map.get(version.to_be_bytes())? {
+            RawHeadDeltaNode::new(head)
+        } else {
+            if let Some(&root) = tree_roots.get(&version) {
+                versions_without_deltas
+                    .entry(root)
+                    .or_default()
+                    .push(version);
+            }
+            continue;
+        };
+        if !head.is_well_formed() {
+            inconsistencies.push(Inconsistency::MalformedDeltaNode {
+                version,
+                node: version,
+            });
+            continue;
+        }
+        let walk = walk_delta_list(version, &head, &versions, delta_map, &mut owners)?;
+        if let Some(broken) = walk.broken {
+            inconsistencies.push(broken);
+        } else if head.tail_key() != walk.nodes.last().copied() {
+            inconsistencies.push(Inconsistency::WrongTailKey {
+                version,
+                tail_key: head.tail_key(),
+                last_node: walk.nodes.last().copied(),
+            });
+        }
+    }
+
+    // Each tree needs exactly one version without deltas, which must be the current version.
+    for (root, candidates) in versions_without_deltas.iter() {
+        if let Some(current) = current_versions.get(root) {
+            for &version in candidates.iter().filter(|&v| v != current) {
+                inconsistencies.push(Inconsistency::MissingDeltaList(version));
+            }
+        } else if roots.contains(root) && candidates.len() != 1 {
+            inconsistencies.push(Inconsistency::AmbiguousCurrentVersion {
+                root: *root,
+                candidates: candidates.clone(),
+            });
+        } else if !roots.contains(root) {
+            for &version in candidates.iter() {
+                inconsistencies.push(Inconsistency::MissingDeltaList(version));
+            }
+        }
+    }
+    for (root, current) in current_versions.iter() {
+        let has_no_deltas = matches!(
+            versions_without_deltas.get(root),
+            Some(candidates) if candidates.contains(current)
+        );
+        if !has_no_deltas {
+            inconsistencies.push(Inconsistency::UnexpectedDeltaList(*current));
+        }
+    }
+
+    let mut num_delta_nodes = 0;
+    for key in delta_map.iter().keys() {
+        let key = key?;
+        if key.len() != mem::size_of::<u64>() {
+            inconsistencies.push(Inconsistency::MalformedDeltaMapKey(key));
+            continue;
+        }
+        let key = u64_from_be_slice(&key);
+        if versions.contains(&key) {
+            continue;
+        }
+        if owners.contains_key(&key) {
+            num_delta_nodes += 1;
+        } else {
+            inconsistencies.push(Inconsistency::OrphanedDeltaNode(key));
+        }
+    }
+
+    Ok(ForestReport {
+        num_versions: versions.len(),
+        num_delta_nodes,
+        inconsistencies,
+    })
+}
+
+/// Finds the root of the tree containing each version by following parent pointers, and reports versions whose stored root
+/// disagrees. If no root can be reached, the stored root is used instead.
+fn find_tree_roots(
+    nodes: &BTreeMap<u64, VersionNode>,
+    inconsistencies: &mut Vec<Inconsistency>,
+) -> BTreeMap<u64, u64> {
+    let mut tree_roots = BTreeMap::new();
+    for (&version, node) in nodes.iter() {
+        let mut visited = BTreeSet::new();
+        let mut ancestor = version;
+        let found_root = loop {
+            if !visited.insert(ancestor) {
+                inconsistencies.push(Inconsistency::ParentCycle(version));
+                break None;
+            }
+            match nodes.get(&ancestor).map(|node| node.parent) {
+                Some(Some(parent)) => ancestor = parent,
+                Some(None) => break Some(ancestor),
+                // Dangling and malformed parents were already reported.
+                None => break None,
+            }
+        };
+
+        if let (Some(parent), true) = (node.parent, node.root == version) {
+            inconsistencies.push(Inconsistency::RootHasParent { version, parent });
+        } else if let Some(expected) = found_root.filter(|&root| root != node.root) {
+            inconsistencies.push(Inconsistency::WrongRoot {
+                version,
+                root: node.root,
+                expected,
+            });
+        }
+        tree_roots.insert(version, found_root.unwrap_or(node.root));
+    }
+    tree_roots
+}
+
+/// The result of following a delta list from its head.
+pub(crate) struct DeltaListWalk {
+    /// The keys of the list nodes that were read, in order.
+    pub nodes: Vec<u64>,
+    /// Why the list ended after the last node in `nodes`, if it didn't end properly.
+    pub broken: Option<Inconsistency>,
+    /// The key that the last node in `nodes` points to, if the list didn't end properly.
+    pub unfollowed_key: Option<u64>,
+}
+
+/// Follows the delta list of `version` until it ends or a node can't be read.
+///
+/// `owners` maps every node visited by previous walks to the version whose list it belongs to, and the nodes of this list are
+/// added to it. A node that is already in `owners` is never followed again.
+pub(crate) fn walk_delta_list(
+    version: u64,
+    head: &RawHeadDeltaNode<IVec>,
+    versions: &BTreeSet<u64>,
+    delta_map: &DeltaMap,
+    owners: &mut BTreeMap<u64, u64>,
+) -> sled::Result<DeltaListWalk> {
+    let mut nodes = Vec::new();
+    let mut maybe_next_key = head.next_key();
+    while let Some(next_key) = maybe_next_key {
+        let node = nodes.last().copied().unwrap_or(version);
+        let broken = if let Some(&owner) = owners.get(&next_key) {
+            if owner == version {
+                Inconsistency::DeltaListCycle {
+                    version,
+                    node,
+                    next_key,
+                }
+            } else {
+                Inconsistency::SharedDeltaNode {
+                    version,
+                    node,
+                    next_key,
+                    owner,
+                }
+            }
+        } else if let (false, Some(bytes)) = (
+            versions.contains(&next_key),
+            delta_map.get(next_key.to_be_bytes())?,
+        ) {
+            // Malformed nodes belong to this list too, even though they can't be followed.
+            owners.insert(next_key, version);
+            let raw_node = RawDeltaNode::new(bytes);
+            if raw_node.is_well_formed() {
+                nodes.push(next_key);
+                maybe_next_key = raw_node.next_key();
+                continue;
+            }
+            Inconsistency::MalformedDeltaNode {
+                version,
+                node: next_key,
+            }
+        } else {
+            Inconsistency::DanglingNextKey {
+                version,
+                node,
+                next_key,
+            }
+        };
+        return Ok(DeltaListWalk {
+            nodes,
+            broken: Some(broken),
+            unfollowed_key: Some(next_key),
+        });
+    }
+
+    Ok(DeltaListWalk {
+        nodes,
+        broken: None,
+        unfollowed_key: None,
+    })
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        delta_node::HeadDeltaNode, test_util::Fixture, version_forest::current_version_key, Delta,
+    };
+
+    #[test]
+    fn check_finds_corruption() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+        for i in 0..3u8 {
+            tree.commit(&[Delta::Insert(IVec::from(&[i]), IVec::from(b"value"))])
+                .unwrap();
+        }
+        let report = check_forest(tree.forest(), tree.delta_map()).unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.num_versions, 4);
+        assert_eq!(report.num_delta_nodes, 3);
+
+        let versions = tree.forest().collect_versions().unwrap();
+        let (v1, v2) = (versions[1], versions[2]);
+        let list_node = |version: u64| {
+            let head = tree.delta_map().get(version.to_be_bytes()).unwrap();
+            RawHeadDeltaNode::new(head.unwrap()).next_key().unwrap()
+        };
+        let (root_node, v1_node, v2_node) = (list_node(root), list_node(v1), list_node(v2));
+
+        // The root forgets its child.
+        let mut node = VersionNode::new_orphan(root);
+        tree.forest()
+            .insert(root.to_be_bytes(), IVec::from(&node))
+            .unwrap();
+        // The delta list of the root points to a missing node.
+        let missing = u64::MAX - 1;
+        tree.delta_map()
+            .insert(
+                root.to_be_bytes(),
+                IVec::from(&HeadDeltaNode::new(missing, missing)),
+            )
+            .unwrap();
+        // The head of v1 has the wrong tail.
+        tree.delta_map()
+            .insert(
+                v1.to_be_bytes(),
+                IVec::from(&HeadDeltaNode::new(v1_node, missing)),
+            )
+            .unwrap();
+        // The only node of v2 points to itself.
+        let mut raw_node = RawDeltaNode::new(
+            tree.delta_map()
+                .get(v2_node.to_be_bytes())
+                .unwrap()
+                .unwrap()
+                .to_vec(),
+        );
+        raw_node.set_next_key(Some(v2_node));
+        tree.delta_map()
+            .insert(v2_node.to_be_bytes(), raw_node.take_bytes())
+            .unwrap();
+
+        let report = check_forest(tree.forest(), tree.delta_map()).unwrap();
+        assert_eq!(
+            report.inconsistencies,
+            vec![
+                Inconsistency::UnmatchedParentPointer {
+                    version: v1,
+                    parent: root
+                },
+                Inconsistency::DanglingNextKey {
+                    version: root,
+                    node: root,
+                    next_key: missing
+                },
+                Inconsistency::WrongTailKey {
+                    version: v1,
+                    tail_key: Some(missing),
+                    last_node: Some(v1_node)
+                },
+                Inconsistency::DeltaListCycle {
+                    version: v2,
+                    node: v2_node,
+                    next_key: v2_node
+                },
+                Inconsistency::OrphanedDeltaNode(root_node),
+            ]
+        );
+
+        // A root with a parent is reported as such.
+        node.parent = Some(v2);
+        tree.forest()
+            .insert(root.to_be_bytes(), IVec::from(&node))
+            .unwrap();
+        let report = check_forest(tree.forest(), tree.delta_map()).unwrap();
+        assert!(report
+            .inconsistencies
+            .contains(&Inconsistency::RootHasParent {
+                version: root,
+                parent: v2
+            }));
+    }
+
+    #[test]
+    fn check_reports_malformed_current_version() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+        tree.forest()
+            .insert(current_version_key(root), b"bad")
+            .unwrap();
+
+        let report = check_forest(tree.forest(), tree.delta_map()).unwrap();
+        assert_eq!(
+            report.inconsistencies,
+            vec![Inconsistency::MalformedCurrentVersion { root }]
+        );
+    }
+}
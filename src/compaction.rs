@@ -0,0 +1,196 @@
+use crate::{
+    transactions::compact_version, DeltaMap, SnapshotError, TransactionalDeltaMap, VersionForest,
+};
+
+use sled::transaction::TransactionResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Compacts the delta list of every version in the forest, as if by [`compact_version`]. Returns how many versions changed.
+///
+/// Each version is compacted in its own transaction, so this never holds up other writers for long. See
+/// [`compact_incremental`] to spread the work out even more.
+pub fn compact_all(
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+) -> TransactionResult<usize, SnapshotError> {
+    let (_cursor, num_compacted) = compact_incremental(forest, delta_map, None, usize::MAX)?;
+    Ok(num_compacted)
+}
+
+/// Compacts the delta lists of up to `limit` versions, starting after `cursor`, or from the beginning if `cursor` is `None`.
+///
+/// Returns the cursor to pass to the next call, or `None` once every version has been visited, along with how many versions
+/// changed.
+pub fn compact_incremental(
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    cursor: Option<u64>,
+    limit: usize,
+) -> TransactionResult<(Option<u64>, usize), SnapshotError> {
+    let mut versions: Box<dyn Iterator<Item = sled::Result<u64>>> = match cursor {
+        Some(cursor) => Box::new(forest.iter_versions_after(cursor)),
+        None => Box::new(forest.iter_versions()),
+    };
+
+    let mut last_visited = None;
+    let mut num_compacted = 0;
+    for _ in 0..limit {
+        let version = if let Some(version) = versions.next() {
+            version?
+        } else {
+            return Ok((None, num_compacted));
+        };
+        if delta_map
+            .transaction(|delta_map| compact_version(version, TransactionalDeltaMap(delta_map)))?
+        {
+            num_compacted += 1;
+        }
+        last_visited = Some(version);
+    }
+
+    Ok((last_visited, num_compacted))
+}
+
+/// Compacts the forest in a background thread, a few versions at a time.
+///
+/// After each pass over the whole forest, the thread sleeps for a while before starting over. The thread stops when the
+/// `BackgroundCompactor` is stopped or dropped.
+pub struct BackgroundCompactor {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<TransactionResult<(), SnapshotError>>>,
+}
+
+impl BackgroundCompactor {
+    /// Starts compacting `batch_size` versions per step, sleeping for `pause` between passes over the forest.
+    pub fn spawn(
+        forest: VersionForest,
+        delta_map: DeltaMap,
+        batch_size: usize,
+        pause: Duration,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut cursor = None;
+            while !thread_stop.load(Ordering::Acquire) {
+                cursor = compact_incremental(&forest, &delta_map, cursor, batch_size)?.0;
+                if cursor.is_none() {
+                    thread::park_timeout(pause);
+                }
+            }
+            Ok(())
+        });
+
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops the thread after its current step and waits for it. Returns the error that stopped the thread early, if any.
+    ///
+    /// # Panics
+    /// If the thread panicked.
+    pub fn stop(mut self) -> TransactionResult<(), SnapshotError> {
+        self.stop_and_join()
+    }
+
+    fn stop_and_join(&mut self) -> TransactionResult<(), SnapshotError> {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            thread.join().expect("Compactor thread panicked")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for BackgroundCompactor {
+    fn drop(&mut self) {
+        let _ = self.stop_and_join();
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        test_util::Fixture, transactions::modify_leaf_snapshot, Delta, SnapshotTree,
+        TransactionalVersionForest,
+    };
+
+    use sled::{IVec, Transactional};
+    use std::time::Instant;
+
+    #[test]
+    fn compact_all_merges_nodes_and_keeps_state() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let leaf = create_fragmented_leaf(&tree);
+        let (forest, delta_map) = (tree.forest(), tree.delta_map());
+
+        // The leaf has a head and 3 list nodes.
+        assert_eq!(delta_map.len(), 4);
+        assert_eq!(compact_all(forest, delta_map), Ok(1));
+        assert_eq!(delta_map.len(), 2);
+        // Already compact.
+        assert_eq!(compact_all(forest, delta_map), Ok(0));
+
+        tree.checkout(leaf).unwrap();
+        assert_eq!(
+            tree.data_tree()
+                .iter()
+                .collect::<sled::Result<Vec<_>>>()
+                .unwrap(),
+            vec![(IVec::from(b"key1"), IVec::from(b"2"))]
+        );
+    }
+
+    #[test]
+    fn background_compactor() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        create_fragmented_leaf(&tree);
+
+        let compactor = BackgroundCompactor::spawn(
+            tree.forest().clone(),
+            tree.delta_map().clone(),
+            1,
+            Duration::from_millis(1),
+        );
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while tree.delta_map().len() > 2 {
+            assert!(Instant::now() < deadline, "Compactor made no progress");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(compactor.stop(), Ok(()));
+    }
+
+    /// Creates a non-current leaf with one list node per modification.
+    fn create_fragmented_leaf(tree: &SnapshotTree) -> u64 {
+        let leaf = tree.branch(tree.root()).unwrap();
+        for value in [&b"0"[..], b"1", b"2"] {
+            (&**tree.forest(), &**tree.delta_map())
+                .transaction(|(forest, delta_map)| {
+                    modify_leaf_snapshot(
+                        leaf,
+                        TransactionalVersionForest(forest),
+                        TransactionalDeltaMap(delta_map),
+                        &[Delta::Insert(&b"key1"[..], value)],
+                    )
+                })
+                .unwrap();
+        }
+        leaf
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{u64_from_be_slice, usize_from_be_slice, SnapshotError};
+
+use sled::{
+    transaction::{abort, ConflictableTransactionResult, TransactionalTree},
+    IVec,
+};
+
+/// The data versioned by a snapshot tree, as seen from inside a transaction.
+///
+/// This is usually a single [`TransactionalTree`], but [`TransactionalDataTrees`] lets one snapshot tree version several
+/// trees at once. Implementations may abort the transaction, e.g. if `key` doesn't name one of their member trees.
+pub trait DataTree {
+    /// Returns the value of `key`, if any.
+    fn get(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError>;
+
+    /// Inserts `(key, value)`, returning the old value of `key`, if any.
+    fn insert(
+        &self,
+        key: &[u8],
+        value: IVec,
+    ) -> ConflictableTransactionResult<Option<IVec>, SnapshotError>;
+
+    /// Removes `key`, returning its old value, if any.
+    fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError>;
+}
+
+impl DataTree for TransactionalTree {
+    fn get(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        Ok(TransactionalTree::get(self, key)?)
+    }
+
+    fn insert(
+        &self,
+        key: &[u8],
+        value: IVec,
+    ) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        Ok(TransactionalTree::insert(self, key, value)?)
+    }
+
+    fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        Ok(TransactionalTree::remove(self, key)?)
+    }
+}
+
+/// A set of data trees which are versioned together, so that every snapshot covers all of them.
+///
+/// Each member tree is identified by its index in the slice. Deltas and other keys passed to the [`transactions`] functions
+/// are prefixed with that index (see [`TransactionalDataTrees::key`]), so the members must always be given in the same order.
+/// A key without a valid index aborts the transaction with [`SnapshotError::InvalidDataTreeKey`].
+///
+/// Since `sled` implements [`sled::Transactional`] for `[&Tree]`, the easiest way to get a set of [`TransactionalTree`]s is to
+/// run the transaction over a slice containing the forest, the delta map and then all of the data trees.
+///
+/// [`transactions`]: crate::transactions
+#[derive(Clone, Copy)]
+pub struct TransactionalDataTrees<'a>(pub &'a [TransactionalTree]);
+
+impl<'a> TransactionalDataTrees<'a> {
+    /// Returns the key used in deltas for `key` in the member tree at `index`.
+    pub fn key(index: usize, key: &[u8]) -> IVec {
+        let mut tagged = Vec::with_capacity(8 + key.len());
+        tagged.extend_from_slice(&(index as u64).to_be_bytes());
+        tagged.extend_from_slice(key);
+        IVec::from(tagged)
+    }
+
+    /// The inverse of [`TransactionalDataTrees::key`], returning the index of the member tree and the key within it.
+    ///
+    /// # Panics
+    /// If `tagged_key` is shorter than the 8-byte index prefix.
+    pub fn split_key(tagged_key: &[u8]) -> (usize, &[u8]) {
+        let (index, key) = tagged_key.split_at(8);
+        (usize_from_be_slice(index), key)
+    }
+
+    /// Aborts the transaction if `tagged_key` doesn't start with the index of a member tree.
+    fn member<'k>(
+        &self,
+        tagged_key: &'k [u8],
+    ) -> ConflictableTransactionResult<(&'a TransactionalTree, &'k [u8]), SnapshotError> {
+        if tagged_key.len() < 8 || u64_from_be_slice(&tagged_key[..8]) >= self.0.len() as u64 {
+            return abort(SnapshotError::InvalidDataTreeKey(IVec::from(tagged_key)));
+        }
+        let (index, key) = Self::split_key(tagged_key);
+        Ok((&self.0[index], key))
+    }
+}
+
+impl<'a> DataTree for TransactionalDataTrees<'a> {
+    fn get(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        let (tree, key) = self.member(key)?;
+        Ok(tree.get(key)?)
+    }
+
+    fn insert(
+        &self,
+        key: &[u8],
+        value: IVec,
+    ) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        let (tree, key) = self.member(key)?;
+        Ok(tree.insert(key, value)?)
+    }
+
+    fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        let (tree, key) = self.member(key)?;
+        Ok(tree.remove(key)?)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        open_snapshot_forest, test_util::Fixture, transactions::*, Delta, SnapshotError,
+        TransactionalDeltaMap, TransactionalVersionForest,
+    };
+
+    use sled::{
+        transaction::{ConflictableTransactionResult, TransactionError, TransactionResult},
+        Transactional, Tree,
+    };
+
+    #[test]
+    fn snapshots_cover_all_member_trees() {
+        let fixture = Fixture::open();
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let docs = fixture.db.open_tree("docs").unwrap();
+        let index = fixture.db.open_tree("index").unwrap();
+        docs.insert(b"doc0", b"red").unwrap();
+        index.insert(b"red", b"doc0").unwrap();
+        let trees = [&*forest, &*delta_map, &docs, &index];
+
+        let (v0, v1) = transaction(&trees, |forest, delta_map, data_trees| {
+            let v0 = create_snapshot_tree(forest)?;
+            let deltas = [
+                Delta::Insert(TransactionalDataTrees::key(0, b"doc0"), IVec::from(b"blue")),
+                Delta::Remove(TransactionalDataTrees::key(1, b"red")),
+                Delta::Insert(TransactionalDataTrees::key(1, b"blue"), IVec::from(b"doc0")),
+            ];
+            let v1 =
+                create_child_snapshot_with_deltas(v0, forest, delta_map, &data_trees, &deltas)?;
+            Ok((v0, v1))
+        })
+        .unwrap();
+        assert_contents(&docs, &[(b"doc0", b"blue")]);
+        assert_contents(&index, &[(b"blue", b"doc0")]);
+
+        transaction(&trees, |forest, delta_map, data_trees| {
+            set_current_version(v1, v0, forest, delta_map, &data_trees)
+        })
+        .unwrap();
+        assert_contents(&docs, &[(b"doc0", b"red")]);
+        assert_contents(&index, &[(b"red", b"doc0")]);
+
+        let diff = transaction(&trees, |forest, delta_map, data_trees| {
+            diff_versions(v0, v1, forest, delta_map, &data_trees)
+        })
+        .unwrap();
+        let diff: Vec<_> = diff
+            .iter()
+            .map(|delta| TransactionalDataTrees::split_key(delta.key()))
+            .collect();
+        assert_eq!(
+            diff,
+            vec![(0, &b"doc0"[..]), (1, &b"blue"[..]), (1, &b"red"[..])]
+        );
+    }
+
+    #[test]
+    fn invalid_member_keys_abort() {
+        let fixture = Fixture::open();
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let docs = fixture.db.open_tree("docs").unwrap();
+        let trees = [&*forest, &*delta_map, &docs];
+        let v0 = transaction(&trees, |forest, _delta_map, _data_trees| {
+            create_snapshot_tree(forest)
+        })
+        .unwrap();
+
+        let short_key = IVec::from(b"doc0");
+        let missing_member_key = TransactionalDataTrees::key(1, b"doc0");
+        for key in [short_key, missing_member_key] {
+            let result = transaction(&trees, |forest, delta_map, data_trees| {
+                let deltas = [Delta::Insert(key.clone(), IVec::from(b"red"))];
+                create_child_snapshot_with_deltas(v0, forest, delta_map, &data_trees, &deltas)
+            });
+            assert_eq!(
+                result,
+                Err(TransactionError::Abort(SnapshotError::InvalidDataTreeKey(
+                    key
+                )))
+            );
+        }
+        assert_eq!(forest.collect_versions(), Ok(vec![v0]));
+        assert!(docs.is_empty());
+    }
+
+    fn transaction<T>(
+        trees: &[&Tree],
+        f: impl Fn(
+            TransactionalVersionForest,
+            TransactionalDeltaMap,
+            TransactionalDataTrees,
+        ) -> ConflictableTransactionResult<T, SnapshotError>,
+    ) -> TransactionResult<T, SnapshotError> {
+        trees.transaction(|trees| {
+            f(
+                TransactionalVersionForest(&trees[0]),
+                TransactionalDeltaMap(&trees[1]),
+                TransactionalDataTrees(&trees[2..]),
+            )
+        })
+    }
+
+    fn assert_contents(tree: &Tree, expected: &[(&[u8], &[u8])]) {
+        let contents: Vec<_> = tree.iter().map(Result::unwrap).collect();
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(k, v)| (IVec::from(*k), IVec::from(*v)))
+            .collect();
+        assert_eq!(contents, expected);
+    }
+}
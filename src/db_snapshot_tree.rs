@@ -0,0 +1,590 @@
+use crate::{
+    data_tree::DataTree, delta::Delta, open_snapshot_forest, open_tag_map, transactions::*,
+    u64_from_be_slice, DeltaMap, SnapshotError, TagMap, TransactionalDeltaMap,
+    TransactionalVersionForest, VersionForest,
+};
+
+use sled::{
+    transaction::{
+        abort, ConflictableTransactionResult, TransactionError, TransactionResult,
+        TransactionalTree,
+    },
+    Db, IVec, Transactional, Tree,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A single tree in a snapshot forest which versions every tree in a [`sled::Db`], including the creation and removal of
+/// trees.
+///
+/// Trees are addressed by name, so the key of each delta is the name of a tree followed by a key in that tree (see
+/// [`DbSnapshotTree::key`]). Committing to a tree that doesn't exist yet creates it, and [`DbSnapshotTree::drop_tree`] drops
+/// one, so restoring an old version recreates the trees that were dropped since and drops the trees that were created since.
+///
+/// Every tree opened with [`Db::open_tree`] is versioned, except for the trees of the snapshot forest itself and the default
+/// tree of the `Db`. A `Db` should therefore hold only one snapshot forest, with only one `DbSnapshotTree` in it.
+///
+/// # Implementation
+///
+/// The set of trees is versioned like any other data, in a "catalog" tree called `"${name}-catalog"` which maps the name of
+/// each tree to a placeholder record. `sled` can't open or drop trees inside of a transaction, so the trees touched by each
+/// operation are opened before its transaction, and the ones that the transaction removed from the catalog are dropped after it
+/// commits. A tree that still holds keys that were never versioned is kept rather than dropped. This means that, unlike the
+/// other methods in this crate, these methods must not race with each other.
+#[derive(Clone)]
+pub struct DbSnapshotTree {
+    root: u64,
+    db: Db,
+    forest: VersionForest,
+    delta_map: DeltaMap,
+    tags: TagMap,
+    catalog: Tree,
+}
+
+impl DbSnapshotTree {
+    /// Opens the snapshot forest called `name` in `db` and creates a new tree in it. The current contents of all trees in `db`
+    /// become the root version.
+    pub fn create(db: &Db, name: &str) -> TransactionResult<Self, SnapshotError> {
+        let (forest, delta_map) = open_snapshot_forest(db, name)?;
+        let tags = open_tag_map(db, name)?;
+        let catalog = db.open_tree(format!("{}-catalog", name))?;
+        let root = forest
+            .transaction(|forest| create_snapshot_tree(TransactionalVersionForest(forest)))?;
+
+        let this = Self {
+            root,
+            db: db.clone(),
+            forest,
+            delta_map,
+            tags,
+            catalog,
+        };
+        // The root version can have any state, so there's no need for a transaction.
+        this.catalog.clear()?;
+        for tree_name in this.db.tree_names() {
+            if this.is_versioned(&tree_name) {
+                this.catalog.insert(tree_name, CATALOG_RECORD)?;
+            }
+        }
+
+        Ok(this)
+    }
+
+    /// Opens the existing tree rooted at `root` in the snapshot forest called `name` in `db`.
+    ///
+    /// Returns `None` if `root` is not the root of a tree in the forest.
+    pub fn open(db: &Db, name: &str, root: u64) -> sled::Result<Option<Self>> {
+        let (forest, delta_map) = open_snapshot_forest(db, name)?;
+        let tags = open_tag_map(db, name)?;
+        if forest.current_version(root)?.is_none() {
+            return Ok(None);
+        }
+        let catalog = db.open_tree(format!("{}-catalog", name))?;
+
+        Ok(Some(Self {
+            root,
+            db: db.clone(),
+            forest,
+            delta_map,
+            tags,
+            catalog,
+        }))
+    }
+
+    /// Returns the key used in deltas for `key` in the tree called `tree_name`.
+    pub fn key(tree_name: &[u8], key: &[u8]) -> IVec {
+        let mut tagged = Vec::with_capacity(8 + tree_name.len() + key.len());
+        tagged.extend_from_slice(&(tree_name.len() as u64).to_be_bytes());
+        tagged.extend_from_slice(tree_name);
+        tagged.extend_from_slice(key);
+        IVec::from(tagged)
+    }
+
+    /// The inverse of [`DbSnapshotTree::key`], returning the name of the tree and the key within it.
+    ///
+    /// # Panics
+    /// If `tagged_key` is too short to hold the name of a tree.
+    pub fn split_key(tagged_key: &[u8]) -> (&[u8], &[u8]) {
+        Self::checked_split_key(tagged_key).expect("Key is too short to hold the name of a tree")
+    }
+
+    /// Same as [`DbSnapshotTree::split_key`], but returns `None` instead of panicking.
+    fn checked_split_key(tagged_key: &[u8]) -> Option<(&[u8], &[u8])> {
+        if tagged_key.len() < 8 {
+            return None;
+        }
+        let name_len = u64_from_be_slice(&tagged_key[..8]);
+        if name_len > (tagged_key.len() - 8) as u64 {
+            return None;
+        }
+        Some(tagged_key[8..].split_at(name_len as usize))
+    }
+
+    /// The root version of this tree.
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn forest(&self) -> &VersionForest {
+        &self.forest
+    }
+
+    pub fn delta_map(&self) -> &DeltaMap {
+        &self.delta_map
+    }
+
+    /// The [`TagMap`] of the forest, for use with the [`transactions`](crate::transactions) module.
+    pub fn tags(&self) -> &TagMap {
+        &self.tags
+    }
+
+    /// Returns the names of the versioned trees at the current version, in order.
+    pub fn tree_names(&self) -> sled::Result<Vec<IVec>> {
+        self.catalog.iter().keys().collect()
+    }
+
+    /// Returns the current version of this tree.
+    pub fn current_version(&self) -> TransactionResult<u64, SnapshotError> {
+        self.forest
+            .transaction(|forest| current_version(self.root, TransactionalVersionForest(forest)))
+    }
+
+    /// Applies `deltas`, whose keys come from [`DbSnapshotTree::key`], as a new child of the current version, which becomes
+    /// the new current version. Any trees that don't exist yet are created.
+    ///
+    /// Aborts the transaction if a delta refers to a tree that isn't versioned, or its key wasn't made by
+    /// [`DbSnapshotTree::key`].
+    pub fn commit(&self, deltas: &[Delta<IVec>]) -> TransactionResult<u64, SnapshotError> {
+        let mut tree_names = BTreeSet::new();
+        for delta in deltas {
+            let tree_name = if let Some((tree_name, _)) = Self::checked_split_key(delta.key()) {
+                tree_name
+            } else {
+                return Err(TransactionError::Abort(SnapshotError::InvalidDataTreeKey(
+                    delta.key().clone(),
+                )));
+            };
+            if !self.is_versioned(tree_name) {
+                return Err(TransactionError::Abort(SnapshotError::DataTreeNotFound(
+                    IVec::from(tree_name),
+                )));
+            }
+            tree_names.insert(IVec::from(tree_name));
+        }
+
+        self.write_transaction(&tree_names, |forest, delta_map, data_trees| {
+            let mut deltas = deltas.to_vec();
+            for tree_name in &tree_names {
+                if data_trees.catalog.get(tree_name)?.is_none() {
+                    deltas.push(Delta::Insert(
+                        self.catalog_key(tree_name),
+                        IVec::from(CATALOG_RECORD),
+                    ));
+                }
+            }
+            let current = forest.current_version(self.root)?;
+            create_child_snapshot_with_deltas(current, forest, delta_map, &data_trees, &deltas)
+        })
+    }
+
+    /// Removes every key in the tree called `tree_name` and then drops it, as a new child of the current version, which becomes
+    /// the new current version.
+    ///
+    /// Aborts the transaction if there is no such tree at the current version.
+    pub fn drop_tree(&self, tree_name: &[u8]) -> TransactionResult<u64, SnapshotError> {
+        if self.catalog.get(tree_name)?.is_none() {
+            return Err(TransactionError::Abort(SnapshotError::DataTreeNotFound(
+                IVec::from(tree_name),
+            )));
+        }
+
+        let mut deltas = self
+            .db
+            .open_tree(tree_name)?
+            .iter()
+            .keys()
+            .map(|key| key.map(|key| Delta::Remove(Self::key(tree_name, &key))))
+            .collect::<sled::Result<Vec<_>>>()?;
+        deltas.push(Delta::Remove(self.catalog_key(tree_name)));
+
+        let tree_names = BTreeSet::from([IVec::from(tree_name)]);
+        self.write_transaction(&tree_names, |forest, delta_map, data_trees| {
+            if data_trees.catalog.get(tree_name)?.is_none() {
+                return abort(SnapshotError::DataTreeNotFound(IVec::from(tree_name)));
+            }
+            let current = forest.current_version(self.root)?;
+            create_child_snapshot_with_deltas(current, forest, delta_map, &data_trees, &deltas)
+        })
+    }
+
+    /// Restores every tree in the database to its state at `version`, which becomes the current version. Trees that didn't exist
+    /// at `version` are dropped, unless they hold keys that were written without being versioned, and trees that did are
+    /// recreated.
+    ///
+    /// See [`set_current_version`].
+    pub fn checkout(&self, version: u64) -> TransactionResult<(), SnapshotError> {
+        let net_deltas = (&*self.forest, &*self.delta_map).transaction(|(forest, delta_map)| {
+            let forest = TransactionalVersionForest(forest);
+            self.check_contains(version, forest)?;
+            net_deltas_to_version(version, forest, TransactionalDeltaMap(delta_map))
+        })?;
+
+        let catalog_name = self.catalog.name();
+        let mut tree_names = self.tree_names()?.into_iter().collect::<BTreeSet<_>>();
+        for key in net_deltas.keys() {
+            let tree_name = if let Some((tree_name, _)) = Self::checked_split_key(key) {
+                tree_name
+            } else {
+                return Err(TransactionError::Abort(SnapshotError::InvalidDataTreeKey(
+                    key.clone(),
+                )));
+            };
+            if catalog_name != tree_name {
+                tree_names.insert(IVec::from(tree_name));
+            }
+        }
+
+        self.write_transaction(&tree_names, |forest, delta_map, data_trees| {
+            self.check_contains(version, forest)?;
+            let current = forest.current_version(self.root)?;
+            set_current_version(current, version, forest, delta_map, &data_trees)
+        })
+    }
+
+    /// Returns the value of `key` in the tree called `tree_name` in the `version` snapshot, without restoring it. No trees are
+    /// created or dropped.
+    ///
+    /// Aborts the transaction if the tree isn't versioned.
+    ///
+    /// See [`get_at_version`].
+    pub fn get_at_version(
+        &self,
+        version: u64,
+        tree_name: &[u8],
+        key: &[u8],
+    ) -> TransactionResult<Option<IVec>, SnapshotError> {
+        if !self.is_versioned(tree_name) {
+            return Err(TransactionError::Abort(SnapshotError::DataTreeNotFound(
+                IVec::from(tree_name),
+            )));
+        }
+        // Opening a tree that doesn't exist would create it. It has no keys at the current version anyway.
+        let mut tree_names = BTreeSet::new();
+        if self.db.tree_names().iter().any(|name| name == tree_name) {
+            tree_names.insert(IVec::from(tree_name));
+        }
+        self.transaction(&tree_names, |forest, delta_map, data_trees| {
+            self.check_contains(version, forest)?;
+            get_at_version(
+                version,
+                &Self::key(tree_name, key),
+                forest,
+                delta_map,
+                &data_trees,
+            )
+        })
+    }
+
+    /// Same as [`DbSnapshotTree::transaction`], but once the transaction commits, the trees that `f` removed from the catalog
+    /// are dropped. Trees that still hold keys which were never versioned are kept, so their data isn't lost.
+    fn write_transaction<T>(
+        &self,
+        tree_names: &BTreeSet<IVec>,
+        f: impl Fn(
+            TransactionalVersionForest,
+            TransactionalDeltaMap,
+            DbDataTrees,
+        ) -> ConflictableTransactionResult<T, SnapshotError>,
+    ) -> TransactionResult<T, SnapshotError> {
+        let (result, removed_tree_names) =
+            self.transaction(tree_names, |forest, delta_map, data_trees| {
+                let catalog = data_trees.catalog;
+                let mut cataloged = Vec::new();
+                for tree_name in tree_names {
+                    if catalog.get(tree_name)?.is_some() {
+                        cataloged.push(tree_name);
+                    }
+                }
+                let result = f(forest, delta_map, data_trees)?;
+                let mut removed_tree_names = Vec::new();
+                for tree_name in cataloged {
+                    if catalog.get(tree_name)?.is_none() {
+                        removed_tree_names.push(tree_name.clone());
+                    }
+                }
+                Ok((result, removed_tree_names))
+            })?;
+
+        for tree_name in removed_tree_names {
+            if self.db.open_tree(&tree_name)?.is_empty() {
+                self.db.drop_tree(&tree_name)?;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Runs `f` in a transaction over the forest, the catalog and the trees called `tree_names`. Any of those trees that don't
+    /// exist yet are created, and they are left behind even if the transaction aborts.
+    fn transaction<T>(
+        &self,
+        tree_names: &BTreeSet<IVec>,
+        f: impl Fn(
+            TransactionalVersionForest,
+            TransactionalDeltaMap,
+            DbDataTrees,
+        ) -> ConflictableTransactionResult<T, SnapshotError>,
+    ) -> TransactionResult<T, SnapshotError> {
+        let members = tree_names
+            .iter()
+            .map(|tree_name| self.db.open_tree(tree_name))
+            .collect::<sled::Result<Vec<_>>>()?;
+        let mut trees = vec![&*self.forest, &*self.delta_map, &self.catalog];
+        trees.extend(members.iter());
+
+        let catalog_name = self.catalog.name();
+        trees[..].transaction(|trees| {
+            let data_trees = DbDataTrees {
+                catalog_name: &catalog_name,
+                catalog: &trees[2],
+                members: tree_names.iter().zip(&trees[3..]).collect(),
+            };
+            f(
+                TransactionalVersionForest(&trees[0]),
+                TransactionalDeltaMap(&trees[1]),
+                data_trees,
+            )
+        })
+    }
+
+    fn catalog_key(&self, tree_name: &[u8]) -> IVec {
+        Self::key(&self.catalog.name(), tree_name)
+    }
+
+    fn is_versioned(&self, tree_name: &[u8]) -> bool {
+        ![
+            self.db.name(),
+            self.forest.name(),
+            self.delta_map.name(),
+            self.tags.name(),
+            self.catalog.name(),
+        ]
+        .iter()
+        .any(|reserved| reserved == tree_name)
+    }
+
+    fn check_contains(
+        &self,
+        version: u64,
+        forest: TransactionalVersionForest,
+    ) -> ConflictableTransactionResult<(), SnapshotError> {
+        if forest.root_of(version)? != self.root {
+            return abort(SnapshotError::VersionNotInTree {
+                version,
+                root: self.root,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Every tree in the catalog has a record, which can't be empty, since an empty value in a delta means removal.
+const CATALOG_RECORD: &[u8] = &[1];
+
+/// The trees opened for a single transaction, addressed by [`DbSnapshotTree::key`].
+struct DbDataTrees<'a> {
+    catalog_name: &'a [u8],
+    catalog: &'a TransactionalTree,
+    members: BTreeMap<&'a IVec, &'a TransactionalTree>,
+}
+
+impl<'a> DbDataTrees<'a> {
+    /// Returns the tree named in `tagged_key`, or `None` if it wasn't opened for this transaction, along with the key within
+    /// it. Aborts the transaction if `tagged_key` wasn't made by [`DbSnapshotTree::key`].
+    fn member<'k>(
+        &self,
+        tagged_key: &'k [u8],
+    ) -> ConflictableTransactionResult<(Option<&'a TransactionalTree>, &'k [u8]), SnapshotError>
+    {
+        let (tree_name, key) = if let Some(split) = DbSnapshotTree::checked_split_key(tagged_key) {
+            split
+        } else {
+            return abort(SnapshotError::InvalidDataTreeKey(IVec::from(tagged_key)));
+        };
+        if tree_name == self.catalog_name {
+            return Ok((Some(self.catalog), key));
+        }
+        Ok((self.members.get(&IVec::from(tree_name)).copied(), key))
+    }
+
+    /// Same as `member`, but also aborts the transaction if the tree wasn't opened, since it can't be written to.
+    fn writable_member<'k>(
+        &self,
+        tagged_key: &'k [u8],
+    ) -> ConflictableTransactionResult<(&'a TransactionalTree, &'k [u8]), SnapshotError> {
+        match self.member(tagged_key)? {
+            (Some(tree), key) => Ok((tree, key)),
+            (None, _) => abort(SnapshotError::InvalidDataTreeKey(IVec::from(tagged_key))),
+        }
+    }
+}
+
+impl<'a> DataTree for DbDataTrees<'a> {
+    /// A tree that wasn't opened doesn't exist, so it has no keys.
+    fn get(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        match self.member(key)? {
+            (Some(tree), key) => Ok(tree.get(key)?),
+            (None, _) => Ok(None),
+        }
+    }
+
+    fn insert(
+        &self,
+        key: &[u8],
+        value: IVec,
+    ) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        let (tree, key) = self.writable_member(key)?;
+        Ok(tree.insert(key, value)?)
+    }
+
+    fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+        let (tree, key) = self.writable_member(key)?;
+        Ok(tree.remove(key)?)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Fixture;
+
+    #[test]
+    fn restore_recreates_dropped_trees() {
+        let fixture = Fixture::open();
+        fixture
+            .db
+            .open_tree("users")
+            .unwrap()
+            .insert(b"alice", b"admin")
+            .unwrap();
+        let snaps = DbSnapshotTree::create(&fixture.db, "snaps").unwrap();
+        let root = snaps.root();
+        assert_eq!(snaps.tree_names(), Ok(vec![IVec::from(b"users")]));
+
+        let v1 = snaps
+            .commit(&[Delta::Insert(
+                DbSnapshotTree::key(b"orders", b"order0"),
+                IVec::from(b"alice"),
+            )])
+            .unwrap();
+        let v2 = snaps.drop_tree(b"users").unwrap();
+        assert_eq!(snaps.tree_names(), Ok(vec![IVec::from(b"orders")]));
+        assert!(!has_tree(&fixture.db, b"users"));
+
+        snaps.checkout(root).unwrap();
+        assert_eq!(snaps.tree_names(), Ok(vec![IVec::from(b"users")]));
+        assert!(!has_tree(&fixture.db, b"orders"));
+        assert_eq!(
+            fixture.db.open_tree("users").unwrap().get(b"alice"),
+            Ok(Some(IVec::from(b"admin")))
+        );
+
+        assert_eq!(
+            snaps.get_at_version(v1, b"orders", b"order0"),
+            Ok(Some(IVec::from(b"alice")))
+        );
+        assert!(!has_tree(&fixture.db, b"orders"));
+
+        snaps.checkout(v2).unwrap();
+        assert!(!has_tree(&fixture.db, b"users"));
+        assert_eq!(
+            fixture.db.open_tree("orders").unwrap().get(b"order0"),
+            Ok(Some(IVec::from(b"alice")))
+        );
+
+        assert_eq!(
+            snaps.drop_tree(b"users"),
+            Err(TransactionError::Abort(SnapshotError::DataTreeNotFound(
+                IVec::from(b"users")
+            )))
+        );
+        assert_eq!(
+            snaps.commit(&[Delta::Remove(DbSnapshotTree::key(b"snaps-versions", b""))]),
+            Err(TransactionError::Abort(SnapshotError::DataTreeNotFound(
+                IVec::from(b"snaps-versions")
+            )))
+        );
+    }
+
+    #[test]
+    fn reads_and_aborts_leave_trees_alone() {
+        let fixture = Fixture::open();
+        let snaps = DbSnapshotTree::create(&fixture.db, "snaps").unwrap();
+        let root = snaps.root();
+
+        // A tree created after the snapshot tree, which was never committed to.
+        let late = fixture.db.open_tree("late").unwrap();
+        late.insert(b"key0", b"value0").unwrap();
+        assert_eq!(
+            snaps.get_at_version(root, b"late", b"key0"),
+            Ok(Some(IVec::from(b"value0")))
+        );
+        let missing_version = root + 100;
+        assert_eq!(
+            snaps.get_at_version(missing_version, b"late", b"key0"),
+            Err(TransactionError::Abort(SnapshotError::VersionNotFound(
+                missing_version
+            )))
+        );
+        assert_eq!(late.len(), 1);
+
+        // Reading a tree that doesn't exist doesn't create it.
+        assert_eq!(snaps.get_at_version(root, b"missing", b"key0"), Ok(None));
+        assert!(!has_tree(&fixture.db, b"missing"));
+
+        // Restoring past the first commit to a tree keeps the keys that were never versioned.
+        snaps
+            .commit(&[Delta::Insert(
+                DbSnapshotTree::key(b"late", b"key1"),
+                IVec::from(b"value1"),
+            )])
+            .unwrap();
+        snaps.checkout(root).unwrap();
+        assert!(has_tree(&fixture.db, b"late"));
+        assert_eq!(
+            late.iter().collect::<sled::Result<Vec<_>>>(),
+            Ok(vec![(IVec::from(b"key0"), IVec::from(b"value0"))])
+        );
+    }
+
+    #[test]
+    fn malformed_keys_abort() {
+        let fixture = Fixture::open();
+        let snaps = DbSnapshotTree::create(&fixture.db, "snaps").unwrap();
+
+        let too_short = IVec::from(b"key");
+        let name_too_long = IVec::from(&u64::MAX.to_be_bytes());
+        for key in [too_short, name_too_long] {
+            assert_eq!(
+                snaps.commit(&[Delta::Insert(key.clone(), IVec::from(b"value"))]),
+                Err(TransactionError::Abort(SnapshotError::InvalidDataTreeKey(
+                    key
+                )))
+            );
+        }
+        assert_eq!(snaps.current_version(), Ok(snaps.root()));
+    }
+
+    fn has_tree(db: &sled::Db, name: &[u8]) -> bool {
+        db.tree_names().iter().any(|n| n == name)
+    }
+}
@@ -1,4 +1,4 @@
-use crate::usize_from_be_slice;
+use crate::{u64_from_be_slice, usize_from_be_slice};
 
 use sled::IVec;
 use std::io;
@@ -34,6 +34,20 @@ where
         Ok(())
     }
 
+    pub fn key(&self) -> &B {
+        match self {
+            Delta::Insert(key, _) | Delta::Remove(key) => key,
+        }
+    }
+
+    /// The value being inserted, or `None` for a removal.
+    pub fn value(&self) -> Option<&B> {
+        match self {
+            Delta::Insert(_, value) => Some(value),
+            Delta::Remove(_) => None,
+        }
+    }
+
     pub fn encoded_size(&self) -> usize {
         match self {
             Delta::Insert(key, value) => 2 * mem::size_of::<u64>() + key.len() + value.len(),
@@ -127,6 +141,21 @@ where
     }
 }
 
+/// Returns the length of the `RawDelta` at the start of `bytes`, or `None` if its header is cut off or the length overflows.
+pub fn checked_delta_len(bytes: &[u8]) -> Option<usize> {
+    let header = bytes.get(..num_value_bytes_range().end)?;
+    let num_key_bytes = u64_from_be_slice(&header[num_key_bytes_range()]);
+    let num_value_bytes = u64_from_be_slice(&header[num_value_bytes_range()]);
+    let len = num_key_bytes
+        .checked_add(num_value_bytes)?
+        .checked_add(header.len() as u64)?;
+    if len <= usize::MAX as u64 {
+        Some(len as usize)
+    } else {
+        None
+    }
+}
+
 const fn num_key_bytes_range() -> Range<usize> {
     0..mem::size_of::<u64>()
 }
@@ -1,6 +1,8 @@
 use crate::{
+    check::walk_delta_list,
     delta::Delta,
     delta_node::{encode_delta_node, HeadDeltaNode, RawDeltaNode, RawHeadDeltaNode},
+    u64_from_be_slice, SnapshotError, VersionForest,
 };
 
 use sled::{
@@ -9,6 +11,8 @@ use sled::{
     },
     IVec, Tree,
 };
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
 use std::ops::Deref;
 
 // PERF: try pointing to deltas from the linked list nodes instead of serializing them inline; probably need a benchmark to
@@ -22,6 +26,7 @@ use std::ops::Deref;
 ///
 /// A key in a `DeltaMap` is either a snapshot version or another globally unique ID being used as a linked list pointer. Values
 /// of the map are nodes in a linked list, each node containing a sequence of deltas.
+#[derive(Clone)]
 pub struct DeltaMap(pub Tree);
 
 impl Deref for DeltaMap {
@@ -32,6 +37,54 @@ impl Deref for DeltaMap {
     }
 }
 
+impl DeltaMap {
+    /// Removes every entry that isn't reachable from the delta list of some version in `forest`, returning how many were
+    /// removed.
+    ///
+    /// Older versions of this crate leaked list nodes whenever a snapshot was deleted, so this cleans up after them. It is not
+    /// transactional: **nothing else may write to `forest` or this `DeltaMap` while it runs**, otherwise it could remove nodes
+    /// that were just created.
+    ///
+    /// A delta list that dangles, loops back on itself or runs into a malformed node only keeps the nodes up to that point.
+    /// Keys that aren't 8 bytes long can't be list nodes, so they are left for [`check_forest`](crate::check_forest) to report.
+    pub fn sweep_orphans(&self, forest: &VersionForest) -> sled::Result<usize> {
+        let versions = forest
+            .iter_versions()
+            .collect::<sled::Result<BTreeSet<_>>>()?;
+        let mut owners = BTreeMap::new();
+        for &version in versions.iter() {
+            let head = if let Some(head) = self.get(version.to_be_bytes())? {
+                RawHeadDeltaNode::new(head)
+            } else {
+                // The current version has no deltas.
+                continue;
+            };
+            if head.is_well_formed() {
+                // A broken list keeps the nodes read before the break.
+                walk_delta_list(version, &head, &versions, self, &mut owners)?;
+            }
+        }
+
+        let mut num_removed = 0;
+        for key in self.iter().keys() {
+            let key = key?;
+            if key.len() != mem::size_of::<u64>() {
+                continue;
+            }
+            let id = u64_from_be_slice(&key);
+            if !versions.contains(&id) && !owners.contains_key(&id) {
+                self.remove(key)?;
+                num_removed += 1;
+            }
+        }
+
+        Ok(num_removed)
+    }
+}
+
+/// A delta list node paired with its key in the `DeltaMap`.
+type KeyedDeltaNode = (u64, RawDeltaNode<IVec>);
+
 /// Same as [DeltaMap] but used in transactions.
 #[derive(Clone, Copy)]
 pub struct TransactionalDeltaMap<'a>(pub &'a TransactionalTree);
@@ -74,22 +127,28 @@ impl<'a> TransactionalDeltaMap<'a> {
             .map(|result| result.map(RawHeadDeltaNode::new))
     }
 
-    /// Removes all deltas for `version`.
-    pub(crate) fn remove_version(
+    /// Reads all delta nodes for `version` without removing them.
+    pub(crate) fn get_delta_nodes(
         &self,
         version: u64,
     ) -> Result<Option<Vec<RawDeltaNode<IVec>>>, UnabortableTransactionError> {
-        if let Some(head_bytes) = self.remove(&version.to_be_bytes())? {
+        Ok(self
+            .get_keyed_delta_nodes(version)?
+            .map(|nodes| nodes.into_iter().map(|(_key, node)| node).collect()))
+    }
+
+    /// Same as `get_delta_nodes`, but each node is paired with its key.
+    fn get_keyed_delta_nodes(
+        &self,
+        version: u64,
+    ) -> Result<Option<Vec<KeyedDeltaNode>>, UnabortableTransactionError> {
+        if let Some(head) = self.get_delta_list_head(version)? {
             let mut all_delta_nodes = Vec::new();
-            let head = RawHeadDeltaNode::new(head_bytes);
             let mut maybe_next_key = head.next_key();
             while let Some(next_key) = maybe_next_key {
-                let node = RawDeltaNode::new(
-                    self.get(&next_key.to_be_bytes())?
-                        .expect("Inconsistent linked list: followed pointer to missing key"),
-                );
+                let node = self.get_list_node(next_key)?;
                 maybe_next_key = node.next_key();
-                all_delta_nodes.push(node);
+                all_delta_nodes.push((next_key, node));
             }
             Ok(Some(all_delta_nodes))
         } else {
@@ -97,11 +156,33 @@ impl<'a> TransactionalDeltaMap<'a> {
         }
     }
 
+    /// Removes all deltas for `version`, freeing the list nodes. The removed nodes are returned in case the caller wants to
+    /// move them somewhere else.
+    pub(crate) fn remove_version(
+        &self,
+        version: u64,
+    ) -> Result<Option<Vec<RawDeltaNode<IVec>>>, UnabortableTransactionError> {
+        let keyed_delta_nodes = if let Some(nodes) = self.get_keyed_delta_nodes(version)? {
+            nodes
+        } else {
+            return Ok(None);
+        };
+
+        self.remove(&version.to_be_bytes())?;
+        let mut all_delta_nodes = Vec::with_capacity(keyed_delta_nodes.len());
+        for (node_key, node) in keyed_delta_nodes {
+            self.remove(&node_key.to_be_bytes())?;
+            all_delta_nodes.push(node);
+        }
+
+        Ok(Some(all_delta_nodes))
+    }
+
     pub(crate) fn append_deltas<B>(
         &self,
         version: u64,
         new_deltas: &[Delta<B>],
-    ) -> ConflictableTransactionResult<()>
+    ) -> ConflictableTransactionResult<(), SnapshotError>
     where
         B: Deref<Target = [u8]>,
     {
@@ -113,10 +194,10 @@ impl<'a> TransactionalDeltaMap<'a> {
             // Write a new delta node.
             let tail_key = self.create_node_with_deltas(None, new_deltas)?;
             // Append the new node to the list.
-            if let Some(tail_key) = head.tail_key() {
-                let mut tail_node = self.get_list_node(tail_key)?;
-                tail_node.set_next_key(Some(tail_key));
-                self.insert(&tail_key.to_be_bytes(), tail_node.take_bytes())?;
+            if let Some(old_tail_key) = head.tail_key() {
+                let mut old_tail_node = self.get_list_node(old_tail_key)?;
+                old_tail_node.set_next_key(Some(tail_key));
+                self.insert(&old_tail_key.to_be_bytes(), old_tail_node.take_bytes())?;
             }
             let new_head_node = HeadDeltaNode::new(head.next_key().unwrap_or(tail_key), tail_key);
             self.insert(&version.to_be_bytes(), &new_head_node)?;
@@ -124,17 +205,16 @@ impl<'a> TransactionalDeltaMap<'a> {
             Ok(())
         } else {
             // Can't append to an entry that does not exist.
-            abort(())
+            abort(SnapshotError::DeltaListNotFound(version))
         }
     }
 
-    /// # Panics
-    /// If `version` is missing. Internal users already followed a pointer to get to this version.
+    /// Aborts the transaction if `version` has no delta list.
     pub(crate) fn prepend_deltas<B>(
         &self,
         version: u64,
         new_deltas: &[Delta<B>],
-    ) -> ConflictableTransactionResult<()>
+    ) -> ConflictableTransactionResult<(), SnapshotError>
     where
         B: Deref<Target = [u8]>,
     {
@@ -151,8 +231,8 @@ impl<'a> TransactionalDeltaMap<'a> {
 
             Ok(())
         } else {
-            // Can't append to an entry that does not exist.
-            abort(())
+            // Can't prepend to an entry that does not exist.
+            abort(SnapshotError::DeltaListNotFound(version))
         }
     }
 
@@ -165,7 +245,7 @@ impl<'a> TransactionalDeltaMap<'a> {
         &self,
         version: u64,
         raw_delta_nodes: Vec<RawDeltaNode<IVec>>,
-    ) -> ConflictableTransactionResult<()> {
+    ) -> Result<(), UnabortableTransactionError> {
         if raw_delta_nodes.is_empty() {
             return Ok(());
         }
@@ -174,7 +254,7 @@ impl<'a> TransactionalDeltaMap<'a> {
             .get_delta_list_head(version)?
             .expect("Inconsistent forest: followed pointer to missing version");
 
-        let (head, tail) = self.recreate_sublist(raw_delta_nodes, version_head.tail_key())?;
+        let (head, tail) = self.recreate_sublist(raw_delta_nodes, version_head.next_key())?;
 
         let new_version_head = HeadDeltaNode::new(head, version_head.tail_key().unwrap_or(tail));
         self.insert(&version.to_be_bytes(), &new_version_head)?;
@@ -187,7 +267,7 @@ impl<'a> TransactionalDeltaMap<'a> {
         &self,
         raw_delta_nodes: Vec<RawDeltaNode<IVec>>,
         tail_next_key: Option<u64>,
-    ) -> ConflictableTransactionResult<(u64, u64)> {
+    ) -> Result<(u64, u64), UnabortableTransactionError> {
         assert!(!raw_delta_nodes.is_empty());
         let num_nodes = raw_delta_nodes.len();
 
@@ -209,6 +289,46 @@ impl<'a> TransactionalDeltaMap<'a> {
         Ok((keys[0], *keys.last().unwrap()))
     }
 
+    /// Rewrites the delta list for `version` as a single node with exactly one delta per key, which has the same effect as
+    /// applying the whole list. Returns whether anything changed, which is not the case if `version` has no delta list or it
+    /// is already compact.
+    pub(crate) fn compact_version(
+        &self,
+        version: u64,
+    ) -> Result<bool, UnabortableTransactionError> {
+        let keyed_delta_nodes = if let Some(nodes) = self.get_keyed_delta_nodes(version)? {
+            nodes
+        } else {
+            return Ok(false);
+        };
+
+        // Only the last delta for each key matters.
+        let mut net_deltas = BTreeMap::new();
+        let mut num_deltas = 0;
+        for (_node_key, node) in keyed_delta_nodes.iter() {
+            for raw_delta in node.deltas().iter_deltas() {
+                let delta = Delta::<IVec>::from(&raw_delta);
+                net_deltas.insert(delta.key().clone(), delta);
+                num_deltas += 1;
+            }
+        }
+        if keyed_delta_nodes.len() <= 1 && num_deltas == net_deltas.len() {
+            return Ok(false);
+        }
+
+        for (node_key, _node) in keyed_delta_nodes.iter() {
+            self.remove(&node_key.to_be_bytes())?;
+        }
+        let deltas: Vec<_> = net_deltas.into_values().collect();
+        let node_key = self.create_node_with_deltas(None, &deltas)?;
+        self.insert(
+            &version.to_be_bytes(),
+            &HeadDeltaNode::new(node_key, node_key),
+        )?;
+
+        Ok(true)
+    }
+
     fn get_list_node(
         &self,
         node_key: u64,
@@ -234,3 +354,172 @@ impl<'a> TransactionalDeltaMap<'a> {
         Ok(deltas_key)
     }
 }
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::TransactionalVersionForest;
+
+    use sled::{transaction::ConflictableTransactionError, Transactional};
+    use std::collections::HashSet;
+
+    #[test]
+    fn prepend_raw_delta_nodes_keeps_whole_list() {
+        let fixture = Fixture::open();
+        let delta_map = fixture.open_delta_map();
+        let (v1, v2, v3) = (
+            fixture.db.generate_id().unwrap(),
+            fixture.db.generate_id().unwrap(),
+            fixture.db.generate_id().unwrap(),
+        );
+
+        delta_map
+            .transaction(|delta_map| {
+                let delta_map = TransactionalDeltaMap(delta_map);
+                delta_map.create_version_with_deltas(v1, vec![insert(b"key2")])?;
+                delta_map.create_version_with_deltas(v2, vec![insert(b"key1")])?;
+                delta_map.create_version_with_deltas(v3, vec![insert(b"key0")])?;
+
+                let moved = delta_map.remove_version(v2)?.unwrap();
+                delta_map.prepend_raw_delta_nodes(v1, moved)?;
+                // The list of v1 has two nodes now, so the moved nodes must link up with its first node, not skip ahead to
+                // its tail.
+                let moved = delta_map.remove_version(v3)?.unwrap();
+                delta_map.prepend_raw_delta_nodes(v1, moved)?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            collect_deltas(&delta_map, v1),
+            vec![
+                vec![insert(b"key0")],
+                vec![insert(b"key1")],
+                vec![insert(b"key2")]
+            ]
+        );
+    }
+
+    #[test]
+    fn append_deltas_links_old_tail_to_new_node() {
+        let fixture = Fixture::open();
+        let delta_map = fixture.open_delta_map();
+        let version = fixture.db.generate_id().unwrap();
+
+        delta_map
+            .transaction(|delta_map| {
+                let delta_map = TransactionalDeltaMap(delta_map);
+                delta_map.create_version_with_deltas(version, vec![insert(b"key0")])?;
+                delta_map.append_deltas(version, &[insert(b"key1")])?;
+                delta_map.append_deltas(version, &[insert(b"key2")])?;
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            collect_deltas(&delta_map, version),
+            vec![
+                vec![insert(b"key0")],
+                vec![insert(b"key1")],
+                vec![insert(b"key2")]
+            ]
+        );
+    }
+
+    #[test]
+    fn sweep_orphans_survives_corruption() {
+        let fixture = Fixture::open();
+        let forest = VersionForest(fixture.db.open_tree("versions").unwrap());
+        let delta_map = fixture.open_delta_map();
+
+        let (v1, v2) = (&*forest, &*delta_map)
+            .transaction(|(forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                let root = forest.create_version(None)?;
+                let v1 = forest.create_version(Some(root))?;
+                let v2 = forest.create_version(Some(root))?;
+                delta_map.create_version_with_deltas(v1, vec![insert(b"key0")])?;
+                delta_map.create_version_with_deltas(v2, vec![insert(b"key1")])?;
+                Ok::<_, ConflictableTransactionError<SnapshotError>>((v1, v2))
+            })
+            .unwrap();
+
+        // The list of v1 loops back on itself and the list of v2 dangles.
+        let dangling_key = fixture.db.generate_id().unwrap();
+        for &(version, next_key) in [(v1, None), (v2, Some(dangling_key))].iter() {
+            let head =
+                RawHeadDeltaNode::new(delta_map.get(version.to_be_bytes()).unwrap().unwrap());
+            let node_key = head.next_key().unwrap();
+            let mut node = RawDeltaNode::new(
+                delta_map
+                    .get(node_key.to_be_bytes())
+                    .unwrap()
+                    .unwrap()
+                    .to_vec(),
+            );
+            node.set_next_key(Some(next_key.unwrap_or(node_key)));
+            delta_map
+                .insert(node_key.to_be_bytes(), node.take_bytes())
+                .unwrap();
+        }
+        let orphan = fixture.db.generate_id().unwrap();
+        delta_map.insert(orphan.to_be_bytes(), &[]).unwrap();
+        delta_map.insert(b"bad", &[]).unwrap();
+
+        assert_eq!(delta_map.sweep_orphans(&forest), Ok(1));
+        assert!(!delta_map.contains_key(orphan.to_be_bytes()).unwrap());
+        assert!(delta_map.contains_key(b"bad").unwrap());
+        assert_eq!(delta_map.len(), 5);
+    }
+
+    fn insert(key: &[u8]) -> Delta<IVec> {
+        Delta::Insert(IVec::from(key), IVec::from(b"value"))
+    }
+
+    /// Reads the delta list of `version` node by node, failing on a cycle instead of looping forever.
+    fn collect_deltas(delta_map: &DeltaMap, version: u64) -> Vec<Vec<Delta<IVec>>> {
+        let head = RawHeadDeltaNode::new(delta_map.get(version.to_be_bytes()).unwrap().unwrap());
+        let mut visited = HashSet::new();
+        let mut nodes = Vec::new();
+        let mut last_key = None;
+        let mut maybe_next_key = head.next_key();
+        while let Some(next_key) = maybe_next_key {
+            assert!(visited.insert(next_key), "delta list has a cycle");
+            let node = RawDeltaNode::new(delta_map.get(next_key.to_be_bytes()).unwrap().unwrap());
+            nodes.push(
+                node.deltas()
+                    .iter_deltas()
+                    .map(|d| Delta::from(&d))
+                    .collect(),
+            );
+            last_key = Some(next_key);
+            maybe_next_key = node.next_key();
+        }
+        assert_eq!(head.tail_key(), last_key);
+        nodes
+    }
+
+    struct Fixture {
+        pub db: sled::Db,
+    }
+
+    impl Fixture {
+        pub fn open() -> Self {
+            let config = sled::Config::new().temporary(true);
+            let db = config.open().unwrap();
+            Self { db }
+        }
+
+        pub fn open_delta_map(&self) -> DeltaMap {
+            DeltaMap(self.db.open_tree("deltas").unwrap())
+        }
+    }
+}
@@ -83,6 +83,11 @@ where
         self.next_key().map(|_| self.raw_tail_key())
     }
 
+    /// Returns `true` if the bytes hold exactly one head node, i.e. the node can be decoded without panicking.
+    pub fn is_well_formed(&self) -> bool {
+        self.bytes.len() == tail_key_range().end
+    }
+
     fn raw_tail_key(&self) -> u64 {
         u64_from_be_slice(&self.bytes[tail_key_range()])
     }
@@ -139,6 +144,11 @@ where
     pub fn deltas(&self) -> RawDeltaSet<&[u8]> {
         RawDeltaSet::new(&self.bytes[delta_set_range()])
     }
+
+    /// Returns `true` if the node and all of its deltas can be decoded without panicking.
+    pub fn is_well_formed(&self) -> bool {
+        self.bytes.len() >= next_key_range().end && self.deltas().is_well_formed()
+    }
 }
 
 fn encode_next_key(next_key: Option<u64>, mut writer: impl io::Write) -> io::Result<()> {
@@ -1,4 +1,4 @@
-use crate::delta::RawDelta;
+use crate::delta::{checked_delta_len, RawDelta};
 
 use std::ops::Deref;
 
@@ -19,6 +19,24 @@ where
     }
 }
 
+impl<B> RawDeltaSet<B>
+where
+    B: Deref<Target = [u8]>,
+{
+    /// Returns `true` if the bytes are a sequence of complete deltas, i.e. the set can be decoded without panicking.
+    pub fn is_well_formed(&self) -> bool {
+        let mut rest = &self.bytes[..];
+        while !rest.is_empty() {
+            let len = match checked_delta_len(rest) {
+                Some(len) if len <= rest.len() => len,
+                _ => return false,
+            };
+            rest = &rest[len..];
+        }
+        true
+    }
+}
+
 impl<'a> RawDeltaSet<&'a [u8]> {
     pub fn iter_deltas(&self) -> RawDeltaIter<'a> {
         RawDeltaIter {
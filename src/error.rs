@@ -0,0 +1,148 @@
+use sled::IVec;
+use std::fmt;
+
+/// The reason that an operation on a snapshot forest aborted its transaction.
+///
+/// Operations in the [`transactions`](crate::transactions) module abort with one of these when a precondition doesn't hold.
+/// None of them leave any partial changes behind, since the whole transaction is rolled back.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SnapshotError {
+    /// The version does not exist in the `VersionForest`.
+    VersionNotFound(u64),
+    /// The version is not the root of a snapshot tree.
+    NotRootVersion(u64),
+    /// The operation requires the current version of a snapshot tree, but this version is not current.
+    NotCurrentVersion(u64),
+    /// The operation requires a leaf version, but this version has children.
+    NotLeafVersion(u64),
+    /// Only non-current snapshots can be modified through the `DeltaMap`. Use
+    /// [`modify_current_leaf_snapshot`](crate::transactions::modify_current_leaf_snapshot) instead.
+    CannotModifyCurrentVersion(u64),
+    /// Root versions can only be deleted along with their entire tree.
+    CannotDeleteRootVersion(u64),
+    /// Root versions have no parent, so there are no changes to cherry-pick from them.
+    CannotCherryPickRootVersion(u64),
+    /// The current version can't be deleted, since it represents the state of the data tree.
+    CannotDeleteCurrentVersion(u64),
+    /// There is no delta list for this version in the `DeltaMap`.
+    DeltaListNotFound(u64),
+    /// The versions belong to different snapshot trees.
+    NoPathBetweenVersions { start: u64, finish: u64 },
+    /// The version doesn't belong to the snapshot tree rooted at `root`.
+    VersionNotInTree { version: u64, root: u64 },
+    /// `ancestor` is not a proper ancestor of `descendant`.
+    NotAncestor { ancestor: u64, descendant: u64 },
+    /// The version has more than one child, so it isn't part of a linear chain of versions.
+    NonLinearChain(u64),
+    /// Tagged versions can't be deleted until their tags are removed.
+    VersionIsTagged(u64),
+    /// There is no tag with this name.
+    TagNotFound(String),
+    /// A tag with this name already points to a different version.
+    TagAlreadyExists(String),
+    /// There is no branch with this name in the snapshot tree.
+    BranchNotFound(String),
+    /// A branch with this name already exists in the snapshot tree.
+    BranchAlreadyExists(String),
+    /// A merge conflict on this key was resolved with [`Resolution::Abort`](crate::Resolution::Abort).
+    UnresolvedConflict(IVec),
+    /// The database has no tree with this name at the current version.
+    DataTreeNotFound(IVec),
+    /// This key doesn't name a member of the data trees it was used with, e.g. it is too short to hold the member's index.
+    InvalidDataTreeKey(IVec),
+    /// [`repair_forest`](crate::repair_forest) lost some of the deltas needed to restore this version.
+    UnrestorableVersion(u64),
+    /// The tree that a snapshot is copied into must be empty, so no data is overwritten.
+    TargetTreeNotEmpty(IVec),
+    /// A snapshot can't be copied into the data tree that it was taken from.
+    TargetIsDataTree(IVec),
+    /// The current version of the tree rooted at `root` is no longer `version`, so a [`SnapshotIter`](crate::SnapshotIter)
+    /// created at `version` can't continue.
+    CurrentVersionChanged { root: u64, version: u64 },
+    /// The snapshot tree rooted at `root` versions the data tree called `expected`, not `found`.
+    WrongDataTree {
+        root: u64,
+        expected: IVec,
+        found: IVec,
+    },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::VersionNotFound(v) => write!(f, "version {} does not exist", v),
+            Self::NotRootVersion(v) => {
+                write!(f, "version {} is not the root of a snapshot tree", v)
+            }
+            Self::NotCurrentVersion(v) => write!(f, "version {} is not the current version", v),
+            Self::NotLeafVersion(v) => write!(f, "version {} is not a leaf", v),
+            Self::CannotModifyCurrentVersion(v) => {
+                write!(f, "version {} is current and can't be modified directly", v)
+            }
+            Self::CannotDeleteRootVersion(v) => {
+                write!(f, "version {} is a root and can't be deleted", v)
+            }
+            Self::CannotCherryPickRootVersion(v) => {
+                write!(
+                    f,
+                    "version {} is a root and has no changes to cherry-pick",
+                    v
+                )
+            }
+            Self::CannotDeleteCurrentVersion(v) => {
+                write!(f, "version {} is current and can't be deleted", v)
+            }
+            Self::DeltaListNotFound(v) => write!(f, "version {} has no delta list", v),
+            Self::NoPathBetweenVersions { start, finish } => write!(
+                f,
+                "versions {} and {} belong to different snapshot trees",
+                start, finish
+            ),
+            Self::VersionNotInTree { version, root } => write!(
+                f,
+                "version {} does not belong to the snapshot tree rooted at {}",
+                version, root
+            ),
+            Self::NotAncestor {
+                ancestor,
+                descendant,
+            } => write!(
+                f,
+                "version {} is not an ancestor of version {}",
+                ancestor, descendant
+            ),
+            Self::NonLinearChain(v) => write!(f, "version {} has more than one child", v),
+            Self::VersionIsTagged(v) => write!(f, "version {} is tagged and can't be deleted", v),
+            Self::TagNotFound(name) => write!(f, "tag {:?} does not exist", name),
+            Self::TagAlreadyExists(name) => write!(f, "tag {:?} already exists", name),
+            Self::BranchNotFound(name) => write!(f, "branch {:?} does not exist", name),
+            Self::BranchAlreadyExists(name) => write!(f, "branch {:?} already exists", name),
+            Self::UnresolvedConflict(key) => write!(f, "unresolved conflict on key {:?}", key),
+            Self::DataTreeNotFound(name) => write!(f, "data tree {:?} does not exist", name),
+            Self::InvalidDataTreeKey(key) => {
+                write!(f, "key {:?} does not name a member data tree", key)
+            }
+            Self::UnrestorableVersion(v) => write!(f, "version {} can no longer be restored", v),
+            Self::TargetTreeNotEmpty(name) => write!(f, "target tree {:?} is not empty", name),
+            Self::TargetIsDataTree(name) => {
+                write!(f, "target tree {:?} is the data tree itself", name)
+            }
+            Self::CurrentVersionChanged { root, version } => write!(
+                f,
+                "the current version of the tree rooted at {} is no longer {}",
+                root, version
+            ),
+            Self::WrongDataTree {
+                root,
+                expected,
+                found,
+            } => write!(
+                f,
+                "the snapshot tree rooted at {} versions data tree {:?}, not {:?}",
+                root, expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
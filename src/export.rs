@@ -0,0 +1,468 @@
+use crate::{
+    transactions::create_snapshot_tree,
+    version_info::{decode_timestamp, encode_timestamp},
+    version_node::NULL_VERSION,
+    Branch, Delta, DeltaMap, SnapshotError, TransactionalDeltaMap, TransactionalVersionForest,
+    VersionForest, VersionInfo,
+};
+
+use sled::{transaction::TransactionError, IVec, Transactional, Tree};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+
+/// Writes the snapshot tree rooted at `root`, along with the contents of its `data_tree`, to `writer`.
+///
+/// The stream holds everything that [`import_snapshot_tree`] needs to recreate the tree, possibly in a different `Db`: the
+/// topology, the metadata and deltas of every version, the branches, the current version and the contents of the data tree.
+/// Tags are left out, since they belong to the whole forest, and so is the checked-out branch, so the imported tree starts
+/// without one.
+///
+/// The snapshot tree is read in a single transaction, but `sled` can't scan the data tree in a transaction, so the data tree
+/// must not be modified while exporting.
+///
+/// # Format
+///
+/// All integers are big-endian `u64`s, and all byte strings are preceded by their length.
+///
+/// 1. The magic bytes `SLEDSNAP`, followed by the format version, which is currently `1`.
+/// 2. The number of versions, followed by each version, with parents before their children:
+///     - the ID of the version, then the ID of its parent, or `u64::MAX` for the root
+///     - the creation time in nanoseconds since the UNIX epoch, then the author, message and user data as byte strings
+///     - the number of deltas, then each delta as the length of the key, the length of the value (`0` for a removal), the key
+///       and the value
+/// 3. The ID of the current version.
+/// 4. The number of branches, followed by each branch as its name and then the ID of its tip.
+/// 5. Each key-value pair in the data tree as two byte strings, followed by `u64::MAX`.
+///
+/// Version IDs only need to be unique within the stream, since they are replaced on import.
+pub fn export_snapshot_tree(
+    root: u64,
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    data_tree: &Tree,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    let tree = (&**forest, &**delta_map)
+        .transaction(|(forest, delta_map)| {
+            let forest = TransactionalVersionForest(forest);
+            let delta_map = TransactionalDeltaMap(delta_map);
+
+            let current = forest.current_version(root)?;
+            let mut versions = Vec::new();
+            let mut queue = VecDeque::from([root]);
+            while let Some(version) = queue.pop_front() {
+                let node = forest.get_existing_version(version)?;
+                queue.extend(node.iter_children());
+
+                let mut deltas = Vec::new();
+                for delta_node in delta_map.get_delta_nodes(version)?.unwrap_or_default() {
+                    for raw_delta in delta_node.deltas().iter_deltas() {
+                        deltas.push(Delta::<IVec>::from(&raw_delta));
+                    }
+                }
+                versions.push(ExportedVersion {
+                    id: version,
+                    parent: node.parent(),
+                    info: forest.version_info(version)?,
+                    deltas,
+                });
+            }
+
+            Ok(ExportedTree {
+                versions,
+                current,
+                branches: forest.list_branches(root)?,
+                data: Vec::new(),
+            })
+        })
+        .map_err(into_io_error)?;
+
+    writer.write_all(MAGIC)?;
+    write_u64(&mut writer, FORMAT_VERSION)?;
+
+    write_u64(&mut writer, tree.versions.len() as u64)?;
+    for version in tree.versions.iter() {
+        write_u64(&mut writer, version.id)?;
+        write_u64(&mut writer, version.parent.unwrap_or(NULL_VERSION))?;
+        writer.write_all(&encode_timestamp(version.info.created_at))?;
+        write_bytes(&mut writer, version.info.author.as_bytes())?;
+        write_bytes(&mut writer, version.info.message.as_bytes())?;
+        write_bytes(&mut writer, &version.info.user_data)?;
+        write_u64(&mut writer, version.deltas.len() as u64)?;
+        for delta in version.deltas.iter() {
+            delta.encode(&mut writer)?;
+        }
+    }
+
+    write_u64(&mut writer, tree.current)?;
+
+    write_u64(&mut writer, tree.branches.len() as u64)?;
+    for branch in tree.branches.iter() {
+        write_bytes(&mut writer, branch.name.as_bytes())?;
+        write_u64(&mut writer, branch.tip)?;
+    }
+
+    for kv in data_tree.iter() {
+        let (key, value) = kv?;
+        write_bytes(&mut writer, &key)?;
+        write_bytes(&mut writer, &value)?;
+    }
+    write_u64(&mut writer, END_OF_DATA)?;
+
+    writer.flush()
+}
+
+/// Reads a snapshot tree written by [`export_snapshot_tree`] from `reader` and adds it to `forest` as a new tree, returning
+/// the new root version.
+///
+/// Every version gets a fresh ID from the target `Db`. `data_tree` must be empty, so that it can't belong to another snapshot
+/// tree, and it's recorded as the data tree of the new tree and filled with the contents from the stream, ending up in the
+/// state of the exported current version. A
+/// non-empty `data_tree` fails with [`SnapshotError::TargetTreeNotEmpty`], wrapped in an [`io::ErrorKind::Other`] error.
+/// Like [`materialize_version`](crate::materialize_version), this can't stop other writers from using `data_tree` while
+/// importing.
+///
+/// Fails with [`io::ErrorKind::InvalidData`] if the stream is malformed or uses an unsupported format version. The whole stream
+/// is read before anything is written, and then it's imported in a single transaction, so a failed import leaves no trace.
+pub fn import_snapshot_tree(
+    reader: impl Read,
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    data_tree: &Tree,
+) -> io::Result<u64> {
+    if !data_tree.is_empty() {
+        return Err(into_io_error(TransactionError::Abort(
+            SnapshotError::TargetTreeNotEmpty(data_tree.name()),
+        )));
+    }
+    let tree = read_exported_tree(StreamReader(reader))?;
+    let data_tree_name = data_tree.name();
+
+    (data_tree, &**forest, &**delta_map)
+        .transaction(|(data_tree, forest, delta_map)| {
+            let forest = TransactionalVersionForest(forest);
+            let delta_map = TransactionalDeltaMap(delta_map);
+
+            let root = create_snapshot_tree(forest)?;
+            forest.set_data_tree_name(root, &data_tree_name)?;
+            let mut new_ids = HashMap::new();
+            for version in tree.versions.iter() {
+                let new_id = match version.parent {
+                    Some(parent) => forest.create_version(Some(new_ids[&parent]))?,
+                    None => root,
+                };
+                forest.replace_version_info(root, new_id, &version.info)?;
+                if version.id != tree.current {
+                    delta_map.create_version_with_deltas(new_id, version.deltas.clone())?;
+                }
+                new_ids.insert(version.id, new_id);
+            }
+            forest.set_current_version(root, new_ids[&tree.current])?;
+
+            let branches: Vec<_> = tree
+                .branches
+                .iter()
+                .map(|branch| Branch {
+                    name: branch.name.clone(),
+                    tip: new_ids[&branch.tip],
+                })
+                .collect();
+            forest.set_branches(root, &branches)?;
+
+            for (key, value) in tree.data.iter() {
+                data_tree.insert(key, value)?;
+            }
+
+            Ok(root)
+        })
+        .map_err(into_io_error)
+}
+
+const MAGIC: &[u8; 8] = b"SLEDSNAP";
+const FORMAT_VERSION: u64 = 1;
+/// Takes the place of a key length to mark the end of the data tree.
+const END_OF_DATA: u64 = u64::MAX;
+
+struct ExportedTree {
+    versions: Vec<ExportedVersion>,
+    current: u64,
+    branches: Vec<Branch>,
+    data: Vec<(IVec, IVec)>,
+}
+
+struct ExportedVersion {
+    id: u64,
+    parent: Option<u64>,
+    info: VersionInfo,
+    deltas: Vec<Delta<IVec>>,
+}
+
+/// Reads and validates a whole stream, so that importing it can't fail halfway through.
+fn read_exported_tree(mut reader: StreamReader<impl Read>) -> io::Result<ExportedTree> {
+    if reader.read_exact_bytes(MAGIC.len() as u64)? != MAGIC {
+        return Err(invalid_data("not a snapshot tree stream"));
+    }
+    if reader.read_u64()? != FORMAT_VERSION {
+        return Err(invalid_data("unsupported format version"));
+    }
+
+    let num_versions = reader.read_u64()?;
+    if num_versions == 0 {
+        return Err(invalid_data("no root version"));
+    }
+    let mut ids = HashSet::new();
+    let mut versions = Vec::new();
+    for i in 0..num_versions {
+        let id = reader.read_u64()?;
+        let parent = match reader.read_u64()? {
+            NULL_VERSION => None,
+            parent => Some(parent),
+        };
+        let parent_is_known = parent.map_or(i == 0, |parent| ids.contains(&parent));
+        if !parent_is_known || !ids.insert(id) {
+            return Err(invalid_data("versions don't form a tree"));
+        }
+
+        let info = VersionInfo {
+            created_at: decode_timestamp(&reader.read_exact_bytes(8)?),
+            author: reader.read_string()?,
+            message: reader.read_string()?,
+            user_data: reader.read_bytes()?,
+        };
+        let num_deltas = reader.read_u64()?;
+        let deltas = (0..num_deltas)
+            .map(|_| reader.read_delta())
+            .collect::<io::Result<_>>()?;
+
+        versions.push(ExportedVersion {
+            id,
+            parent,
+            info,
+            deltas,
+        });
+    }
+
+    let current = reader.read_u64()?;
+    if !ids.contains(&current) {
+        return Err(invalid_data("unknown current version"));
+    }
+
+    let num_branches = reader.read_u64()?;
+    let mut branches = Vec::new();
+    for _ in 0..num_branches {
+        let name = reader.read_string()?;
+        let tip = reader.read_u64()?;
+        if !ids.contains(&tip) {
+            return Err(invalid_data("unknown branch tip"));
+        }
+        branches.push(Branch { name, tip });
+    }
+    branches.sort_by(|a, b| a.name.cmp(&b.name));
+    branches.dedup_by(|a, b| a.name == b.name);
+
+    let mut data = Vec::new();
+    loop {
+        let key_len = reader.read_u64()?;
+        if key_len == END_OF_DATA {
+            break;
+        }
+        let key = reader.read_exact_bytes(key_len)?;
+        let value = reader.read_bytes()?;
+        data.push((IVec::from(key), IVec::from(value)));
+    }
+
+    Ok(ExportedTree {
+        versions,
+        current,
+        branches,
+        data,
+    })
+}
+
+struct StreamReader<R>(R);
+
+impl<R: Read> StreamReader<R> {
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self.0.read_exact(&mut bytes)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Doesn't trust `len` enough to allocate it up front.
+    fn read_exact_bytes(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        (&mut self.0).take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_u64()?;
+        self.read_exact_bytes(len)
+    }
+
+    fn read_string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.read_bytes()?).map_err(|_| invalid_data("string is not UTF-8"))
+    }
+
+    fn read_delta(&mut self) -> io::Result<Delta<IVec>> {
+        let key_len = self.read_u64()?;
+        let value_len = self.read_u64()?;
+        let key = IVec::from(self.read_exact_bytes(key_len)?);
+        if value_len == 0 {
+            Ok(Delta::Remove(key))
+        } else {
+            Ok(Delta::Insert(
+                key,
+                IVec::from(self.read_exact_bytes(value_len)?),
+            ))
+        }
+    }
+}
+
+fn write_u64(writer: &mut impl Write, x: u64) -> io::Result<()> {
+    writer.write_all(&x.to_be_bytes())
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write_u64(writer, bytes.len() as u64)?;
+    writer.write_all(bytes)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn into_io_error(error: TransactionError<SnapshotError>) -> io::Error {
+    match error {
+        TransactionError::Abort(error) => io::Error::other(error),
+        TransactionError::Storage(error) => error.into(),
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use crate::{
+        open_snapshot_forest, open_tag_map, test_util::Fixture, Delta, SnapshotError, SnapshotTree,
+    };
+
+    use sled::IVec;
+    use std::io;
+
+    #[test]
+    fn export_import_round_trip() {
+        let source = Fixture::open();
+        let tree = source.create_snapshot_tree();
+        let root = tree.root();
+        let v1 = tree.commit(&[insert(b"key1", b"value1")]).unwrap();
+        tree.set_version_metadata(v1, "alice", "first", b"data")
+            .unwrap();
+        tree.create_branch("side", root).unwrap();
+        let v2 = tree
+            .commit(&[insert(b"key1", b"value2"), insert(b"key2", b"value2")])
+            .unwrap();
+        tree.checkout_branch("side").unwrap();
+        let b1 = tree.commit(&[insert(b"key3", b"value3")]).unwrap();
+        tree.checkout(v2).unwrap();
+
+        let mut stream = Vec::new();
+        tree.export(&mut stream).unwrap();
+
+        let target = Fixture::open();
+        // Burn some IDs so the imported versions can't keep their old ones by accident.
+        for _ in 0..10 {
+            target.db.generate_id().unwrap();
+        }
+        // A data tree that's already in use is left alone.
+        let target_data = target.db.open_tree("data").unwrap();
+        target_data.insert(b"stale", b"value").unwrap();
+        let (forest, _) = open_snapshot_forest(&target.db, "snaps").unwrap();
+        let before = forest.len();
+        let error = import(&target, "data", &stream).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert_eq!(
+            error.into_inner().unwrap().downcast::<SnapshotError>().ok(),
+            Some(Box::new(SnapshotError::TargetTreeNotEmpty(
+                target_data.name()
+            )))
+        );
+        assert_eq!(target_data.len(), 1);
+        assert_eq!(forest.len(), before);
+        target_data.clear().unwrap();
+
+        let imported = import(&target, "data", &stream).unwrap();
+        let new_root = imported.root();
+        assert_ne!(new_root, root);
+        assert_eq!(
+            imported.forest().data_tree_name(new_root),
+            Ok(Some(IVec::from("data")))
+        );
+
+        let versions = imported.forest().collect_versions().unwrap();
+        assert_eq!(versions.len(), 4);
+        let current = imported.current_version().unwrap();
+        assert_eq!(imported.data_tree().len(), 2);
+        assert_eq!(
+            imported.data_tree().get(b"key1"),
+            Ok(Some(IVec::from(b"value2")))
+        );
+
+        let branches = imported.list_branches().unwrap();
+        assert_eq!(branches.len(), 1);
+        let new_b1 = branches[0].tip;
+        assert_eq!(
+            imported.version_info(new_b1).unwrap(),
+            tree.version_info(b1).unwrap()
+        );
+        let new_v1 = imported
+            .forest()
+            .transaction(|forest| {
+                crate::TransactionalVersionForest(forest).find_path_to_root(current)
+            })
+            .unwrap()[1];
+        assert_eq!(
+            imported.version_info(new_v1).unwrap(),
+            tree.version_info(v1).unwrap()
+        );
+
+        imported.checkout(new_b1).unwrap();
+        assert_eq!(
+            imported.data_tree().iter().collect::<Result<Vec<_>, _>>(),
+            Ok(vec![(IVec::from(b"key3"), IVec::from(b"value3"))])
+        );
+        imported.checkout(new_v1).unwrap();
+        assert_eq!(
+            imported.data_tree().iter().collect::<Result<Vec<_>, _>>(),
+            Ok(vec![(IVec::from(b"key1"), IVec::from(b"value1"))])
+        );
+
+        // A truncated stream imports nothing.
+        let before = imported.forest().len();
+        let error = import(&target, "other", &stream[..stream.len() - 1])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = import(&target, "other", b"SLEDSNAX").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(imported.forest().len(), before);
+    }
+
+    fn insert(key: &[u8], value: &[u8]) -> Delta<IVec> {
+        Delta::Insert(IVec::from(key), IVec::from(value))
+    }
+
+    fn import(fixture: &Fixture, data_tree: &str, stream: &[u8]) -> io::Result<SnapshotTree> {
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps")?;
+        let tags = open_tag_map(&fixture.db, "snaps")?;
+        let data_tree = fixture.db.open_tree(data_tree)?;
+        SnapshotTree::import(stream, &forest, &delta_map, &tags, data_tree)
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{
+    materialize::check_target_tree,
+    materialize_version,
+    transactions::{create_snapshot_tree, diff_versions},
+    DeltaMap, SnapshotError, TransactionalDeltaMap, TransactionalVersionForest, VersionForest,
+};
+
+use sled::{transaction::TransactionResult, Transactional, Tree};
+
+/// Creates a new, independent tree in the snapshot forest whose data tree `new_data_tree` starts out with the contents of the
+/// `version` snapshot. Returns the root of the new tree.
+///
+/// If `copy_history` is `false`, then the new tree only has a root version. Otherwise the chain of snapshots from the root of
+/// `version`'s tree down to `version` is copied into the new tree, along with the metadata of each snapshot, and the copy of
+/// `version` becomes the current version. Other branches of the old tree are left out. Either way, the two trees don't share
+/// anything afterwards.
+///
+/// `new_data_tree` must be empty and must not be `data_tree` itself, since the two trees must never share a data tree.
+/// Otherwise this fails with [`SnapshotError::TargetTreeNotEmpty`] or [`SnapshotError::TargetIsDataTree`] before anything is
+/// written. The same restrictions as [`materialize_version`] apply while the data is copied.
+///
+/// # Implementation Details
+///
+/// `new_data_tree` is filled with [`materialize_version`] first. Then a single transaction creates the new tree, records the name
+/// of `new_data_tree` as its data tree, and when copying history, stores the difference between each copied snapshot and its
+/// child in the chain as its deltas, which is found with [`diff_versions`].
+pub fn fork_snapshot_tree(
+    version: u64,
+    copy_history: bool,
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    data_tree: &Tree,
+    new_data_tree: &Tree,
+) -> TransactionResult<u64, SnapshotError> {
+    check_target_tree(data_tree, new_data_tree)?;
+    materialize_version(version, forest, delta_map, data_tree, new_data_tree)?;
+
+    (data_tree, &**forest, &**delta_map).transaction(|(data_tree, forest, delta_map)| {
+        let forest = TransactionalVersionForest(forest);
+        let delta_map = TransactionalDeltaMap(delta_map);
+
+        let new_root = create_snapshot_tree(forest)?;
+        forest.set_data_tree_name(new_root, &new_data_tree.name())?;
+        if !copy_history {
+            return Ok(new_root);
+        }
+
+        let mut path = forest.find_path_to_root(version)?;
+        path.reverse();
+
+        let mut new_parent = new_root;
+        forest.replace_version_info(new_root, new_root, &forest.version_info(path[0])?)?;
+        for (&parent, &child) in path.iter().zip(path.iter().skip(1)) {
+            let deltas = diff_versions(child, parent, forest, delta_map, data_tree)?;
+            delta_map.create_version_with_deltas(new_parent, deltas)?;
+
+            let new_child = forest.create_version(Some(new_parent))?;
+            forest.replace_version_info(new_root, new_child, &forest.version_info(child)?)?;
+            new_parent = new_child;
+        }
+        forest.set_current_version(new_root, new_parent)?;
+
+        Ok(new_root)
+    })
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use crate::{test_util::Fixture, Delta, SnapshotError};
+
+    use sled::{transaction::TransactionError, IVec, Tree};
+
+    #[test]
+    fn fork_with_and_without_history() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+        let v1 = tree.commit(&[insert(b"key1", b"value1")]).unwrap();
+        tree.set_version_metadata(v1, "alice", "add key1", b"")
+            .unwrap();
+        let v2 = tree
+            .commit(&[insert(b"key1", b"value2"), insert(b"key2", b"value2")])
+            .unwrap();
+        // Neither the current version nor other branches matter.
+        tree.checkout(root).unwrap();
+        tree.commit(&[insert(b"key3", b"value3")]).unwrap();
+
+        let template = tree
+            .fork(v2, false, fixture.db.open_tree("template").unwrap())
+            .unwrap();
+        assert_eq!(template.forest().collect_versions().unwrap().len(), 5);
+        assert_eq!(template.current_version(), Ok(template.root()));
+        assert_eq!(
+            template.forest().data_tree_name(template.root()),
+            Ok(Some(IVec::from("template")))
+        );
+        assert_eq!(
+            contents(template.data_tree()),
+            vec![
+                (b"key1".into(), b"value2".into()),
+                (b"key2".into(), b"value2".into())
+            ]
+        );
+
+        let fork = tree
+            .fork(v2, true, fixture.db.open_tree("fork").unwrap())
+            .unwrap();
+        let new_v2 = fork.current_version().unwrap();
+        assert_eq!(
+            fork.forest().data_tree_name(fork.root()),
+            Ok(Some(IVec::from("fork")))
+        );
+        assert_eq!(contents(fork.data_tree()), contents(template.data_tree()));
+        assert_eq!(
+            fork.version_info(new_v2).unwrap().created_at,
+            tree.version_info(v2).unwrap().created_at
+        );
+
+        fork.checkout(fork.root()).unwrap();
+        assert!(fork.data_tree().is_empty());
+        let new_v1 = fork
+            .forest()
+            .transaction(|forest| {
+                crate::TransactionalVersionForest(forest).find_path_to_root(new_v2)
+            })
+            .unwrap()[1];
+        assert_eq!(fork.version_info(new_v1).unwrap().author, "alice");
+        fork.checkout(new_v1).unwrap();
+        assert_eq!(
+            contents(fork.data_tree()),
+            vec![(b"key1".into(), b"value1".into())]
+        );
+
+        // The original is untouched.
+        tree.checkout(v1).unwrap();
+        assert_eq!(
+            contents(tree.data_tree()),
+            vec![(b"key1".into(), b"value1".into())]
+        );
+    }
+
+    #[test]
+    fn fork_only_into_empty_trees() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let v1 = tree.commit(&[insert(b"key1", b"value1")]).unwrap();
+        let num_versions = tree.forest().collect_versions().unwrap().len();
+
+        assert_eq!(
+            tree.fork(v1, true, tree.data_tree().clone()).err(),
+            Some(TransactionError::Abort(SnapshotError::TargetIsDataTree(
+                tree.data_tree().name()
+            )))
+        );
+        let used = fixture.db.open_tree("used").unwrap();
+        used.insert(b"key0", b"value0").unwrap();
+        assert_eq!(
+            tree.fork(v1, true, used.clone()).err(),
+            Some(TransactionError::Abort(SnapshotError::TargetTreeNotEmpty(
+                used.name()
+            )))
+        );
+
+        assert_eq!(
+            tree.forest().collect_versions().unwrap().len(),
+            num_versions
+        );
+        assert_eq!(
+            contents(tree.data_tree()),
+            vec![(b"key1".into(), b"value1".into())]
+        );
+        assert_eq!(contents(&used), vec![(b"key0".into(), b"value0".into())]);
+    }
+
+    fn insert(key: &[u8], value: &[u8]) -> Delta<IVec> {
+        Delta::Insert(IVec::from(key), IVec::from(value))
+    }
+
+    fn contents(tree: &Tree) -> Vec<(IVec, IVec)> {
+        tree.iter().collect::<Result<_, _>>().unwrap()
+    }
+}
@@ -8,28 +8,57 @@
 //!
 //! You are free to provide any [`sled::Tree`] as the root version of a tree, but once more snapshots are created, you must use
 //! one of the functions in the [`transactions`] module to update your data tree; **manual updates to your data tree void the
-//! warranty** (your data tree will get out of sync with the snapshot tree).
+//! warranty** (your data tree will get out of sync with the snapshot tree). Code that needs to write to the data tree like a
+//! plain [`sled::Tree`] can use a [`RecordingTree`] instead, which records every write in the current version. The one method
+//! that doesn't match [`sled::Tree`] is [`RecordingTree::apply_batch`], which takes a [`RecordingBatch`] instead of a
+//! [`sled::Batch`].
 //!
 //! Each snapshot tree has a "current" version which indicates the current state of your data tree. By calling
 //! [`set_current_version`](crate::transactions::set_current_version), you can restore the state of your data tree to that of any
 //! snapshot. If the current version has no children, you can modify it as much as you want with
 //! [`modify_current_leaf_snapshot`](crate::transactions::modify_current_leaf_snapshot). Once you want to freeze the state of the
 //! current version, create a child snapshot with [`create_child_snapshot`](crate::transactions::create_child_snapshot).
+//! The forest remembers the current version of each tree, so you can always look it up with
+//! [`current_version`](crate::transactions::current_version) or [`VersionForest::current_version`].
 //!
 //! All operations on the forest are transactional. See the [`transactions`] module for all supported operations on a snapshot
 //! forest. Note that none of these operations will flush for you!
 //!
+//! Versions are just opaque [`u64`]s, so you can give them names with [`tag`](crate::transactions::tag) and look them up
+//! again with [`resolve_tag`](crate::transactions::resolve_tag). Tags live in a separate tree, opened with [`open_tag_map`].
+//!
+//! Branches work much like they do in git. Each [`Branch`] is a named pointer to a version. Use
+//! [`create_branch`](crate::transactions::create_branch) to start one and [`checkout_branch`](crate::transactions::checkout_branch)
+//! to restore its tip. Only the checked-out branch advances when a new current snapshot is created from its tip, so branches
+//! that start at the same version can diverge.
+//!
+//! A single snapshot tree can also version several data trees at once, e.g. a table and its secondary indexes. Any function in
+//! the [`transactions`] module that takes a data tree accepts a [`TransactionalDataTrees`], whose deltas are keyed by the
+//! index of the member tree, so that every snapshot covers all of the members.
+//!
+//! To version an entire [`sled::Db`], including the creation and removal of trees, use a [`DbSnapshotTree`].
+//!
+//! A whole snapshot tree, with its full history, can be moved to another `Db` with [`export_snapshot_tree`] and
+//! [`import_snapshot_tree`].
+//!
+//! Operations on the forest panic if they find its invariants broken, e.g. after its trees were written to directly. To find
+//! such problems ahead of time, [`check_forest`] reports every broken invariant without modifying anything. Then
+//! [`repair_forest`] fixes what it safely can and marks the versions whose history was lost, keeping the rest of the history.
+//!
+//! If you only need to version a single data tree, [`SnapshotTree`] bundles the data tree with its snapshot tree and runs each
+//! operation in its own transaction, so you don't have to assemble the transactional trees yourself.
+//!
 //! # Implementation
 //!
-//! The snapshot forest is implemented on top of two [`sled::Tree`]s. One is the [`VersionForest`] which stores the version
-//! [`u64`] of every snapshot as a vertex in a bidirectional graph, specifically a tree. The other is the [`DeltaMap`], which
+//! The snapshot forest is implemented on top of three [`sled::Tree`]s. One is the [`VersionForest`] which stores the version
+//! [`u64`] of every snapshot as a vertex in a bidirectional graph, specifically a tree. Another is the [`DeltaMap`], which
 //! stores a set of deltas for each snapshot. This enables snapshots to take up relatively little space, only remembering what
-//! changes between each version.
+//! changes between each version. The last is the [`TagMap`], which gives memorable names to versions.
 //!
 //! # Example
 //!
 //! ```rust
-//! # fn run_demo() -> sled::transaction::TransactionResult<()> {
+//! # fn run_demo() -> sled::transaction::TransactionResult<(), sled_snapshots::SnapshotError> {
 //! use sled::{IVec, Transactional};
 //! use sled_snapshots::{transactions::*, *};
 //!
@@ -86,30 +115,77 @@
 
 use sled::Db;
 
+mod branch;
+mod check;
+mod compaction;
+mod data_tree;
+mod db_snapshot_tree;
 mod delta;
 mod delta_map;
 mod delta_node;
 mod delta_set;
+mod error;
+mod export;
+mod fork;
+mod materialize;
+mod merge;
+mod recording_tree;
+mod repair;
+mod retention;
+mod snapshot_iter;
+mod snapshot_tree;
+mod tag_map;
+#[cfg(test)]
+mod test_util;
+mod time_travel;
 mod version_forest;
+mod version_info;
 mod version_node;
 
 pub mod transactions;
 
+pub use branch::Branch;
+pub use check::{check_forest, ForestReport, Inconsistency};
+pub use compaction::{compact_all, compact_incremental, BackgroundCompactor};
+pub use data_tree::{DataTree, TransactionalDataTrees};
+pub use db_snapshot_tree::DbSnapshotTree;
 pub use delta::Delta;
 pub use delta_map::*;
+pub use error::SnapshotError;
+pub use export::{export_snapshot_tree, import_snapshot_tree};
+pub use fork::fork_snapshot_tree;
+pub use materialize::materialize_version;
+pub use merge::{Conflict, MergeOutcome, Resolution};
+pub use recording_tree::{RecordingBatch, RecordingTransactionalTree, RecordingTree};
+pub use repair::{repair_forest, RepairAction, RepairMode};
+pub use retention::{RetentionPolicy, RetentionTier};
+pub use snapshot_iter::{iter_at_version, range_at_version, SnapshotIter};
+pub use snapshot_tree::SnapshotTree;
+pub use tag_map::*;
+pub use time_travel::restore_to_time;
 pub use version_forest::*;
+pub use version_info::VersionInfo;
 
 /// Opens two `sled::Tree`s in `db` which represent a "snapshot forest."
 ///
-/// This doesn't actually mutate the `sled::Tree`s. It's just for convenience and a little extra type safety.
+/// This is mostly for convenience and a little extra type safety. The only write is the first time a forest is opened: it
+/// records the encoding format of the forest, and upgrades a forest that was written by an older version of this crate.
 ///
 /// The `VersionForest` will be called `"${name}-versions"`, and it stores the version forest, i.e. a set of versions where each
 /// version is a node in some tree. The `DeltaMap` will be called `"${name}-deltas"`, and it stores a set of deltas for each
 /// version.
 pub fn open_snapshot_forest(db: &Db, name: &str) -> sled::Result<(VersionForest, DeltaMap)> {
-    let version_forest = db.open_tree(format!("{}-versions", name))?;
-    let delta_map = db.open_tree(format!("{}-deltas", name))?;
-    Ok((VersionForest(version_forest), DeltaMap(delta_map)))
+    let version_forest = VersionForest(db.open_tree(format!("{}-versions", name))?);
+    let delta_map = DeltaMap(db.open_tree(format!("{}-deltas", name))?);
+    version_forest.migrate(&delta_map)?;
+    Ok((version_forest, delta_map))
+}
+
+/// Opens the [`TagMap`] of the snapshot forest called `name` in `db`.
+///
+/// The `TagMap` will be called `"${name}-tags"`, and it stores the names given to versions.
+pub fn open_tag_map(db: &Db, name: &str) -> sled::Result<TagMap> {
+    Ok(TagMap(db.open_tree(format!("{}-tags", name))?))
 }
 
 fn u64_from_be_slice(s: &[u8]) -> u64 {
@@ -0,0 +1,172 @@
+use crate::{
+    transactions::net_deltas_to_version, DeltaMap, SnapshotError, TransactionalDeltaMap,
+    TransactionalVersionForest, VersionForest,
+};
+
+use sled::{
+    transaction::{TransactionError, TransactionResult},
+    Batch, Transactional, Tree,
+};
+
+/// Writes every key-value pair of the `version` snapshot into `target_tree`, without changing the current version or
+/// `data_tree`.
+///
+/// `target_tree` must be empty and must not be `data_tree` itself, so that no data is lost by mistake. Otherwise this fails with
+/// [`SnapshotError::TargetTreeNotEmpty`] or [`SnapshotError::TargetIsDataTree`] before anything is written.
+///
+/// `target_tree` is just a copy, so it can be read at leisure (e.g. by long-running queries) while the snapshot tree moves on.
+/// Other writers may keep creating and modifying snapshots on top of the current version in the meantime. However, if
+/// `version` is itself the current version, it must not be modified until this returns.
+///
+/// # Implementation Details
+///
+/// `sled` can't scan a tree in a transaction, so `data_tree` is first copied into `target_tree` with a plain scan. Then a
+/// single transaction gathers the net change from the current version to `version`, which overrides the copied values. Any key
+/// written during the scan went through a new or modified snapshot on top of the old current version, so its reverse delta is
+/// on that path and it gets overridden too. This doesn't hold if the current version was restored to a different branch
+/// during the scan, in which case the current version from before the scan is no longer an ancestor of the current version,
+/// and we start over with an empty `target_tree`.
+pub fn materialize_version(
+    version: u64,
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    data_tree: &Tree,
+    target_tree: &Tree,
+) -> TransactionResult<(), SnapshotError> {
+    check_target_tree(data_tree, target_tree)?;
+
+    loop {
+        let start_version = forest
+            .transaction(|forest| TransactionalVersionForest(forest).current_version_of(version))?;
+
+        // Only undoes our own copy from the last attempt.
+        target_tree.clear()?;
+        for kv in data_tree.iter() {
+            let (key, value) = kv?;
+            target_tree.insert(key, value)?;
+        }
+
+        let net_deltas = (&**forest, &**delta_map).transaction(|(forest, delta_map)| {
+            let forest = TransactionalVersionForest(forest);
+            let current_version = forest.current_version_of(version)?;
+            if !forest
+                .find_path_to_root(current_version)?
+                .contains(&start_version)
+            {
+                return Ok(None);
+            }
+            net_deltas_to_version(version, forest, TransactionalDeltaMap(delta_map)).map(Some)
+        })?;
+
+        if let Some(net_deltas) = net_deltas {
+            let mut batch = Batch::default();
+            for (key, value) in net_deltas {
+                match value {
+                    Some(value) => batch.insert(key, value),
+                    None => batch.remove(key),
+                }
+            }
+            target_tree.apply_batch(batch)?;
+            return Ok(());
+        }
+    }
+}
+
+/// Fails unless `target_tree` is empty and isn't `data_tree`, which is recognized by its name.
+pub(crate) fn check_target_tree(
+    data_tree: &Tree,
+    target_tree: &Tree,
+) -> TransactionResult<(), SnapshotError> {
+    if target_tree.name() == data_tree.name() {
+        return Err(TransactionError::Abort(SnapshotError::TargetIsDataTree(
+            target_tree.name(),
+        )));
+    }
+    if !target_tree.is_empty() {
+        return Err(TransactionError::Abort(SnapshotError::TargetTreeNotEmpty(
+            target_tree.name(),
+        )));
+    }
+    Ok(())
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use crate::{test_util::Fixture, Delta, SnapshotError};
+
+    use sled::{transaction::TransactionError, IVec, Tree};
+
+    #[test]
+    fn materialize_leaves_data_tree_alone() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        tree.data_tree().insert(b"key0", b"value0").unwrap();
+        let root = tree.root();
+        let v1 = tree
+            .commit(&[
+                Delta::Remove(IVec::from(b"key0")),
+                Delta::Insert(IVec::from(b"key1"), IVec::from(b"value1")),
+            ])
+            .unwrap();
+        tree.commit(&[Delta::Insert(IVec::from(b"key2"), IVec::from(b"value2"))])
+            .unwrap();
+        let current = tree.current_version().unwrap();
+
+        let target = fixture.db.open_tree("target").unwrap();
+        tree.materialize(root, &target).unwrap();
+        assert_eq!(contents(&target), vec![(b"key0".into(), b"value0".into())]);
+
+        let target = fixture.db.open_tree("target1").unwrap();
+        tree.materialize(v1, &target).unwrap();
+        assert_eq!(contents(&target), vec![(b"key1".into(), b"value1".into())]);
+
+        assert_eq!(tree.current_version(), Ok(current));
+        assert_eq!(
+            contents(tree.data_tree()),
+            vec![
+                (b"key1".into(), b"value1".into()),
+                (b"key2".into(), b"value2".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn materialize_only_into_empty_trees() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+        tree.commit(&[Delta::Insert(IVec::from(b"key1"), IVec::from(b"value1"))])
+            .unwrap();
+
+        let target = fixture.db.open_tree("target").unwrap();
+        target.insert(b"stale", b"value").unwrap();
+        assert_eq!(
+            tree.materialize(root, &target),
+            Err(TransactionError::Abort(SnapshotError::TargetTreeNotEmpty(
+                target.name()
+            )))
+        );
+        assert_eq!(contents(&target), vec![(b"stale".into(), b"value".into())]);
+
+        assert_eq!(
+            tree.materialize(root, tree.data_tree()),
+            Err(TransactionError::Abort(SnapshotError::TargetIsDataTree(
+                tree.data_tree().name()
+            )))
+        );
+        assert_eq!(
+            contents(tree.data_tree()),
+            vec![(b"key1".into(), b"value1".into())]
+        );
+    }
+
+    fn contents(tree: &Tree) -> Vec<(IVec, IVec)> {
+        tree.iter().collect::<Result<_, _>>().unwrap()
+    }
+}
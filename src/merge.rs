@@ -0,0 +1,35 @@
+use sled::IVec;
+
+/// A key that was changed differently on both sides of a merge.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Conflict {
+    pub key: IVec,
+    /// The value in the common ancestor, or `None` if the key was absent.
+    pub base: Option<IVec>,
+    /// The value in the version being merged into.
+    pub ours: Option<IVec>,
+    /// The value in the version being merged from.
+    pub theirs: Option<IVec>,
+}
+
+/// How to settle a [`Conflict`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Resolution {
+    /// Keep the value from the version being merged into.
+    Ours,
+    /// Take the value from the version being merged from.
+    Theirs,
+    /// Use this value instead. `None` removes the key.
+    Value(Option<IVec>),
+    /// Abort the transaction with [`SnapshotError::UnresolvedConflict`](crate::SnapshotError::UnresolvedConflict).
+    Abort,
+}
+
+/// The result of a successful merge.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MergeOutcome {
+    /// The new snapshot holding the merged state. It is the new current version.
+    pub version: u64,
+    /// Every conflict that was passed to the resolver, sorted by key.
+    pub conflicts: Vec<Conflict>,
+}
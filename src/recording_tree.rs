@@ -0,0 +1,341 @@
+use crate::{
+    delta::Delta, transactions::modify_current_leaf_snapshot, SnapshotError, SnapshotTree,
+    TransactionalDeltaMap, TransactionalVersionForest,
+};
+
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionResult,
+        TransactionalTree, UnabortableTransactionError,
+    },
+    CompareAndSwapError, IVec, Transactional,
+};
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+/// Wraps the data tree of a [`SnapshotTree`] with the same writing methods as a [`sled::Tree`], except that every write is
+/// recorded in the current version with [`modify_current_leaf_snapshot`].
+///
+/// This is a drop-in replacement for code that writes to the data tree directly, which would otherwise get the data tree out
+/// of sync with its snapshots. The current version must be a leaf, and each write becomes part of it. To freeze the current
+/// version, create a child snapshot with [`SnapshotTree::commit`], e.g. `tree.snapshot_tree().commit(&[])`, and later writes
+/// will go to the child.
+///
+/// Each method runs in its own transaction over the data tree, [`VersionForest`](crate::VersionForest) and
+/// [`DeltaMap`](crate::DeltaMap). Use [`RecordingTree::transaction`] to group several writes.
+///
+/// The only method that differs from [`sled::Tree`] is [`RecordingTree::apply_batch`], which takes a [`RecordingBatch`]
+/// instead of a [`sled::Batch`].
+#[derive(Clone)]
+pub struct RecordingTree {
+    snapshot_tree: SnapshotTree,
+}
+
+impl RecordingTree {
+    pub fn new(snapshot_tree: SnapshotTree) -> Self {
+        Self { snapshot_tree }
+    }
+
+    pub fn snapshot_tree(&self) -> &SnapshotTree {
+        &self.snapshot_tree
+    }
+
+    pub fn into_snapshot_tree(self) -> SnapshotTree {
+        self.snapshot_tree
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> sled::Result<Option<IVec>> {
+        self.snapshot_tree.data_tree().get(key)
+    }
+
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> sled::Result<bool> {
+        self.snapshot_tree.data_tree().contains_key(key)
+    }
+
+    pub fn iter(&self) -> sled::Iter {
+        self.snapshot_tree.data_tree().iter()
+    }
+
+    pub fn range<K, R>(&self, range: R) -> sled::Iter
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.snapshot_tree.data_tree().range(range)
+    }
+
+    pub fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> sled::Iter {
+        self.snapshot_tree.data_tree().scan_prefix(prefix)
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshot_tree.data_tree().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshot_tree.data_tree().is_empty()
+    }
+
+    /// Inserts `(key, value)`, returning the old value of `key`, if any.
+    pub fn insert<K, V>(&self, key: K, value: V) -> TransactionResult<Option<IVec>, SnapshotError>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        let value = value.into();
+        self.transaction(|tree| tree.insert(key.as_ref(), value.clone()))
+    }
+
+    /// Removes `key`, returning its old value, if any.
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> TransactionResult<Option<IVec>, SnapshotError> {
+        self.transaction(|tree| tree.remove(key.as_ref()))
+    }
+
+    /// Sets `key` to `new` if its current value is `old`, where `None` means that the key is absent. Otherwise, returns the
+    /// current value in a [`CompareAndSwapError`].
+    pub fn compare_and_swap<K, OV, NV>(
+        &self,
+        key: K,
+        old: Option<OV>,
+        new: Option<NV>,
+    ) -> TransactionResult<Result<(), CompareAndSwapError>, SnapshotError>
+    where
+        K: AsRef<[u8]>,
+        OV: AsRef<[u8]>,
+        NV: Into<IVec>,
+    {
+        let key = key.as_ref();
+        let new = new.map(Into::into);
+        self.transaction(|tree| {
+            let current = tree.get(key)?;
+            if current.as_deref() != old.as_ref().map(AsRef::as_ref) {
+                return Ok(Err(CompareAndSwapError {
+                    current,
+                    proposed: new.clone(),
+                }));
+            }
+            match &new {
+                Some(value) => tree.insert(key, value.clone())?,
+                None => tree.remove(key)?,
+            };
+            Ok(Ok(()))
+        })
+    }
+
+    /// Applies all writes in `batch` atomically.
+    ///
+    /// Unlike [`sled::Tree::apply_batch`], this takes a [`RecordingBatch`], since the writes in a [`sled::Batch`] can't be read
+    /// back out to record them. Code that builds a [`sled::Batch`] has to build a [`RecordingBatch`] instead, which has the
+    /// same `insert` and `remove` methods.
+    pub fn apply_batch(&self, batch: RecordingBatch) -> TransactionResult<(), SnapshotError> {
+        self.transaction(|tree| tree.apply_batch(&batch))
+    }
+
+    /// Runs `f` in a transaction, like [`sled::Tree::transaction`], where every write is recorded in the current version.
+    ///
+    /// Aborts with [`SnapshotError::NotLeafVersion`] (converted into `E`) on the first write if the current version isn't a
+    /// leaf.
+    pub fn transaction<F, A, E>(&self, f: F) -> TransactionResult<A, E>
+    where
+        F: Fn(&RecordingTransactionalTree<E>) -> ConflictableTransactionResult<A, E>,
+        E: From<SnapshotError>,
+    {
+        let snapshot_tree = &self.snapshot_tree;
+        (
+            snapshot_tree.data_tree(),
+            &**snapshot_tree.forest(),
+            &**snapshot_tree.delta_map(),
+        )
+            .transaction(|(data_tree, forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let current_version = convert_abort(forest.current_version(snapshot_tree.root()))?;
+                f(&RecordingTransactionalTree {
+                    data_tree,
+                    forest,
+                    delta_map: TransactionalDeltaMap(delta_map),
+                    current_version,
+                    error: PhantomData,
+                })
+            })
+    }
+}
+
+/// The view of a [`RecordingTree`] inside of [`RecordingTree::transaction`].
+pub struct RecordingTransactionalTree<'a, E = SnapshotError> {
+    data_tree: &'a TransactionalTree,
+    forest: TransactionalVersionForest<'a>,
+    delta_map: TransactionalDeltaMap<'a>,
+    current_version: u64,
+    error: PhantomData<fn() -> E>,
+}
+
+impl<'a, E: From<SnapshotError>> RecordingTransactionalTree<'a, E> {
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>, UnabortableTransactionError> {
+        self.data_tree.get(key)
+    }
+
+    /// Inserts `(key, value)`, returning the old value of `key`, if any.
+    pub fn insert<K, V>(&self, key: K, value: V) -> ConflictableTransactionResult<Option<IVec>, E>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        let key = IVec::from(key.as_ref());
+        let old_value = self.data_tree.get(&key)?;
+        self.record(&[Delta::Insert(key, value.into())])?;
+        Ok(old_value)
+    }
+
+    /// Removes `key`, returning its old value, if any.
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> ConflictableTransactionResult<Option<IVec>, E> {
+        let key = IVec::from(key.as_ref());
+        let old_value = self.data_tree.get(&key)?;
+        if old_value.is_some() {
+            self.record(&[Delta::Remove(key)])?;
+        }
+        Ok(old_value)
+    }
+
+    /// Applies all writes in `batch`.
+    pub fn apply_batch(&self, batch: &RecordingBatch) -> ConflictableTransactionResult<(), E> {
+        let deltas: Vec<_> = batch
+            .writes
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => Delta::Insert(key.clone(), value.clone()),
+                None => Delta::Remove(key.clone()),
+            })
+            .collect();
+        self.record(&deltas)
+    }
+
+    fn record(&self, deltas: &[Delta<IVec>]) -> ConflictableTransactionResult<(), E> {
+        convert_abort(modify_current_leaf_snapshot(
+            self.current_version,
+            self.forest,
+            self.delta_map,
+            self.data_tree,
+            deltas,
+        ))
+    }
+}
+
+/// A batch of writes for [`RecordingTree::apply_batch`], like a [`sled::Batch`].
+///
+/// A [`sled::Batch`] can't be used, because its writes can't be read back out to record them.
+#[derive(Clone, Debug, Default)]
+pub struct RecordingBatch {
+    writes: BTreeMap<IVec, Option<IVec>>,
+}
+
+impl RecordingBatch {
+    /// Set a key to a new value.
+    pub fn insert<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<IVec>,
+        V: Into<IVec>,
+    {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    /// Remove a key.
+    pub fn remove<K: Into<IVec>>(&mut self, key: K) {
+        self.writes.insert(key.into(), None);
+    }
+}
+
+fn convert_abort<T, E: From<SnapshotError>>(
+    result: ConflictableTransactionResult<T, SnapshotError>,
+) -> ConflictableTransactionResult<T, E> {
+    result.map_err(|error| match error {
+        ConflictableTransactionError::Abort(error) => {
+            ConflictableTransactionError::Abort(error.into())
+        }
+        ConflictableTransactionError::Conflict => ConflictableTransactionError::Conflict,
+        ConflictableTransactionError::Storage(error) => {
+            ConflictableTransactionError::Storage(error)
+        }
+    })
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Fixture;
+
+    use sled::transaction::{abort, TransactionError};
+
+    #[test]
+    fn writes_are_recorded_in_snapshots() {
+        let fixture = Fixture::open();
+        let tree = create_recording_tree(&fixture);
+        let root = tree.snapshot_tree().root();
+
+        // Writes to the root go straight to the data tree.
+        assert_eq!(tree.insert(b"key0", b"value0"), Ok(None));
+        let v1 = tree.snapshot_tree().commit(&[]).unwrap();
+
+        assert_eq!(
+            tree.insert(b"key0", b"value1"),
+            Ok(Some(IVec::from(b"value0")))
+        );
+        assert_eq!(
+            tree.compare_and_swap(b"key0", Some(b"value0"), Some(b"value2")),
+            Ok(Err(CompareAndSwapError {
+                current: Some(IVec::from(b"value1")),
+                proposed: Some(IVec::from(b"value2")),
+            }))
+        );
+        assert_eq!(
+            tree.compare_and_swap(b"key1", None::<&[u8]>, Some(b"value1")),
+            Ok(Ok(()))
+        );
+        let mut batch = RecordingBatch::default();
+        batch.insert(b"key2", b"value2");
+        batch.remove(b"key0");
+        tree.apply_batch(batch).unwrap();
+
+        // An aborted transaction records nothing.
+        let result: TransactionResult<(), SnapshotError> = tree.transaction(|tree| {
+            tree.remove(b"key1")?;
+            abort(SnapshotError::VersionNotFound(0))
+        });
+        assert_eq!(
+            result,
+            Err(TransactionError::Abort(SnapshotError::VersionNotFound(0)))
+        );
+
+        // Freeze v1 with the writes above.
+        tree.snapshot_tree().commit(&[]).unwrap();
+
+        tree.snapshot_tree().checkout(root).unwrap();
+        assert_eq!(
+            tree.iter().collect::<Result<Vec<_>, _>>(),
+            Ok(vec![(IVec::from(b"key0"), IVec::from(b"value0"))])
+        );
+        assert_eq!(
+            tree.insert(b"key0", b"value1"),
+            Err(TransactionError::Abort(SnapshotError::NotLeafVersion(root)))
+        );
+
+        tree.snapshot_tree().checkout(v1).unwrap();
+        assert_eq!(
+            tree.iter().collect::<Result<Vec<_>, _>>(),
+            Ok(vec![
+                (IVec::from(b"key1"), IVec::from(b"value1")),
+                (IVec::from(b"key2"), IVec::from(b"value2"))
+            ])
+        );
+    }
+
+    fn create_recording_tree(fixture: &Fixture) -> RecordingTree {
+        RecordingTree::new(fixture.create_snapshot_tree())
+    }
+}
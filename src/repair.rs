@@ -0,0 +1,442 @@
+use crate::{
+    check::walk_delta_list,
+    delta_node::{HeadDeltaNode, RawDeltaNode, RawHeadDeltaNode},
+    u64_from_be_slice,
+    version_node::{RawVersionNode, VersionNode},
+    DeltaMap, VersionForest,
+};
+
+use sled::IVec;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem;
+
+/// Whether [`repair_forest`] writes its repairs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RepairMode {
+    /// Only report what would be repaired.
+    DryRun,
+    /// Repair the forest.
+    Apply,
+}
+
+/// A single repair made by [`repair_forest`], or in a dry run, a repair that would be made.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RepairAction {
+    /// The children of `version` were replaced with the versions whose parent pointers point to it.
+    RebuildChildren { version: u64, children: Vec<u64> },
+    /// The head of the delta list of the version couldn't be decoded, so the list was emptied.
+    ResetDeltaList(u64),
+    /// The delta list of `version` was cut off after `node`, which pointed to `next_key`.
+    TruncateDeltaList {
+        version: u64,
+        node: u64,
+        next_key: u64,
+    },
+    /// The tail key in the head of the delta list of `version` was set to the key of its last node.
+    SetTailKey { version: u64, tail_key: Option<u64> },
+    /// The delta list node was not in any delta list, so it was deleted.
+    DeleteDeltaNode(u64),
+    /// Restoring the version needs deltas that were lost, so it was marked as unrestorable.
+    MarkUnrestorable(u64),
+}
+
+impl fmt::Display for RepairAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RebuildChildren { version, children } => {
+                write!(
+                    f,
+                    "set the children of version {} to {:?}",
+                    version, children
+                )
+            }
+            Self::ResetDeltaList(v) => write!(f, "emptied the delta list of version {}", v),
+            Self::TruncateDeltaList {
+                version,
+                node,
+                next_key,
+            } => write!(
+                f,
+                "cut off the delta list of version {} after node {}, which pointed to {}",
+                version, node, next_key
+            ),
+            Self::SetTailKey { version, tail_key } => write!(
+                f,
+                "set the tail of the delta list of version {} to {:?}",
+                version, tail_key
+            ),
+            Self::DeleteDeltaNode(k) => write!(f, "deleted orphaned delta list node {}", k),
+            Self::MarkUnrestorable(v) => write!(f, "marked version {} as unrestorable", v),
+        }
+    }
+}
+
+/// Repairs the problems that [`check_forest`](crate::check_forest) finds in `forest` and `delta_map`, as far as that can be
+/// done safely, and returns a log of every repair. With [`RepairMode::DryRun`], nothing is modified, but the log is the same.
+///
+/// Parent pointers are trusted over child pointers, so the children of every version are rebuilt from the parent pointers of
+/// the other versions. Delta lists are cut off before the first node that can't be followed, the tail key of every delta list
+/// is set to its last node, and delta list nodes that aren't in any delta list are deleted. Any version that can't be restored
+/// anymore, because it has no deltas or its own deltas or the deltas of a version on its path to the current version were cut
+/// off, is marked, and transactions that would need to restore it abort with
+/// [`SnapshotError::UnrestorableVersion`](crate::SnapshotError::UnrestorableVersion). Other versions keep working as before.
+///
+/// Problems that can't be repaired without losing track of the current state of a data tree, like a missing, malformed or
+/// invalid current version or a broken parent pointer, are left alone. Run [`check_forest`](crate::check_forest) afterwards to
+/// find them.
+///
+/// `sled` can't scan trees in a transaction, so the forest must not be modified while it is being repaired. The repairs are not
+/// atomic either, but versions are marked before any deltas are cut off, so running this again after an interruption finishes
+/// the job.
+pub fn repair_forest(
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    mode: RepairMode,
+) -> sled::Result<Vec<RepairAction>> {
+    let actions = plan_repairs(forest, delta_map)?;
+    if mode == RepairMode::Apply {
+        // Marks go first, so that no version can be restored from lost deltas if the repair is interrupted.
+        let (marks, repairs): (Vec<_>, Vec<_>) = actions
+            .iter()
+            .partition(|action| matches!(action, RepairAction::MarkUnrestorable(_)));
+        for action in marks.into_iter().chain(repairs) {
+            apply_repair(action, forest, delta_map)?;
+        }
+    }
+    Ok(actions)
+}
+
+fn plan_repairs(forest: &VersionForest, delta_map: &DeltaMap) -> sled::Result<Vec<RepairAction>> {
+    let mut actions = Vec::new();
+
+    let mut nodes = BTreeMap::new();
+    let mut versions = BTreeSet::new();
+    for kv in forest.iter_version_nodes() {
+        let (version, bytes) = kv?;
+        versions.insert(version);
+        let raw_node = RawVersionNode::new(bytes);
+        if raw_node.is_well_formed() {
+            nodes.insert(version, VersionNode::from(raw_node));
+        }
+    }
+
+    let mut children_of: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    for (&version, node) in nodes.iter() {
+        if let Some(parent) = node.parent {
+            children_of.entry(parent).or_default().push(version);
+        }
+    }
+    for (&version, node) in nodes.iter_mut() {
+        let children = children_of.remove(&version).unwrap_or_default();
+        // Keep the order of the children that were already listed.
+        let mut rebuilt_children = Vec::with_capacity(children.len());
+        for &child in node.children.iter().chain(children.iter()) {
+            if children.contains(&child) && !rebuilt_children.contains(&child) {
+                rebuilt_children.push(child);
+            }
+        }
+        if rebuilt_children != node.children {
+            node.children = rebuilt_children;
+            actions.push(RepairAction::RebuildChildren {
+                version,
+                children: node.children.clone(),
+            });
+        }
+    }
+
+    let mut owners = BTreeMap::new();
+    let mut reachable = BTreeSet::new();
+    let mut lost_deltas = BTreeSet::new();
+    let mut versions_without_deltas = BTreeSet::new();
+    for &version in versions.iter() {
+        let head = if let Some(head) = delta_map.get(version.to_be_bytes())? {
+            RawHeadDeltaNode::new(head)
+        } else {
+            versions_without_deltas.insert(version);
+            continue;
+        };
+        if !head.is_well_formed() {
+            actions.push(RepairAction::ResetDeltaList(version));
+            lost_deltas.insert(version);
+            continue;
+        }
+
+        let walk = walk_delta_list(version, &head, &versions, delta_map, &mut owners)?;
+        if let Some(unfollowed_key) = walk.unfollowed_key {
+            actions.push(RepairAction::TruncateDeltaList {
+                version,
+                node: walk.nodes.last().copied().unwrap_or(version),
+                next_key: unfollowed_key,
+            });
+            lost_deltas.insert(version);
+        }
+        reachable.extend(walk.nodes.iter().copied());
+
+        let tail_key = walk.nodes.last().copied();
+        if head.tail_key() != tail_key {
+            actions.push(RepairAction::SetTailKey { version, tail_key });
+        }
+    }
+
+    for key in delta_map.iter().keys() {
+        let key = key?;
+        if key.len() != mem::size_of::<u64>() {
+            continue;
+        }
+        let node = u64_from_be_slice(&key);
+        if versions.contains(&node) || reachable.contains(&node) {
+            continue;
+        }
+        actions.push(RepairAction::DeleteDeltaNode(node));
+    }
+
+    let mut current_versions = BTreeMap::new();
+    for record in forest.iter_current_versions() {
+        if let (root, Some(current)) = record? {
+            current_versions.insert(root, current);
+        }
+    }
+    let mut paths_to_current = BTreeMap::new();
+    for (&root, &current) in current_versions.iter() {
+        match find_path_to_root(current, &nodes) {
+            Some(path) if path.last() == Some(&root) => {
+                paths_to_current.insert(root, path);
+            }
+            _ => (),
+        }
+    }
+    for &version in nodes.keys() {
+        let path = if let Some(path) = find_path_to_root(version, &nodes) {
+            path
+        } else {
+            continue;
+        };
+        let current_path =
+            if let Some(current_path) = path.last().and_then(|root| paths_to_current.get(root)) {
+                current_path
+            } else {
+                continue;
+            };
+
+        // Restoring `version` applies the deltas of every version between the current version and `version`.
+        let join = path
+            .iter()
+            .position(|v| current_path.contains(v))
+            .expect("Both paths end at the root");
+        let current_join = current_path.iter().position(|&v| v == path[join]).unwrap();
+        let current = current_path[0];
+        let needs_lost_deltas = path[..=join]
+            .iter()
+            .chain(current_path[..=current_join].iter())
+            .any(|&v| {
+                v != current && (lost_deltas.contains(&v) || versions_without_deltas.contains(&v))
+            });
+        if needs_lost_deltas && forest.is_restorable(version)? {
+            actions.push(RepairAction::MarkUnrestorable(version));
+        }
+    }
+
+    Ok(actions)
+}
+
+fn apply_repair(
+    action: &RepairAction,
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+) -> sled::Result<()> {
+    match action {
+        RepairAction::RebuildChildren { version, children } => {
+            let mut node = VersionNode::from(RawVersionNode::new(
+                forest
+                    .get(version.to_be_bytes())?
+                    .expect("Repaired version was just read"),
+            ));
+            node.children = children.clone();
+            forest.insert(version.to_be_bytes(), IVec::from(&node))?;
+        }
+        RepairAction::ResetDeltaList(version) => {
+            delta_map.insert(
+                version.to_be_bytes(),
+                IVec::from(&HeadDeltaNode::new_empty()),
+            )?;
+        }
+        RepairAction::TruncateDeltaList { version, node, .. } if node == version => {
+            delta_map.insert(
+                version.to_be_bytes(),
+                IVec::from(&HeadDeltaNode::new_empty()),
+            )?;
+        }
+        RepairAction::TruncateDeltaList { node, .. } => {
+            let mut raw_node = RawDeltaNode::new(
+                delta_map
+                    .get(node.to_be_bytes())?
+                    .expect("Repaired delta list node was just read")
+                    .to_vec(),
+            );
+            raw_node.set_next_key(None);
+            delta_map.insert(node.to_be_bytes(), raw_node.take_bytes())?;
+        }
+        RepairAction::SetTailKey { version, tail_key } => {
+            let head = RawHeadDeltaNode::new(
+                delta_map
+                    .get(version.to_be_bytes())?
+                    .expect("Repaired delta list was just read"),
+            );
+            let new_head = match (head.next_key(), tail_key) {
+                (Some(next_key), Some(tail_key)) => HeadDeltaNode::new(next_key, *tail_key),
+                _ => HeadDeltaNode::new_empty(),
+            };
+            delta_map.insert(version.to_be_bytes(), IVec::from(&new_head))?;
+        }
+        RepairAction::DeleteDeltaNode(node) => {
+            delta_map.remove(node.to_be_bytes())?;
+        }
+        RepairAction::MarkUnrestorable(version) => forest.mark_unrestorable(*version)?,
+    }
+    Ok(())
+}
+
+/// Follows parent pointers from `version` to its root. Returns `None` if they dangle or form a cycle.
+fn find_path_to_root(version: u64, nodes: &BTreeMap<u64, VersionNode>) -> Option<Vec<u64>> {
+    let mut path = vec![version];
+    while let Some(parent) = nodes.get(path.last().unwrap())?.parent {
+        if path.contains(&parent) {
+            return None;
+        }
+        path.push(parent);
+    }
+    Some(path)
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        check_forest, test_util::Fixture, version_forest::current_version_key, Delta, SnapshotError,
+    };
+
+    use sled::transaction::TransactionError;
+
+    #[test]
+    fn repair_keeps_restorable_versions() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+        for i in 0..3u8 {
+            tree.commit(&[Delta::Insert(IVec::from(&[i]), IVec::from(b"value"))])
+                .unwrap();
+        }
+        let versions = tree.forest().collect_versions().unwrap();
+        let (v1, v2) = (versions[1], versions[2]);
+        let list_node = |version: u64| {
+            let head = tree.delta_map().get(version.to_be_bytes()).unwrap();
+            RawHeadDeltaNode::new(head.unwrap()).next_key().unwrap()
+        };
+        let (v1_node, v2_node) = (list_node(v1), list_node(v2));
+        let node_bytes = tree
+            .delta_map()
+            .get(v1_node.to_be_bytes())
+            .unwrap()
+            .unwrap();
+
+        // The root forgets its child.
+        tree.forest()
+            .insert(
+                root.to_be_bytes(),
+                IVec::from(&VersionNode::new_orphan(root)),
+            )
+            .unwrap();
+        // The only node of v1 points to a missing node.
+        let missing = u64::MAX - 1;
+        let mut raw_node = RawDeltaNode::new(node_bytes.to_vec());
+        raw_node.set_next_key(Some(missing));
+        tree.delta_map()
+            .insert(v1_node.to_be_bytes(), raw_node.take_bytes())
+            .unwrap();
+        // The head of v2 has the wrong tail.
+        tree.delta_map()
+            .insert(
+                v2.to_be_bytes(),
+                IVec::from(&HeadDeltaNode::new(v2_node, missing)),
+            )
+            .unwrap();
+        // A node that isn't in any list.
+        let orphan = missing - 1;
+        tree.delta_map()
+            .insert(orphan.to_be_bytes(), node_bytes)
+            .unwrap();
+
+        let expected_actions = vec![
+            RepairAction::RebuildChildren {
+                version: root,
+                children: vec![v1],
+            },
+            RepairAction::TruncateDeltaList {
+                version: v1,
+                node: v1_node,
+                next_key: missing,
+            },
+            RepairAction::SetTailKey {
+                version: v2,
+                tail_key: Some(v2_node),
+            },
+            RepairAction::DeleteDeltaNode(orphan),
+            RepairAction::MarkUnrestorable(root),
+            RepairAction::MarkUnrestorable(v1),
+        ];
+        let report = check_forest(tree.forest(), tree.delta_map()).unwrap();
+        assert_eq!(
+            repair_forest(tree.forest(), tree.delta_map(), RepairMode::DryRun),
+            Ok(expected_actions.clone())
+        );
+        assert_eq!(check_forest(tree.forest(), tree.delta_map()), Ok(report));
+
+        assert_eq!(
+            repair_forest(tree.forest(), tree.delta_map(), RepairMode::Apply),
+            Ok(expected_actions)
+        );
+        assert!(check_forest(tree.forest(), tree.delta_map())
+            .unwrap()
+            .is_consistent());
+        assert_eq!(
+            repair_forest(tree.forest(), tree.delta_map(), RepairMode::Apply),
+            Ok(vec![])
+        );
+
+        assert_eq!(
+            tree.checkout(v1),
+            Err(TransactionError::Abort(SnapshotError::UnrestorableVersion(
+                v1
+            )))
+        );
+        tree.checkout(v2).unwrap();
+        let contents: Vec<_> = tree.data_tree().iter().keys().collect();
+        assert_eq!(contents, vec![Ok(IVec::from(&[0])), Ok(IVec::from(&[1]))]);
+    }
+
+    #[test]
+    fn repair_leaves_malformed_current_version_alone() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        tree.commit(&[Delta::Insert(IVec::from(b"key"), IVec::from(b"value"))])
+            .unwrap();
+        tree.forest()
+            .insert(current_version_key(tree.root()), b"bad")
+            .unwrap();
+        let report = check_forest(tree.forest(), tree.delta_map()).unwrap();
+
+        assert_eq!(
+            repair_forest(tree.forest(), tree.delta_map(), RepairMode::Apply),
+            Ok(vec![])
+        );
+        assert_eq!(check_forest(tree.forest(), tree.delta_map()), Ok(report));
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Declarative rules for which versions of a snapshot tree to keep, used by [`prune`](crate::transactions::prune).
+///
+/// A version is pruned if it is older than `max_age`, or if any of the other rules are set and none of them keep it. The root,
+/// the current version, tagged versions and branch tips are never pruned.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    /// Keep the newest `n` versions on each branch, i.e. on the path from each leaf up to the root.
+    pub keep_last: Option<usize>,
+    /// Keep one version per bucket of time, like "one per hour for a day, one per day for a month."
+    pub tiers: Vec<RetentionTier>,
+    /// Drop anything older than this.
+    pub max_age: Option<Duration>,
+}
+
+/// Keep the newest version created in each `interval` of time, for versions created within `span` of now.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetentionTier {
+    pub interval: Duration,
+    pub span: Duration,
+}
+
+/// What a policy needs to know about each version in the tree.
+pub(crate) struct VersionSummary {
+    pub version: u64,
+    pub parent: Option<u64>,
+    pub created_at: SystemTime,
+    pub is_leaf: bool,
+}
+
+impl RetentionPolicy {
+    /// Returns the versions that this policy doesn't retain, ignoring which versions are protected.
+    pub(crate) fn unretained(&self, versions: &[VersionSummary], now: SystemTime) -> BTreeSet<u64> {
+        let age = |v: &VersionSummary| now.duration_since(v.created_at).unwrap_or_default();
+
+        let mut retained = BTreeSet::new();
+
+        if let Some(n) = self.keep_last {
+            let parents: HashMap<u64, Option<u64>> =
+                versions.iter().map(|v| (v.version, v.parent)).collect();
+            for leaf in versions.iter().filter(|v| v.is_leaf) {
+                let mut maybe_version = Some(leaf.version);
+                for _ in 0..n {
+                    let version = if let Some(version) = maybe_version {
+                        version
+                    } else {
+                        break;
+                    };
+                    retained.insert(version);
+                    maybe_version = parents[&version];
+                }
+            }
+        }
+
+        for tier in self.tiers.iter() {
+            let interval = tier.interval.as_nanos().max(1);
+            // The newest version in each bucket.
+            let mut buckets = BTreeMap::new();
+            for v in versions.iter().filter(|v| age(v) <= tier.span) {
+                let since_epoch = v.created_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let bucket = buckets
+                    .entry(since_epoch.as_nanos() / interval)
+                    .or_insert(v);
+                if (v.created_at, v.version) > (bucket.created_at, bucket.version) {
+                    *bucket = v;
+                }
+            }
+            retained.extend(buckets.values().map(|v| v.version));
+        }
+
+        let has_keep_rules = self.keep_last.is_some() || !self.tiers.is_empty();
+        versions
+            .iter()
+            .filter(|v| {
+                let too_old = self.max_age.is_some_and(|max_age| age(v) > max_age);
+                too_old || (has_keep_rules && !retained.contains(&v.version))
+            })
+            .map(|v| v.version)
+            .collect()
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn keep_last_on_each_branch() {
+        // 0 -> 1 -> 2 -> 3
+        //        \-> 4
+        let versions = summarize(&[
+            (0, None),
+            (1, Some(0)),
+            (2, Some(1)),
+            (3, Some(2)),
+            (4, Some(1)),
+        ]);
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.unretained(&versions, at_hour(10)),
+            [0].iter().cloned().collect()
+        );
+    }
+
+    #[test]
+    fn tiers_keep_newest_per_bucket() {
+        let versions = summarize(&[
+            (0, None),
+            (1, Some(0)),
+            (2, Some(1)),
+            (3, Some(2)),
+            (4, Some(3)),
+        ]);
+        // Versions are created at hours 0 through 4. Keep one per 2 hours for the last 3 hours.
+        let policy = RetentionPolicy {
+            tiers: vec![RetentionTier {
+                interval: 2 * HOUR,
+                span: 3 * HOUR,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.unretained(&versions, at_hour(4)),
+            [0, 2].iter().cloned().collect()
+        );
+    }
+
+    #[test]
+    fn max_age_overrides_keep_rules() {
+        let versions = summarize(&[(0, None), (1, Some(0)), (2, Some(1))]);
+        let policy = RetentionPolicy {
+            keep_last: Some(3),
+            max_age: Some(HOUR),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.unretained(&versions, at_hour(2)),
+            [0].iter().cloned().collect()
+        );
+    }
+
+    /// Version `i` is created at hour `i`.
+    fn summarize(parents: &[(u64, Option<u64>)]) -> Vec<VersionSummary> {
+        parents
+            .iter()
+            .map(|&(version, parent)| VersionSummary {
+                version,
+                parent,
+                created_at: at_hour(version as u32),
+                is_leaf: !parents.iter().any(|&(_, p)| p == Some(version)),
+            })
+            .collect()
+    }
+
+    fn at_hour(hour: u32) -> SystemTime {
+        UNIX_EPOCH + HOUR * hour
+    }
+}
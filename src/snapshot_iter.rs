@@ -0,0 +1,287 @@
+use crate::{
+    transactions::net_deltas_to_version, DeltaMap, SnapshotError, TransactionalDeltaMap,
+    TransactionalVersionForest, VersionForest,
+};
+
+use sled::{
+    transaction::{TransactionError, TransactionResult},
+    IVec, Transactional, Tree,
+};
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::vec;
+
+/// Returns an iterator over all key-value pairs in the `version` snapshot, in key order, without restoring it.
+///
+/// See [`range_at_version`].
+pub fn iter_at_version(
+    version: u64,
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    data_tree: &Tree,
+) -> TransactionResult<SnapshotIter, SnapshotError> {
+    range_at_version::<&[u8], _>(version, .., forest, delta_map, data_tree)
+}
+
+/// Returns an iterator over the key-value pairs in `range` of the `version` snapshot, in key order, without restoring it.
+///
+/// The net change between the current version and `version` is gathered up front in a single transaction, but `data_tree` is
+/// scanned lazily, so `data_tree` must be the data tree of `version`'s snapshot tree. The iterator remembers the current
+/// version, and once that changes, e.g. because of a commit or a restore, it yields
+/// [`SnapshotError::CurrentVersionChanged`] and ends, rather than returning a mix of versions. Writes to `data_tree` that
+/// don't go through the snapshot tree can't be detected.
+pub fn range_at_version<K, R>(
+    version: u64,
+    range: R,
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    data_tree: &Tree,
+) -> TransactionResult<SnapshotIter, SnapshotError>
+where
+    K: AsRef<[u8]>,
+    R: RangeBounds<K>,
+{
+    let (root, current_version, net_deltas) =
+        (&**forest, &**delta_map).transaction(|(forest, delta_map)| {
+            let forest = TransactionalVersionForest(forest);
+            let root = forest.root_of(version)?;
+            let current_version = forest.current_version(root)?;
+            let net_deltas =
+                net_deltas_to_version(version, forest, TransactionalDeltaMap(delta_map))?;
+            Ok((root, current_version, net_deltas))
+        })?;
+    Ok(SnapshotIter::new(
+        net_deltas,
+        forest,
+        root,
+        current_version,
+        data_tree,
+        range,
+    ))
+}
+
+/// Iterates over the key-value pairs of a snapshot by merging a scan of the data tree with the net change from the current
+/// version to the snapshot.
+///
+/// After each read, the iterator checks that the current version of the tree is still the one the net change was computed
+/// from. If not, it yields [`SnapshotError::CurrentVersionChanged`] once and then ends.
+pub struct SnapshotIter {
+    data: Peekable<sled::Iter>,
+    net_deltas: Peekable<vec::IntoIter<(IVec, Option<IVec>)>>,
+    forest: VersionForest,
+    root: u64,
+    current_version: u64,
+    done: bool,
+}
+
+impl SnapshotIter {
+    pub(crate) fn new<K, R>(
+        net_deltas: BTreeMap<IVec, Option<IVec>>,
+        forest: &VersionForest,
+        root: u64,
+        current_version: u64,
+        data_tree: &Tree,
+        range: R,
+    ) -> Self
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let bounds = (
+            to_ivec_bound(range.start_bound()),
+            to_ivec_bound(range.end_bound()),
+        );
+        let net_deltas: Vec<_> = net_deltas
+            .range(bounds.clone())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        Self {
+            data: data_tree.range(bounds).peekable(),
+            net_deltas: net_deltas.into_iter().peekable(),
+            forest: forest.clone(),
+            root,
+            current_version,
+            done: false,
+        }
+    }
+
+    /// Merges the next key-value pair, without checking whether the current version changed.
+    fn next_unchecked(&mut self) -> Option<sled::Result<(IVec, IVec)>> {
+        loop {
+            let take_data = match (self.data.peek(), self.net_deltas.peek()) {
+                (None, None) => return None,
+                (Some(Err(_)), _) => true,
+                (Some(Ok(_)), None) => true,
+                (None, Some(_)) => false,
+                (Some(Ok((data_key, _))), Some((delta_key, _))) => {
+                    if data_key == delta_key {
+                        // The delta overrides the value in the data tree.
+                        self.data.next();
+                        false
+                    } else {
+                        data_key < delta_key
+                    }
+                }
+            };
+
+            if take_data {
+                return self.data.next();
+            }
+            if let Some((key, Some(value))) = self.net_deltas.next() {
+                return Some(Ok((key, value)));
+            }
+            // The key is absent in this snapshot.
+        }
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = TransactionResult<(IVec, IVec), SnapshotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_unchecked();
+        // The data tree was read before this check, so it was still in the state of the current version.
+        match self.forest.current_version(self.root) {
+            Ok(Some(current_version)) if current_version == self.current_version => {
+                next.map(|result| result.map_err(TransactionError::Storage))
+            }
+            Ok(_) => {
+                self.done = true;
+                Some(Err(TransactionError::Abort(
+                    SnapshotError::CurrentVersionChanged {
+                        root: self.root,
+                        version: self.current_version,
+                    },
+                )))
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(TransactionError::Storage(error)))
+            }
+        }
+    }
+}
+
+fn to_ivec_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<IVec> {
+    match bound {
+        Bound::Included(k) => Bound::Included(IVec::from(k.as_ref())),
+        Bound::Excluded(k) => Bound::Excluded(IVec::from(k.as_ref())),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_util::Fixture, Delta};
+
+    #[test]
+    fn iterate_old_versions_while_current_is_newer() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+
+        let v1 = tree
+            .commit(&[
+                Delta::Insert(IVec::from(b"a"), IVec::from(b"1")),
+                Delta::Insert(IVec::from(b"c"), IVec::from(b"3")),
+            ])
+            .unwrap();
+        let v2 = tree
+            .commit(&[
+                Delta::Remove(IVec::from(b"a")),
+                Delta::Insert(IVec::from(b"b"), IVec::from(b"2")),
+                Delta::Insert(IVec::from(b"c"), IVec::from(b"33")),
+                Delta::Insert(IVec::from(b"d"), IVec::from(b"4")),
+            ])
+            .unwrap();
+
+        assert_eq!(collect(tree.iter_at_version(root).unwrap()), vec![]);
+        assert_eq!(
+            collect(tree.iter_at_version(v1).unwrap()),
+            kvs(&[("a", "1"), ("c", "3")])
+        );
+        assert_eq!(
+            collect(tree.iter_at_version(v2).unwrap()),
+            kvs(&[("b", "2"), ("c", "33"), ("d", "4")])
+        );
+        assert_eq!(
+            collect(tree.range_at_version(v1, b"b".as_ref()..).unwrap()),
+            kvs(&[("c", "3")])
+        );
+
+        // Nothing was restored.
+        assert_eq!(tree.current_version(), Ok(v2));
+        assert_eq!(tree.data_tree().len(), 3);
+    }
+
+    #[test]
+    fn iterate_newer_version_while_current_is_older() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+
+        let v1 = tree
+            .commit(&[
+                Delta::Insert(IVec::from(b"a"), IVec::from(b"1")),
+                Delta::Insert(IVec::from(b"b"), IVec::from(b"2")),
+                Delta::Insert(IVec::from(b"c"), IVec::from(b"3")),
+            ])
+            .unwrap();
+        tree.checkout(root).unwrap();
+
+        assert_eq!(
+            collect(
+                tree.range_at_version(v1, b"a".as_ref()..b"c".as_ref())
+                    .unwrap()
+            ),
+            kvs(&[("a", "1"), ("b", "2")])
+        );
+    }
+
+    #[test]
+    fn iteration_stops_once_current_version_changes() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+
+        let v1 = tree
+            .commit(&[
+                Delta::Insert(IVec::from(b"a"), IVec::from(b"1")),
+                Delta::Insert(IVec::from(b"b"), IVec::from(b"2")),
+            ])
+            .unwrap();
+        let mut iter = tree.iter_at_version(v1).unwrap();
+        assert_eq!(iter.next(), Some(Ok((IVec::from(b"a"), IVec::from(b"1")))));
+
+        tree.checkout(root).unwrap();
+        assert_eq!(
+            iter.next(),
+            Some(Err(TransactionError::Abort(
+                SnapshotError::CurrentVersionChanged { root, version: v1 }
+            )))
+        );
+        assert_eq!(iter.next(), None);
+    }
+
+    fn collect(iter: SnapshotIter) -> Vec<(IVec, IVec)> {
+        iter.collect::<TransactionResult<_, _>>().unwrap()
+    }
+
+    fn kvs(kvs: &[(&str, &str)]) -> Vec<(IVec, IVec)> {
+        kvs.iter()
+            .map(|(k, v)| (IVec::from(k.as_bytes()), IVec::from(v.as_bytes())))
+            .collect()
+    }
+}
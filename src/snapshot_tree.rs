@@ -0,0 +1,801 @@
+use crate::{
+    delta::Delta, transactions::*, Branch, Conflict, DeltaMap, MergeOutcome, Resolution,
+    RetentionPolicy, SnapshotError, SnapshotIter, TagMap, TransactionalDeltaMap,
+    TransactionalTagMap, TransactionalVersionForest, VersionForest, VersionInfo,
+};
+
+use sled::{
+    transaction::{
+        abort, ConflictableTransactionResult, TransactionError, TransactionResult,
+        TransactionalTree,
+    },
+    IVec, Transactional, Tree,
+};
+use std::io;
+use std::ops::RangeBounds;
+use std::time::SystemTime;
+
+/// A single tree in a snapshot forest, bound to the data tree that it versions.
+///
+/// This is a convenience layer over the [`transactions`](crate::transactions) module. Each method runs exactly one transaction
+/// over the data tree, [`VersionForest`] and [`DeltaMap`] (and the [`TagMap`] when needed), which `sled` will retry on
+/// conflict. The forest records the name of the data tree of each snapshot tree, so the handle can't be paired with a
+/// different data tree.
+#[derive(Clone)]
+pub struct SnapshotTree {
+    root: u64,
+    data_tree: Tree,
+    forest: VersionForest,
+    delta_map: DeltaMap,
+    tags: TagMap,
+}
+
+impl SnapshotTree {
+    /// Creates a new tree in the snapshot forest. The current contents of `data_tree` become the root version.
+    pub fn create(
+        forest: &VersionForest,
+        delta_map: &DeltaMap,
+        tags: &TagMap,
+        data_tree: Tree,
+    ) -> TransactionResult<Self, SnapshotError> {
+        let root = forest.transaction(|forest| {
+            let forest = TransactionalVersionForest(forest);
+            let root = create_snapshot_tree(forest)?;
+            forest.set_data_tree_name(root, &data_tree.name())?;
+            Ok(root)
+        })?;
+
+        Ok(Self {
+            root,
+            data_tree,
+            forest: forest.clone(),
+            delta_map: delta_map.clone(),
+            tags: tags.clone(),
+        })
+    }
+
+    /// Opens the existing snapshot tree rooted at `root`, which versions `data_tree`.
+    ///
+    /// Returns `None` if `root` is not the root of a tree in `forest`. Returns [`SnapshotError::WrongDataTree`] if the tree was
+    /// created with a data tree of a different name. Trees created before the name was recorded are bound to `data_tree`.
+    pub fn open(
+        forest: &VersionForest,
+        delta_map: &DeltaMap,
+        tags: &TagMap,
+        data_tree: Tree,
+        root: u64,
+    ) -> TransactionResult<Option<Self>, SnapshotError> {
+        if forest.current_version(root)?.is_none() {
+            return Ok(None);
+        }
+        match forest.data_tree_name(root)? {
+            Some(expected) if expected != data_tree.name() => {
+                return Err(TransactionError::Abort(SnapshotError::WrongDataTree {
+                    root,
+                    expected,
+                    found: data_tree.name(),
+                }));
+            }
+            Some(_) => (),
+            None => forest.set_data_tree_name(root, &data_tree.name())?,
+        }
+
+        Ok(Some(Self {
+            root,
+            data_tree,
+            forest: forest.clone(),
+            delta_map: delta_map.clone(),
+            tags: tags.clone(),
+        }))
+    }
+
+    /// The root version of this tree.
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// The data tree, which always reflects the state of the current version.
+    ///
+    /// Don't write to it directly, or it will get out of sync with the snapshots.
+    pub fn data_tree(&self) -> &Tree {
+        &self.data_tree
+    }
+
+    pub fn forest(&self) -> &VersionForest {
+        &self.forest
+    }
+
+    pub fn delta_map(&self) -> &DeltaMap {
+        &self.delta_map
+    }
+
+    pub fn tags(&self) -> &TagMap {
+        &self.tags
+    }
+
+    /// Returns the current version of this tree.
+    pub fn current_version(&self) -> TransactionResult<u64, SnapshotError> {
+        self.forest
+            .transaction(|forest| current_version(self.root, TransactionalVersionForest(forest)))
+    }
+
+    /// Applies `deltas` to the data tree as a new child of the current version, which becomes the new current version.
+    ///
+    /// See [`create_child_snapshot_with_deltas`].
+    pub fn commit(&self, deltas: &[Delta<IVec>]) -> TransactionResult<u64, SnapshotError> {
+        self.transaction(|forest, delta_map, data_tree| {
+            let current = forest.current_version(self.root)?;
+            create_child_snapshot_with_deltas(current, forest, delta_map, data_tree, deltas)
+        })
+    }
+
+    /// Applies `deltas` to the data tree without creating a new snapshot. The current version must be a leaf.
+    ///
+    /// See [`modify_current_leaf_snapshot`].
+    pub fn modify(&self, deltas: &[Delta<IVec>]) -> TransactionResult<(), SnapshotError> {
+        self.transaction(|forest, delta_map, data_tree| {
+            let current = forest.current_version(self.root)?;
+            modify_current_leaf_snapshot(current, forest, delta_map, data_tree, deltas)
+        })
+    }
+
+    /// Restores the data tree to the state of `version`, which becomes the current version.
+    ///
+    /// See [`set_current_version`].
+    pub fn checkout(&self, version: u64) -> TransactionResult<(), SnapshotError> {
+        self.transaction(|forest, delta_map, data_tree| {
+            self.check_contains(version, forest)?;
+            let current = forest.current_version(self.root)?;
+            set_current_version(current, version, forest, delta_map, data_tree)
+        })
+    }
+
+    /// Returns the value of `key` in the `version` snapshot without restoring it.
+    ///
+    /// See [`get_at_version`].
+    pub fn get_at_version(
+        &self,
+        version: u64,
+        key: &[u8],
+    ) -> TransactionResult<Option<IVec>, SnapshotError> {
+        self.transaction(|forest, delta_map, data_tree| {
+            self.check_contains(version, forest)?;
+            get_at_version(version, key, forest, delta_map, data_tree)
+        })
+    }
+
+    /// Returns an iterator over all key-value pairs in the `version` snapshot without restoring it.
+    ///
+    /// See [`iter_at_version`](crate::iter_at_version).
+    pub fn iter_at_version(&self, version: u64) -> TransactionResult<SnapshotIter, SnapshotError> {
+        self.range_at_version::<&[u8], _>(version, ..)
+    }
+
+    /// Returns an iterator over the key-value pairs in `range` of the `version` snapshot without restoring it.
+    ///
+    /// See [`range_at_version`](crate::range_at_version).
+    pub fn range_at_version<K, R>(
+        &self,
+        version: u64,
+        range: R,
+    ) -> TransactionResult<SnapshotIter, SnapshotError>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (current_version, net_deltas) = self.transaction(|forest, delta_map, _data_tree| {
+            self.check_contains(version, forest)?;
+            let current_version = forest.current_version(self.root)?;
+            Ok((
+                current_version,
+                net_deltas_to_version(version, forest, delta_map)?,
+            ))
+        })?;
+        Ok(SnapshotIter::new(
+            net_deltas,
+            &self.forest,
+            self.root,
+            current_version,
+            &self.data_tree,
+            range,
+        ))
+    }
+
+    /// Returns the minimal set of deltas that turns snapshot `start` into snapshot `finish`.
+    ///
+    /// See [`diff_versions`].
+    pub fn diff_versions(
+        &self,
+        start: u64,
+        finish: u64,
+    ) -> TransactionResult<Vec<Delta<IVec>>, SnapshotError> {
+        self.transaction(|forest, delta_map, data_tree| {
+            self.check_contains(start, forest)?;
+            self.check_contains(finish, forest)?;
+            diff_versions(start, finish, forest, delta_map, data_tree)
+        })
+    }
+
+    /// Creates a new leaf snapshot as a child of `parent_version` without changing the current version. The new snapshot
+    /// starts out identical to its parent.
+    ///
+    /// See [`create_child_snapshot`].
+    pub fn branch(&self, parent_version: u64) -> TransactionResult<u64, SnapshotError> {
+        self.transaction(|forest, delta_map, _data_tree| {
+            self.check_contains(parent_version, forest)?;
+            create_child_snapshot(parent_version, false, forest, delta_map)
+        })
+    }
+
+    /// Deletes the snapshot at `version`.
+    ///
+    /// See [`delete_snapshot`].
+    pub fn delete(&self, version: u64) -> TransactionResult<(), SnapshotError> {
+        self.transaction_with_tags(|forest, delta_map, tags, _data_tree| {
+            self.check_contains(version, forest)?;
+            delete_snapshot(version, forest, delta_map, tags)
+        })
+    }
+
+    /// Merges the changes made on the branch leading to `other_version` into the current version, as a new child snapshot.
+    ///
+    /// See [`merge`].
+    pub fn merge(
+        &self,
+        other_version: u64,
+        resolve: impl Fn(&Conflict) -> Resolution,
+    ) -> TransactionResult<MergeOutcome, SnapshotError> {
+        self.transaction(|forest, delta_map, data_tree| {
+            self.check_contains(other_version, forest)?;
+            let current = forest.current_version(self.root)?;
+            merge(
+                current,
+                other_version,
+                forest,
+                delta_map,
+                data_tree,
+                &resolve,
+            )
+        })
+    }
+
+    /// Applies the changes that `version` introduced relative to its parent onto the current version, as a new child
+    /// snapshot.
+    ///
+    /// See [`cherry_pick`].
+    pub fn cherry_pick(
+        &self,
+        version: u64,
+        resolve: impl Fn(&Conflict) -> Resolution,
+    ) -> TransactionResult<MergeOutcome, SnapshotError> {
+        self.transaction(|forest, delta_map, data_tree| {
+            self.check_contains(version, forest)?;
+            let current = forest.current_version(self.root)?;
+            cherry_pick(version, current, forest, delta_map, data_tree, &resolve)
+        })
+    }
+
+    /// Collapses the linear chain of versions from `ancestor` down to `descendant`.
+    ///
+    /// See [`squash`].
+    pub fn squash(&self, ancestor: u64, descendant: u64) -> TransactionResult<(), SnapshotError> {
+        self.transaction_with_tags(|forest, delta_map, tags, _data_tree| {
+            self.check_contains(descendant, forest)?;
+            squash(ancestor, descendant, forest, delta_map, tags)
+        })
+    }
+
+    /// Deletes every version in this tree that `policy` doesn't retain, measuring ages from now. Returns the deleted versions.
+    ///
+    /// See [`prune`].
+    pub fn prune(&self, policy: &RetentionPolicy) -> TransactionResult<Vec<u64>, SnapshotError> {
+        let now = SystemTime::now();
+        self.transaction_with_tags(|forest, delta_map, tags, _data_tree| {
+            prune(self.root, policy, now, forest, delta_map, tags)
+        })
+    }
+
+    /// Tags `version` with `name`. Tags are shared by every tree in the forest.
+    ///
+    /// See [`tag`].
+    pub fn tag(&self, name: &str, version: u64) -> TransactionResult<(), SnapshotError> {
+        self.transaction_with_tags(|forest, _delta_map, tags, _data_tree| {
+            self.check_contains(version, forest)?;
+            tag(name, version, forest, tags)
+        })
+    }
+
+    /// Removes the tag `name`, returning the version it pointed to. Tags on versions of other trees are left alone.
+    ///
+    /// See [`untag`].
+    pub fn untag(&self, name: &str) -> TransactionResult<Option<u64>, SnapshotError> {
+        self.transaction_with_tags(|forest, _delta_map, tags, _data_tree| {
+            if let Some(version) = resolve_tag(name, tags)? {
+                self.check_contains(version, forest)?;
+            }
+            untag(name, tags)
+        })
+    }
+
+    /// Returns the names of all tags on `version`.
+    ///
+    /// See [`tags_of`].
+    pub fn tags_of(&self, version: u64) -> TransactionResult<Vec<String>, SnapshotError> {
+        self.transaction_with_tags(|forest, _delta_map, tags, _data_tree| {
+            self.check_contains(version, forest)?;
+            tags_of(version, tags)
+        })
+    }
+
+    /// Restores the data tree to the state of the version tagged with `name`, which becomes the current version.
+    pub fn checkout_tag(&self, name: &str) -> TransactionResult<u64, SnapshotError> {
+        self.transaction_with_tags(|forest, delta_map, tags, data_tree| {
+            let version = if let Some(version) = resolve_tag(name, tags)? {
+                version
+            } else {
+                return abort(SnapshotError::TagNotFound(name.to_owned()));
+            };
+            self.check_contains(version, forest)?;
+            let current = forest.current_version(self.root)?;
+            set_current_version(current, version, forest, delta_map, data_tree)?;
+            Ok(version)
+        })
+    }
+
+    /// Records the author, message and application-specific `user_data` of `version`.
+    ///
+    /// See [`set_version_metadata`].
+    pub fn set_version_metadata(
+        &self,
+        version: u64,
+        author: &str,
+        message: &str,
+        user_data: &[u8],
+    ) -> TransactionResult<(), SnapshotError> {
+        self.transaction(|forest, _delta_map, _data_tree| {
+            self.check_contains(version, forest)?;
+            set_version_metadata(version, author, message, user_data, forest)
+        })
+    }
+
+    /// Returns the metadata of `version`.
+    ///
+    /// See [`version_info`].
+    pub fn version_info(&self, version: u64) -> TransactionResult<VersionInfo, SnapshotError> {
+        self.transaction(|forest, _delta_map, _data_tree| {
+            self.check_contains(version, forest)?;
+            version_info(version, forest)
+        })
+    }
+
+    /// Restores the data tree to the latest snapshot created at or before `time` out of `branch_leaf` and its ancestors.
+    ///
+    /// See [`restore_to_time`](crate::restore_to_time).
+    pub fn restore_to_time(
+        &self,
+        time: SystemTime,
+        branch_leaf: u64,
+    ) -> TransactionResult<Option<u64>, SnapshotError> {
+        crate::restore_to_time(
+            self.root,
+            time,
+            branch_leaf,
+            &self.forest,
+            &self.delta_map,
+            &self.data_tree,
+        )
+    }
+
+    /// Copies the `version` snapshot into `target_tree` without restoring it.
+    ///
+    /// See [`materialize_version`](crate::materialize_version).
+    pub fn materialize(
+        &self,
+        version: u64,
+        target_tree: &Tree,
+    ) -> TransactionResult<(), SnapshotError> {
+        self.forest.transaction(|forest| {
+            self.check_contains(version, TransactionalVersionForest(forest))
+        })?;
+        crate::materialize_version(
+            version,
+            &self.forest,
+            &self.delta_map,
+            &self.data_tree,
+            target_tree,
+        )
+    }
+
+    /// Creates a new tree in the same forest, versioning `new_data_tree`, which starts out as a copy of the `version` snapshot.
+    ///
+    /// See [`fork_snapshot_tree`](crate::fork_snapshot_tree).
+    pub fn fork(
+        &self,
+        version: u64,
+        copy_history: bool,
+        new_data_tree: Tree,
+    ) -> TransactionResult<Self, SnapshotError> {
+        self.forest.transaction(|forest| {
+            self.check_contains(version, TransactionalVersionForest(forest))
+        })?;
+        let root = crate::fork_snapshot_tree(
+            version,
+            copy_history,
+            &self.forest,
+            &self.delta_map,
+            &self.data_tree,
+            &new_data_tree,
+        )?;
+
+        Ok(Self {
+            root,
+            data_tree: new_data_tree,
+            forest: self.forest.clone(),
+            delta_map: self.delta_map.clone(),
+            tags: self.tags.clone(),
+        })
+    }
+
+    /// Writes this tree and its data tree to `writer`.
+    ///
+    /// See [`export_snapshot_tree`](crate::export_snapshot_tree).
+    pub fn export(&self, writer: impl io::Write) -> io::Result<()> {
+        crate::export_snapshot_tree(
+            self.root,
+            &self.forest,
+            &self.delta_map,
+            &self.data_tree,
+            writer,
+        )
+    }
+
+    /// Reads a tree written by [`SnapshotTree::export`] from `reader` into `forest`, filling the empty `data_tree`.
+    ///
+    /// See [`import_snapshot_tree`](crate::import_snapshot_tree).
+    pub fn import(
+        reader: impl io::Read,
+        forest: &VersionForest,
+        delta_map: &DeltaMap,
+        tags: &TagMap,
+        data_tree: Tree,
+    ) -> io::Result<Self> {
+        let root = crate::import_snapshot_tree(reader, forest, delta_map, &data_tree)?;
+
+        Ok(Self {
+            root,
+            data_tree,
+            forest: forest.clone(),
+            delta_map: delta_map.clone(),
+            tags: tags.clone(),
+        })
+    }
+
+    /// Creates a branch called `name` that points to `version`.
+    ///
+    /// See [`create_branch`].
+    pub fn create_branch(&self, name: &str, version: u64) -> TransactionResult<(), SnapshotError> {
+        self.transaction(|forest, _delta_map, _data_tree| {
+            self.check_contains(version, forest)?;
+            create_branch(name, version, forest)
+        })
+    }
+
+    /// Restores the data tree to the state of the tip of branch `name`, which becomes the current version.
+    ///
+    /// See [`checkout_branch`].
+    pub fn checkout_branch(&self, name: &str) -> TransactionResult<u64, SnapshotError> {
+        self.transaction(|forest, delta_map, data_tree| {
+            checkout_branch(self.root, name, forest, delta_map, data_tree)
+        })
+    }
+
+    /// Returns the branches of this tree, ordered by name.
+    pub fn list_branches(&self) -> sled::Result<Vec<Branch>> {
+        self.forest.list_branches(self.root)
+    }
+
+    /// Deletes the branch `name`, returning its tip.
+    ///
+    /// See [`delete_branch`].
+    pub fn delete_branch(&self, name: &str) -> TransactionResult<Option<u64>, SnapshotError> {
+        self.transaction(|forest, _delta_map, _data_tree| delete_branch(self.root, name, forest))
+    }
+
+    fn transaction<T>(
+        &self,
+        f: impl Fn(
+            TransactionalVersionForest,
+            TransactionalDeltaMap,
+            &TransactionalTree,
+        ) -> ConflictableTransactionResult<T, SnapshotError>,
+    ) -> TransactionResult<T, SnapshotError> {
+        (&self.data_tree, &*self.forest, &*self.delta_map).transaction(
+            |(data_tree, forest, delta_map)| {
+                f(
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    data_tree,
+                )
+            },
+        )
+    }
+
+    fn transaction_with_tags<T>(
+        &self,
+        f: impl Fn(
+            TransactionalVersionForest,
+            TransactionalDeltaMap,
+            TransactionalTagMap,
+            &TransactionalTree,
+        ) -> ConflictableTransactionResult<T, SnapshotError>,
+    ) -> TransactionResult<T, SnapshotError> {
+        (
+            &self.data_tree,
+            &*self.forest,
+            &*self.delta_map,
+            &*self.tags,
+        )
+            .transaction(|(data_tree, forest, delta_map, tags)| {
+                f(
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                    data_tree,
+                )
+            })
+    }
+
+    /// Aborts the transaction unless `version` belongs to this tree.
+    fn check_contains(
+        &self,
+        version: u64,
+        forest: TransactionalVersionForest,
+    ) -> ConflictableTransactionResult<(), SnapshotError> {
+        if forest.root_of(version)? != self.root {
+            return abort(SnapshotError::VersionNotInTree {
+                version,
+                root: self.root,
+            });
+        }
+        Ok(())
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{open_snapshot_forest, open_tag_map, test_util::Fixture};
+
+    #[test]
+    fn commit_checkout_and_delete() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+
+        let v1 = tree
+            .commit(&[Delta::Insert(IVec::from(b"key1"), IVec::from(b"value1"))])
+            .unwrap();
+        let v2 = tree
+            .commit(&[Delta::Insert(IVec::from(b"key2"), IVec::from(b"value2"))])
+            .unwrap();
+        assert_eq!(tree.current_version(), Ok(v2));
+
+        tree.checkout(root).unwrap();
+        assert_eq!(tree.current_version(), Ok(root));
+        assert!(tree.data_tree().is_empty());
+
+        tree.delete(v1).unwrap();
+        tree.checkout(v2).unwrap();
+        assert_eq!(tree.data_tree().len(), 2);
+        assert_eq!(tree.forest().collect_versions().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn branch_from_root_and_modify() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+
+        let v1 = tree
+            .commit(&[Delta::Insert(IVec::from(b"key1"), IVec::from(b"value1"))])
+            .unwrap();
+        let b1 = tree.branch(root).unwrap();
+        assert_eq!(tree.current_version(), Ok(v1));
+
+        tree.checkout(b1).unwrap();
+        assert!(tree.data_tree().is_empty());
+        tree.modify(&[Delta::Insert(IVec::from(b"key2"), IVec::from(b"value2"))])
+            .unwrap();
+
+        tree.checkout(v1).unwrap();
+        assert_eq!(
+            tree.data_tree().get(b"key1"),
+            Ok(Some(IVec::from(b"value1")))
+        );
+        assert_eq!(tree.data_tree().get(b"key2"), Ok(None));
+
+        tree.checkout(b1).unwrap();
+        assert_eq!(tree.data_tree().get(b"key1"), Ok(None));
+        assert_eq!(
+            tree.data_tree().get(b"key2"),
+            Ok(Some(IVec::from(b"value2")))
+        );
+    }
+
+    #[test]
+    fn checkout_version_from_other_tree_aborts() {
+        let fixture = Fixture::open();
+        let tree1 = fixture.create_snapshot_tree_for("data1");
+        let tree2 = fixture.create_snapshot_tree_for("data2");
+
+        assert_eq!(
+            tree1.checkout(tree2.root()),
+            Err(TransactionError::Abort(SnapshotError::VersionNotInTree {
+                version: tree2.root(),
+                root: tree1.root()
+            }))
+        );
+    }
+
+    #[test]
+    fn checkout_tag() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+
+        let v1 = tree
+            .commit(&[Delta::Insert(IVec::from(b"key1"), IVec::from(b"value1"))])
+            .unwrap();
+        tree.tag("v1", v1).unwrap();
+        tree.commit(&[Delta::Remove(IVec::from(b"key1"))]).unwrap();
+
+        assert_eq!(tree.checkout_tag("v1"), Ok(v1));
+        assert_eq!(tree.data_tree().len(), 1);
+        assert_eq!(tree.tags_of(v1), Ok(vec!["v1".to_owned()]));
+        assert_eq!(
+            tree.checkout_tag("v2"),
+            Err(TransactionError::Abort(SnapshotError::TagNotFound(
+                "v2".to_owned()
+            )))
+        );
+    }
+
+    #[test]
+    fn untag_only_removes_own_tags() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let other = fixture.create_snapshot_tree_for("other");
+
+        tree.tag("v0", tree.root()).unwrap();
+        assert_eq!(
+            other.untag("v0"),
+            Err(TransactionError::Abort(SnapshotError::VersionNotInTree {
+                version: tree.root(),
+                root: other.root(),
+            }))
+        );
+        assert_eq!(tree.untag("v0"), Ok(Some(tree.root())));
+        assert_eq!(other.untag("v0"), Ok(None));
+    }
+
+    #[test]
+    fn branches_follow_commits_and_deletes() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+
+        tree.create_branch("main", root).unwrap();
+        tree.create_branch("feature", root).unwrap();
+        assert_eq!(
+            tree.create_branch("main", root),
+            Err(TransactionError::Abort(SnapshotError::BranchAlreadyExists(
+                "main".to_owned()
+            )))
+        );
+
+        // Both branches point at the root, but only the checked-out one advances.
+        assert_eq!(tree.checkout_branch("main"), Ok(root));
+        let v1 = tree
+            .commit(&[Delta::Insert(IVec::from(b"key1"), IVec::from(b"value1"))])
+            .unwrap();
+        let v2 = tree
+            .commit(&[Delta::Insert(IVec::from(b"key2"), IVec::from(b"value2"))])
+            .unwrap();
+        assert_eq!(
+            tree.list_branches().unwrap(),
+            vec![
+                Branch {
+                    name: "feature".to_owned(),
+                    tip: root
+                },
+                Branch {
+                    name: "main".to_owned(),
+                    tip: v2
+                }
+            ]
+        );
+
+        assert_eq!(tree.checkout_branch("feature"), Ok(root));
+        let f1 = tree
+            .commit(&[Delta::Insert(IVec::from(b"key3"), IVec::from(b"value3"))])
+            .unwrap();
+        assert_eq!(
+            tree.list_branches().unwrap(),
+            vec![
+                Branch {
+                    name: "feature".to_owned(),
+                    tip: f1
+                },
+                Branch {
+                    name: "main".to_owned(),
+                    tip: v2
+                }
+            ]
+        );
+        assert_eq!(tree.checkout_branch("main"), Ok(v2));
+        assert_eq!(tree.data_tree().len(), 2);
+
+        // A child that doesn't become current leaves the branch alone.
+        let side = tree.branch(v2).unwrap();
+        assert_eq!(tree.checkout_branch("main"), Ok(v2));
+        tree.delete(side).unwrap();
+
+        // Deleting a tip moves the branch to its parent.
+        tree.delete(f1).unwrap();
+        assert_eq!(tree.checkout_branch("feature"), Ok(root));
+        assert!(tree.data_tree().is_empty());
+
+        // Deleting a version in the middle of a branch leaves the tip alone.
+        tree.delete(v1).unwrap();
+        assert_eq!(tree.checkout_branch("main"), Ok(v2));
+        assert_eq!(tree.data_tree().len(), 2);
+
+        assert_eq!(tree.delete_branch("feature"), Ok(Some(root)));
+        assert_eq!(
+            tree.checkout_branch("feature"),
+            Err(TransactionError::Abort(SnapshotError::BranchNotFound(
+                "feature".to_owned()
+            )))
+        );
+
+        // Deleting the checked-out branch leaves no branch checked out.
+        assert_eq!(
+            tree.forest().checked_out_branch(root),
+            Ok(Some("main".to_owned()))
+        );
+        assert_eq!(tree.delete_branch("main"), Ok(Some(v2)));
+        assert_eq!(tree.forest().checked_out_branch(root), Ok(None));
+    }
+
+    #[test]
+    fn open_checks_data_tree() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+        let open = |name: &str| {
+            SnapshotTree::open(
+                &forest,
+                &delta_map,
+                &tags,
+                fixture.db.open_tree(name).unwrap(),
+                tree.root(),
+            )
+        };
+
+        assert_eq!(open("data").unwrap().unwrap().root(), tree.root());
+        assert_eq!(
+            open("other").err(),
+            Some(TransactionError::Abort(SnapshotError::WrongDataTree {
+                root: tree.root(),
+                expected: IVec::from("data"),
+                found: IVec::from("other"),
+            }))
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{u64_from_be_slice, SnapshotError};
+
+use sled::{
+    transaction::{
+        abort, ConflictableTransactionResult, TransactionalTree, UnabortableTransactionError,
+    },
+    IVec, Tree,
+};
+use std::ops::Deref;
+
+/// A [sled::Tree] that maps names to versions, since versions themselves are hard to remember.
+///
+/// Tags are global to the snapshot forest, so a name refers to at most one version in any tree.
+///
+/// # Implementation
+///
+/// Each tag is stored twice: once keyed by its name, pointing to the version, and once in a list of names keyed by the version.
+/// The second record lets us find the tags of a version inside a transaction, which can't scan.
+#[derive(Clone)]
+pub struct TagMap(pub Tree);
+
+impl Deref for TagMap {
+    type Target = Tree;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl TagMap {
+    /// Returns an iterator over all `(name, version)` tags in the forest, ordered by name.
+    pub fn iter_tags(&self) -> impl Iterator<Item = sled::Result<(String, u64)>> {
+        self.scan_prefix([NAME_TAG])
+            .map(|kv_result| kv_result.map(|(k, v)| (decode_name(&k[1..]), u64_from_be_slice(&v))))
+    }
+
+    /// Returns the version tagged with `name`, if any.
+    pub fn resolve_tag(&self, name: &str) -> sled::Result<Option<u64>> {
+        self.get(name_key(name))
+            .map(|result| result.map(|bytes| u64_from_be_slice(&bytes)))
+    }
+}
+
+/// Same as [TagMap] but used in transactions.
+#[derive(Clone, Copy)]
+pub struct TransactionalTagMap<'a>(pub &'a TransactionalTree);
+
+impl<'a> Deref for TransactionalTagMap<'a> {
+    type Target = TransactionalTree;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl<'a> TransactionalTagMap<'a> {
+    /// Returns the version tagged with `name`, if any.
+    pub fn resolve_tag(&self, name: &str) -> Result<Option<u64>, UnabortableTransactionError> {
+        self.get(name_key(name))
+            .map(|result| result.map(|bytes| u64_from_be_slice(&bytes)))
+    }
+
+    /// Returns the names of all tags on `version`, in the order they were added.
+    pub fn tags_of(&self, version: u64) -> Result<Vec<String>, UnabortableTransactionError> {
+        Ok(self
+            .get(version_key(version))?
+            .map(|bytes| decode_names(&bytes))
+            .unwrap_or_default())
+    }
+
+    /// Tags `version` with `name`. Aborts the transaction if `name` already tags a different version.
+    pub(crate) fn insert_tag(
+        &self,
+        name: &str,
+        version: u64,
+    ) -> ConflictableTransactionResult<(), SnapshotError> {
+        match self.resolve_tag(name)? {
+            Some(tagged) if tagged == version => return Ok(()),
+            Some(_) => return abort(SnapshotError::TagAlreadyExists(name.to_owned())),
+            None => (),
+        }
+
+        self.insert(name_key(name), &version.to_be_bytes())?;
+        let mut names = self.tags_of(version)?;
+        names.push(name.to_owned());
+        self.insert(&version_key(version), encode_names(&names))?;
+
+        Ok(())
+    }
+
+    /// Removes the tag `name`, returning the version it pointed to.
+    pub(crate) fn remove_tag(
+        &self,
+        name: &str,
+    ) -> Result<Option<u64>, UnabortableTransactionError> {
+        let version = if let Some(bytes) = self.remove(name_key(name))? {
+            u64_from_be_slice(&bytes)
+        } else {
+            return Ok(None);
+        };
+
+        let mut names = self.tags_of(version)?;
+        names.retain(|n| n != name);
+        if names.is_empty() {
+            self.remove(&version_key(version))?;
+        } else {
+            self.insert(&version_key(version), encode_names(&names))?;
+        }
+
+        Ok(Some(version))
+    }
+
+    /// Removes all tags on `version`.
+    pub(crate) fn remove_version(&self, version: u64) -> Result<(), UnabortableTransactionError> {
+        if let Some(bytes) = self.remove(&version_key(version))? {
+            for name in decode_names(&bytes) {
+                self.remove(name_key(&name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+const NAME_TAG: u8 = 0;
+const VERSION_TAG: u8 = 1;
+
+fn name_key(name: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + name.len());
+    key.push(NAME_TAG);
+    key.extend_from_slice(name.as_bytes());
+    key
+}
+
+fn version_key(version: u64) -> [u8; 9] {
+    let mut key = [VERSION_TAG; 9];
+    key[1..].copy_from_slice(&version.to_be_bytes());
+    key
+}
+
+/// Each name is encoded as its length in bytes followed by the bytes.
+fn encode_names(names: &[String]) -> IVec {
+    let mut bytes = Vec::new();
+    for name in names {
+        bytes.extend_from_slice(&(name.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    IVec::from(bytes)
+}
+
+/// A truncated record keeps the names that can still be read, and a name whose length runs past the end is cut short.
+fn decode_names(mut bytes: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    while bytes.len() >= 8 {
+        let len = (u64_from_be_slice(&bytes[..8]) as usize).min(bytes.len() - 8);
+        names.push(decode_name(&bytes[8..8 + len]));
+        bytes = &bytes[8 + len..];
+    }
+    names
+}
+
+/// Invalid UTF-8 is replaced rather than failing the read, so a corrupt tag can't hide the rest.
+fn decode_name(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_names_survives_corruption() {
+        let mut bytes = encode_names(&["a".to_owned(), "bc".to_owned()]).to_vec();
+        // The only byte of the first name.
+        bytes[8] = 0xff;
+        assert_eq!(decode_names(&bytes), vec!["\u{fffd}", "bc"]);
+
+        // The second name claims to be longer than the record.
+        bytes[9..17].copy_from_slice(&100u64.to_be_bytes());
+        assert_eq!(decode_names(&bytes), vec!["\u{fffd}", "bc"]);
+        assert_eq!(
+            decode_names(&bytes[..bytes.len() - 1]),
+            vec!["\u{fffd}", "b"]
+        );
+        assert_eq!(decode_names(&bytes[..12]), vec!["\u{fffd}"]);
+    }
+}
@@ -0,0 +1,27 @@
+use crate::{open_snapshot_forest, open_tag_map, SnapshotTree};
+
+/// A temporary database shared by the tests of several modules.
+pub struct Fixture {
+    pub db: sled::Db,
+}
+
+impl Fixture {
+    pub fn open() -> Self {
+        let config = sled::Config::new().temporary(true);
+        let db = config.open().unwrap();
+        Self { db }
+    }
+
+    /// Creates a snapshot tree in the `"snaps"` forest that versions the `"data"` tree.
+    pub fn create_snapshot_tree(&self) -> SnapshotTree {
+        self.create_snapshot_tree_for("data")
+    }
+
+    /// Creates a snapshot tree in the `"snaps"` forest that versions the tree called `data_tree_name`.
+    pub fn create_snapshot_tree_for(&self, data_tree_name: &str) -> SnapshotTree {
+        let (forest, delta_map) = open_snapshot_forest(&self.db, "snaps").unwrap();
+        let tags = open_tag_map(&self.db, "snaps").unwrap();
+        let data_tree = self.db.open_tree(data_tree_name).unwrap();
+        SnapshotTree::create(&forest, &delta_map, &tags, data_tree).unwrap()
+    }
+}
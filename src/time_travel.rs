@@ -0,0 +1,157 @@
+use crate::{
+    transactions::set_current_version, DeltaMap, SnapshotError, TransactionalDeltaMap,
+    TransactionalVersionForest, VersionForest,
+};
+
+use sled::{transaction::TransactionResult, Transactional, Tree};
+use std::time::SystemTime;
+
+/// Restores `data_tree` to the latest snapshot created at or before `time` out of `branch_leaf` and its ancestors, which
+/// becomes the current version of the tree rooted at `root`. Returns the restored version, or `None` if there is no such
+/// snapshot, in which case nothing changes.
+///
+/// The snapshot is found with [`VersionForest::version_at_time`] before the transaction starts. If it gets deleted in the
+/// meantime, the transaction aborts with [`SnapshotError::VersionNotFound`].
+pub fn restore_to_time(
+    root: u64,
+    time: SystemTime,
+    branch_leaf: u64,
+    forest: &VersionForest,
+    delta_map: &DeltaMap,
+    data_tree: &Tree,
+) -> TransactionResult<Option<u64>, SnapshotError> {
+    let version = if let Some(version) = forest.version_at_time(root, time, branch_leaf)? {
+        version
+    } else {
+        return Ok(None);
+    };
+
+    (data_tree, &**forest, &**delta_map).transaction(|(data_tree, forest, delta_map)| {
+        let forest = TransactionalVersionForest(forest);
+        let current_version = forest.current_version(root)?;
+        set_current_version(
+            current_version,
+            version,
+            forest,
+            TransactionalDeltaMap(delta_map),
+            data_tree,
+        )?;
+        Ok(Some(version))
+    })
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use crate::{test_util::Fixture, Delta, SnapshotTree, TransactionalVersionForest, VersionInfo};
+
+    use sled::{transaction::ConflictableTransactionError, IVec};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn restore_ancestor_by_creation_time() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+
+        let v1 = commit(&tree, b"key1");
+        let v2 = commit(&tree, b"key2");
+        tree.checkout(root).unwrap();
+        let b1 = commit(&tree, b"key3");
+
+        set_created_at(&tree, root, at(10));
+        set_created_at(&tree, v1, at(20));
+        set_created_at(&tree, b1, at(25));
+        set_created_at(&tree, v2, at(30));
+        let forest = tree.forest();
+
+        // Versions on other branches are skipped.
+        assert_eq!(forest.version_at_time(root, at(40), v2), Ok(Some(v2)));
+        assert_eq!(forest.version_at_time(root, at(25), v2), Ok(Some(v1)));
+        assert_eq!(forest.version_at_time(root, at(20), v2), Ok(Some(v1)));
+        assert_eq!(forest.version_at_time(root, at(25), b1), Ok(Some(b1)));
+        assert_eq!(forest.version_at_time(root, at(5), v2), Ok(None));
+
+        assert_eq!(tree.restore_to_time(at(30), v2), Ok(Some(v2)));
+        assert_eq!(tree.current_version(), Ok(v2));
+        assert_eq!(tree.data_tree().len(), 2);
+
+        tree.delete(v1).unwrap();
+        assert_eq!(tree.restore_to_time(at(20), v2), Ok(Some(root)));
+        assert!(tree.data_tree().is_empty());
+    }
+
+    #[test]
+    fn find_version_at_time_in_long_history_after_deletes() {
+        let fixture = Fixture::open();
+        let tree = fixture.create_snapshot_tree();
+        let root = tree.root();
+
+        let mut history = vec![(root, 10)];
+        for i in 1..100 {
+            history.push((commit(&tree, &[i]), 10 * (u64::from(i) + 1)));
+        }
+        for &(version, secs) in history.iter() {
+            set_created_at(&tree, version, at(secs));
+        }
+        let leaf = history.last().unwrap().0;
+        assert_versions_at_times(&tree, leaf, &history);
+
+        // Versions that skipped to a deleted version skip past it instead.
+        for (i, &(version, _)) in history.iter().enumerate().rev() {
+            if i % 3 == 1 {
+                tree.delete(version).unwrap();
+            }
+        }
+        let remaining: Vec<_> = history
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i % 3 != 1)
+            .map(|(_, &entry)| entry)
+            .collect();
+        assert_versions_at_times(&tree, leaf, &remaining);
+    }
+
+    /// Checks `version_at_time` from `leaf` against `history`, the `(version, secs)` of `leaf` and its ancestors from the root.
+    fn assert_versions_at_times(tree: &SnapshotTree, leaf: u64, history: &[(u64, u64)]) {
+        let forest = tree.forest();
+        for secs in (0..=history.last().unwrap().1 + 10).step_by(5) {
+            let expected = history
+                .iter()
+                .rev()
+                .find(|&&(_, created)| created <= secs)
+                .map(|&(version, _)| version);
+            assert_eq!(
+                forest.version_at_time(tree.root(), at(secs), leaf),
+                Ok(expected)
+            );
+        }
+    }
+
+    fn commit(tree: &SnapshotTree, key: &[u8]) -> u64 {
+        tree.commit(&[Delta::Insert(IVec::from(key), IVec::from(b"value"))])
+            .unwrap()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn set_created_at(tree: &SnapshotTree, version: u64, created_at: SystemTime) {
+        tree.forest()
+            .transaction(|forest| {
+                TransactionalVersionForest(forest).replace_version_info(
+                    tree.root(),
+                    version,
+                    &VersionInfo::new(created_at),
+                )?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .unwrap();
+    }
+}
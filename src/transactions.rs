@@ -1,17 +1,18 @@
 //! Each function in this module is implemented as a single `sled` transaction.
 
-use crate::{delta::Delta, TransactionalDeltaMap, TransactionalVersionForest, VersionPath};
+use crate::{
+    delta::Delta, retention::VersionSummary, Branch, Conflict, DataTree, MergeOutcome, Resolution,
+    RetentionPolicy, SnapshotError, TransactionalDeltaMap, TransactionalTagMap,
+    TransactionalVersionForest, VersionInfo, VersionPath,
+};
 
 use itertools::Itertools;
 use sled::{
-    transaction::{
-        abort, ConflictableTransactionResult, TransactionalTree, UnabortableTransactionError,
-    },
+    transaction::{abort, ConflictableTransactionResult, UnabortableTransactionError},
     IVec,
 };
-
-// TODO: for versioning multiple trees at a time, we can have another "data tree" that actually stores sets of versions of other
-// data trees
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, HashSet};
+use std::time::SystemTime;
 
 /// Creates a new tree in the snapshot forest and returns the root version.
 ///
@@ -21,24 +22,54 @@ use sled::{
 /// If `sled` runs out of IDs.
 pub fn create_snapshot_tree(
     forest: TransactionalVersionForest,
-) -> ConflictableTransactionResult<u64> {
-    forest.create_version(None)
+) -> ConflictableTransactionResult<u64, SnapshotError> {
+    let root = forest.create_version(None)?;
+    forest.set_current_version(root, root)?;
+    Ok(root)
+}
+
+/// Returns the current version of the snapshot tree rooted at `root`.
+///
+/// Aborts the transaction if `root` is not the root of a snapshot tree.
+pub fn current_version(
+    root: u64,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<u64, SnapshotError> {
+    forest.current_version(root)
+}
+
+/// Returns the current version of the snapshot tree that contains `version`.
+///
+/// Aborts the transaction if `version` does not exist.
+pub fn current_version_of(
+    version: u64,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<u64, SnapshotError> {
+    forest.current_version_of(version)
 }
 
 /// Returns `true` iff `version` is the (unique) current version in its tree.
 ///
 /// # Implementation Details
-/// True iff `version` exists in the `VersionForest` and has no entry in the `DeltaMap`. Aborts the transaction if `version`
-/// does not exist in `VersionForest`.
+/// The `VersionForest` keeps a pointer from each root to the current version of its tree. The current version is also the only
+/// version in its tree without an entry in the `DeltaMap`. Aborts the transaction if `version` does not exist in
+/// `VersionForest`.
+pub fn is_current(
+    version: u64,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<bool, SnapshotError> {
+    Ok(forest.current_version_of(version)? == version)
+}
+
+/// Same as [`is_current`]. The `DeltaMap` used to be needed to find the current version, but the `VersionForest` now keeps
+/// track of it.
+#[deprecated(note = "use `is_current`, which doesn't need the `DeltaMap`")]
 pub fn is_current_version(
     version: u64,
     forest: TransactionalVersionForest,
-    delta_map: TransactionalDeltaMap,
-) -> ConflictableTransactionResult<bool> {
-    if forest.get_version(version)?.is_none() {
-        return abort(());
-    }
-    Ok(delta_map.get_delta_list_head(version)?.is_none())
+    _delta_map: TransactionalDeltaMap,
+) -> ConflictableTransactionResult<bool, SnapshotError> {
+    is_current(version, forest)
 }
 
 /// Creates a child of `parent_version` and returns the version. The new snapshot is identical to the parent, i.e. there are no
@@ -59,15 +90,18 @@ pub fn create_child_snapshot(
     make_current: bool,
     forest: TransactionalVersionForest,
     delta_map: TransactionalDeltaMap,
-) -> ConflictableTransactionResult<u64> {
-    if make_current && !is_current_version(parent_version, forest, delta_map)? {
-        return abort(());
+) -> ConflictableTransactionResult<u64, SnapshotError> {
+    if make_current && !is_current(parent_version, forest)? {
+        return abort(SnapshotError::NotCurrentVersion(parent_version));
     }
 
     let child_version = forest.create_version(Some(parent_version))?;
 
     if make_current {
         delta_map.create_empty_version(parent_version)?;
+        let root = forest.root_of(parent_version)?;
+        forest.set_current_version(root, child_version)?;
+        forest.advance_checked_out_branch(root, parent_version, child_version)?;
     } else {
         delta_map.create_empty_version(child_version)?;
     }
@@ -85,9 +119,12 @@ pub fn modify_leaf_snapshot(
     forest: TransactionalVersionForest,
     delta_map: TransactionalDeltaMap,
     deltas: &[Delta<&[u8]>],
-) -> ConflictableTransactionResult<()> {
-    if !forest.is_leaf(version)? || is_current_version(version, forest, delta_map)? {
-        return abort(());
+) -> ConflictableTransactionResult<(), SnapshotError> {
+    if !forest.is_leaf(version)? {
+        return abort(SnapshotError::NotLeafVersion(version));
+    }
+    if is_current(version, forest)? {
+        return abort(SnapshotError::CannotModifyCurrentVersion(version));
     }
     delta_map.append_deltas(version, deltas)
 }
@@ -111,12 +148,14 @@ pub fn modify_current_leaf_snapshot(
     current_version: u64,
     forest: TransactionalVersionForest,
     delta_map: TransactionalDeltaMap,
-    data_tree: &TransactionalTree,
+    data_tree: &impl DataTree,
     deltas: &[Delta<IVec>],
-) -> ConflictableTransactionResult<()> {
-    if !forest.is_leaf(current_version)? || !is_current_version(current_version, forest, delta_map)?
-    {
-        return abort(());
+) -> ConflictableTransactionResult<(), SnapshotError> {
+    if !forest.is_leaf(current_version)? {
+        return abort(SnapshotError::NotLeafVersion(current_version));
+    }
+    if !is_current(current_version, forest)? {
+        return abort(SnapshotError::NotCurrentVersion(current_version));
     }
     if let Some(parent_version) = forest.parent_of(current_version)? {
         let reverse_deltas = apply_deltas(deltas.iter().cloned(), data_tree)?;
@@ -125,7 +164,7 @@ pub fn modify_current_leaf_snapshot(
         for delta in deltas {
             match delta {
                 Delta::Insert(key, value) => {
-                    data_tree.insert(key, value)?;
+                    data_tree.insert(key, value.clone())?;
                 }
                 Delta::Remove(key) => {
                     data_tree.remove(key)?;
@@ -142,17 +181,20 @@ pub fn create_child_snapshot_with_deltas(
     current_version: u64,
     forest: TransactionalVersionForest,
     delta_map: TransactionalDeltaMap,
-    data_tree: &TransactionalTree,
+    data_tree: &impl DataTree,
     deltas: &[Delta<IVec>],
-) -> ConflictableTransactionResult<u64> {
-    if !is_current_version(current_version, forest, delta_map)? {
-        return abort(());
+) -> ConflictableTransactionResult<u64, SnapshotError> {
+    if !is_current(current_version, forest)? {
+        return abort(SnapshotError::NotCurrentVersion(current_version));
     }
 
     let child_version = forest.create_version(Some(current_version))?;
 
     let reverse_deltas = apply_deltas(deltas.iter().cloned(), data_tree)?;
     delta_map.create_version_with_deltas(current_version, reverse_deltas)?;
+    let root = forest.root_of(current_version)?;
+    forest.set_current_version(root, child_version)?;
+    forest.advance_checked_out_branch(root, current_version, child_version)?;
 
     Ok(child_version)
 }
@@ -163,10 +205,7 @@ pub fn create_child_snapshot_with_deltas(
 /// - `current_version` is not actually the current version (as tracked by the snapshot trees)
 /// - `current_version` does not exist
 /// - `target_version` does not exist
-///
-/// # Panics
-/// If no path exists between `current_version` and `target_version`. This is only possible if these versions belong to
-/// different trees in the forest.
+/// - no path exists between `current_version` and `target_version`, i.e. they belong to different trees in the forest
 ///
 /// # Implementation Details
 ///
@@ -184,56 +223,100 @@ pub fn create_child_snapshot_with_deltas(
 /// We first transitions from `v2` to `v1`, then from `v1` to `v3`. Each step `A -> B`, involves:
 ///
 /// 1. Pops all deltas from the snapshot at `B`.
-/// 2. Applies those deltas to `data_tree`, keeping the old values as reverse deltas.
-/// 3. Inserts the reverse deltas from `data_tree` into the previously empty snapshot at `A`.
+/// 2. Applies those deltas to an in-memory overlay of `data_tree`, keeping the old values as reverse deltas.
+/// 3. Inserts the net reverse deltas, one per key, into the previously empty snapshot at `A`.
+///
+/// Only once the whole path has been walked is the overlay written to `data_tree`, so each key is written at most once, and not
+/// at all if it ends up with its original value.
 pub fn set_current_version(
     current_version: u64,
     target_version: u64,
     forest: TransactionalVersionForest,
     delta_map: TransactionalDeltaMap,
-    data_tree: &TransactionalTree,
-) -> ConflictableTransactionResult<()> {
+    data_tree: &impl DataTree,
+) -> ConflictableTransactionResult<(), SnapshotError> {
     // Make sure this is actually the current version.
-    if !is_current_version(current_version, forest, delta_map)? {
-        return abort(());
+    if !is_current(current_version, forest)? {
+        return abort(SnapshotError::NotCurrentVersion(current_version));
     }
+    check_restorable(target_version, forest)?;
 
     match forest.find_path_between_versions(current_version, target_version)? {
         VersionPath::PathExists(path) => {
+            let mut overlay = BTreeMap::new();
             for (v1, v2) in path.into_iter().tuple_windows() {
-                nudge_version(v1, v2, delta_map, data_tree)?;
+                nudge_version(v1, v2, delta_map, data_tree, &mut overlay)?;
             }
+            for (key, write) in overlay {
+                if write.value == write.original {
+                    continue;
+                }
+                match write.value {
+                    Some(value) => data_tree.insert(&key, value)?,
+                    None => data_tree.remove(&key)?,
+                };
+            }
+            forest.set_current_version(forest.root_of(target_version)?, target_version)?;
         }
         VersionPath::NoPathExists => {
-            panic!(
-                "No path exists between versions: current={} target={}",
-                current_version, target_version
-            );
+            return abort(SnapshotError::NoPathBetweenVersions {
+                start: current_version,
+                finish: target_version,
+            });
         }
     }
 
     Ok(())
 }
 
+/// A key in the overlay of the data tree used by [`set_current_version`].
+struct PendingWrite {
+    /// The value in the data tree.
+    original: Option<IVec>,
+    /// The value after the steps taken so far.
+    value: Option<IVec>,
+}
+
 fn nudge_version(
     current_version: u64,
     target_version: u64,
     delta_map: TransactionalDeltaMap,
-    data_tree: &TransactionalTree,
-) -> ConflictableTransactionResult<()> {
-    // Gather up all of the raw deltas in the target version.
+    data_tree: &impl DataTree,
+    overlay: &mut BTreeMap<IVec, PendingWrite>,
+) -> ConflictableTransactionResult<(), SnapshotError> {
     let raw_delta_nodes = delta_map
         .remove_version(target_version)?
         .expect("Version already found in transaction");
-    let mut deltas = Vec::new();
+
+    // Only the value from before this step is needed to reverse it.
+    let mut reverse_values = BTreeMap::new();
     for node in raw_delta_nodes.iter() {
-        let delta_set = node.deltas();
-        for delta in delta_set.iter_deltas() {
-            deltas.push(delta);
+        for raw_delta in node.deltas().iter_deltas() {
+            let delta = Delta::<IVec>::from(&raw_delta);
+            let write = match overlay.entry(delta.key().clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let original = data_tree.get(entry.key())?;
+                    entry.insert(PendingWrite {
+                        value: original.clone(),
+                        original,
+                    })
+                }
+            };
+            reverse_values
+                .entry(delta.key().clone())
+                .or_insert_with(|| write.value.clone());
+            write.value = delta.value().cloned();
         }
     }
 
-    let reverse_deltas = apply_deltas(deltas.iter().map(Delta::<IVec>::from), data_tree)?;
+    let reverse_deltas = reverse_values
+        .into_iter()
+        .map(|(key, value)| match value {
+            Some(value) => Delta::Insert(key, value),
+            None => Delta::Remove(key),
+        })
+        .collect();
     delta_map.create_version_with_deltas(current_version, reverse_deltas)?;
     Ok(())
 }
@@ -241,13 +324,13 @@ fn nudge_version(
 /// Applies `deltas` to `data_tree` and adds the corresponding reverse deltas to `reverse_deltas`.
 fn apply_deltas(
     deltas: impl Iterator<Item = Delta<IVec>>,
-    data_tree: &TransactionalTree,
-) -> Result<Vec<Delta<IVec>>, UnabortableTransactionError> {
+    data_tree: &impl DataTree,
+) -> ConflictableTransactionResult<Vec<Delta<IVec>>, SnapshotError> {
     let mut reverse_deltas = Vec::new();
     for delta in deltas {
         let (key, old_value) = match delta {
-            Delta::Insert(key, value) => (key.clone(), data_tree.insert(key, value)?),
-            Delta::Remove(key) => (key.clone(), data_tree.remove(key)?),
+            Delta::Insert(key, value) => (key.clone(), data_tree.insert(&key, value)?),
+            Delta::Remove(key) => (key.clone(), data_tree.remove(&key)?),
         };
         if let Some(old_value) = old_value {
             reverse_deltas.push(Delta::Insert(key.clone(), old_value));
@@ -261,8 +344,8 @@ fn apply_deltas(
 
 /// Deletes the snapshot at `version`.
 ///
-/// Deleting the current version or any root version is forbidden; any attempt to do so will abort the transaction. If
-/// necessary, you can delete an entire snapshot tree with `delete_snapshot_tree`.
+/// Deleting the current version, any root version or any tagged version is forbidden; any attempt to do so will abort the
+/// transaction. If necessary, you can delete an entire snapshot tree with `delete_snapshot_tree`.
 ///
 /// # Implementation Details
 ///
@@ -280,7 +363,7 @@ fn apply_deltas(
 ///
 /// There are two cases to consider:
 ///
-/// 1. if `C` is an ancestor of `D`
+/// 1. if `C` is not a descendent of `D` (it's an ancestor, or in some other branch)
 ///     - Move `deltas(D)` to all children of `D`
 ///     - Deltas are dropped if `D` has no children
 /// 2. else `C` is a descendent of `D`
@@ -289,22 +372,23 @@ pub fn delete_snapshot(
     version: u64,
     forest: TransactionalVersionForest,
     delta_map: TransactionalDeltaMap,
-) -> ConflictableTransactionResult<()> {
+    tags: TransactionalTagMap,
+) -> ConflictableTransactionResult<(), SnapshotError> {
     // Make sure we don't delete the current version.
-    if is_current_version(version, forest, delta_map)? {
-        return abort(());
+    let current_version = forest.current_version_of(version)?;
+    if version == current_version {
+        return abort(SnapshotError::CannotDeleteCurrentVersion(version));
     }
-
-    // See if the current version is an ancestor.
-    let mut current_is_ancestor = false;
-    let path_to_root = forest.find_path_to_root(version)?;
-    for &v in &path_to_root[1..] {
-        if is_current_version(v, forest, delta_map)? {
-            current_is_ancestor = true;
-            break;
-        }
+    // Tags must be removed explicitly so they don't silently disappear.
+    if !tags.tags_of(version)?.is_empty() {
+        return abort(SnapshotError::VersionIsTagged(version));
     }
 
+    // See if the current version is a descendent.
+    let current_is_descendent = forest
+        .find_path_to_root(current_version)?
+        .contains(&version);
+
     // Delete the version.
     let rm_node = forest
         .remove_version(version)?
@@ -315,35 +399,582 @@ pub fn delete_snapshot(
         .remove_version(version)?
         .expect("Version already found in transaction");
 
-    if current_is_ancestor {
-        // Move the deltas to every child.
-        let node_clones = vec![raw_delta_nodes; rm_node.children.len()];
-        for (&child, raw_delta_nodes) in rm_node.children.iter().zip(node_clones.into_iter()) {
-            delta_map.prepend_raw_delta_nodes(child, raw_delta_nodes)?;
-        }
-    } else {
+    if current_is_descendent {
         // Move the deltas to the parent.
         delta_map.prepend_raw_delta_nodes(
             rm_node.parent.expect("Deleting a root is forbidden"),
             raw_delta_nodes,
         )?;
+    } else {
+        // Move the deltas to every child.
+        let node_clones = vec![raw_delta_nodes; rm_node.children.len()];
+        for (&child, raw_delta_nodes) in rm_node.children.iter().zip(node_clones) {
+            delta_map.prepend_raw_delta_nodes(child, raw_delta_nodes)?;
+        }
     }
 
     Ok(())
 }
 
-/// Deletes `root` snapshot and all snapshots that have `root` as an ancestor.
+/// Deletes `root` snapshot and all snapshots that have `root` as an ancestor, along with all of their tags.
 pub fn delete_snapshot_tree(
     root: u64,
     forest: TransactionalVersionForest,
     delta_map: TransactionalDeltaMap,
-) -> ConflictableTransactionResult<()> {
+    tags: TransactionalTagMap,
+) -> ConflictableTransactionResult<(), SnapshotError> {
     forest.delete_tree(root, |deleted_version| {
         delta_map.remove_version(deleted_version)?;
+        tags.remove_version(deleted_version)?;
         Ok(())
     })
 }
 
+/// Tags `version` with `name`, so it can be found later with [`resolve_tag`]. Tagging a version with the same name twice does
+/// nothing.
+///
+/// Tagged versions can't be deleted with [`delete_snapshot`] until all of their tags are removed.
+///
+/// Aborts the transaction if:
+/// - `version` does not exist
+/// - `name` already tags a different version
+pub fn tag(
+    name: &str,
+    version: u64,
+    forest: TransactionalVersionForest,
+    tags: TransactionalTagMap,
+) -> ConflictableTransactionResult<(), SnapshotError> {
+    forest.get_existing_version(version)?;
+    tags.insert_tag(name, version)
+}
+
+/// Removes the tag `name`, returning the version it pointed to, or `None` if there was no such tag.
+pub fn untag(
+    name: &str,
+    tags: TransactionalTagMap,
+) -> ConflictableTransactionResult<Option<u64>, SnapshotError> {
+    Ok(tags.remove_tag(name)?)
+}
+
+/// Returns the version tagged with `name`, or `None` if there is no such tag.
+pub fn resolve_tag(
+    name: &str,
+    tags: TransactionalTagMap,
+) -> ConflictableTransactionResult<Option<u64>, SnapshotError> {
+    Ok(tags.resolve_tag(name)?)
+}
+
+/// Returns the names of all tags on `version`, in the order they were added.
+pub fn tags_of(
+    version: u64,
+    tags: TransactionalTagMap,
+) -> ConflictableTransactionResult<Vec<String>, SnapshotError> {
+    Ok(tags.tags_of(version)?)
+}
+
+/// Records the author, message and application-specific `user_data` of `version`, replacing any that were set before. The
+/// creation time is kept.
+///
+/// This is usually called in the same transaction that creates `version`. Aborts the transaction if `version` does not exist.
+pub fn set_version_metadata(
+    version: u64,
+    author: &str,
+    message: &str,
+    user_data: &[u8],
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<(), SnapshotError> {
+    let info = VersionInfo {
+        author: author.to_owned(),
+        message: message.to_owned(),
+        user_data: user_data.to_vec(),
+        ..forest.version_info(version)?
+    };
+    Ok(forest.set_version_info(version, &info)?)
+}
+
+/// Returns the metadata of `version`. Aborts the transaction if `version` does not exist.
+pub fn version_info(
+    version: u64,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<VersionInfo, SnapshotError> {
+    forest.version_info(version)
+}
+
+/// Creates a branch called `name` that points to `version`. Branch names are scoped to the snapshot tree containing
+/// `version`.
+///
+/// Aborts the transaction if:
+/// - `version` does not exist
+/// - the tree already has a branch called `name`
+pub fn create_branch(
+    name: &str,
+    version: u64,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<(), SnapshotError> {
+    let root = forest.root_of(version)?;
+    let mut branches = forest.list_branches(root)?;
+    match branches.binary_search_by(|b| b.name.as_str().cmp(name)) {
+        Ok(_) => abort(SnapshotError::BranchAlreadyExists(name.to_owned())),
+        Err(i) => {
+            branches.insert(
+                i,
+                Branch {
+                    name: name.to_owned(),
+                    tip: version,
+                },
+            );
+            Ok(forest.set_branches(root, &branches)?)
+        }
+    }
+}
+
+/// Restores `data_tree` to the state of the tip of branch `name` in the tree rooted at `root`, and returns the tip.
+///
+/// The branch stays checked out, so every snapshot that is made current on top of its tip, e.g. by
+/// [`create_child_snapshot_with_deltas`], [`merge`] or [`cherry_pick`], advances it. No other branch moves.
+///
+/// Aborts the transaction if:
+/// - `root` is not the root of a snapshot tree
+/// - the tree has no branch called `name`
+pub fn checkout_branch(
+    root: u64,
+    name: &str,
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+    data_tree: &impl DataTree,
+) -> ConflictableTransactionResult<u64, SnapshotError> {
+    let current_version = forest.current_version(root)?;
+    let tip = if let Some(branch) = forest
+        .list_branches(root)?
+        .into_iter()
+        .find(|b| b.name == name)
+    {
+        branch.tip
+    } else {
+        return abort(SnapshotError::BranchNotFound(name.to_owned()));
+    };
+    set_current_version(current_version, tip, forest, delta_map, data_tree)?;
+    forest.set_checked_out_branch(root, Some(name))?;
+    Ok(tip)
+}
+
+/// Returns the branches of the tree rooted at `root`, ordered by name. Aborts the transaction if `root` is not the root of a
+/// snapshot tree.
+pub fn list_branches(
+    root: u64,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<Vec<Branch>, SnapshotError> {
+    forest.current_version(root)?;
+    Ok(forest.list_branches(root)?)
+}
+
+/// Deletes the branch `name` from the tree rooted at `root`, returning its tip, or `None` if there was no such branch. The
+/// versions on the branch are left alone.
+pub fn delete_branch(
+    root: u64,
+    name: &str,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<Option<u64>, SnapshotError> {
+    let mut branches = forest.list_branches(root)?;
+    if let Some(i) = branches.iter().position(|b| b.name == name) {
+        let branch = branches.remove(i);
+        forest.set_branches(root, &branches)?;
+        if forest.checked_out_branch(root)?.as_deref() == Some(name) {
+            forest.set_checked_out_branch(root, None)?;
+        }
+        Ok(Some(branch.tip))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Collapses the linear chain of versions from `ancestor` down to `descendant` so that `descendant` becomes a child of
+/// `ancestor`. The combined deltas of the chain are compacted so each key is only changed once.
+///
+/// Every version strictly between `ancestor` and `descendant` is deleted, as if by [`delete_snapshot`], so the "ancestor of"
+/// relation is preserved and both endpoints can still be restored. If `descendant` is already a child of `ancestor`, then only
+/// the compaction happens.
+///
+/// Aborts the transaction if:
+/// - either version does not exist
+/// - `ancestor` is not a proper ancestor of `descendant`
+/// - any version between them has more than one child
+/// - any version between them is the current version or is tagged
+pub fn squash(
+    ancestor: u64,
+    descendant: u64,
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+    tags: TransactionalTagMap,
+) -> ConflictableTransactionResult<(), SnapshotError> {
+    let path_to_root = forest.find_path_to_root(descendant)?;
+    let ancestor_index = if let Some(i) = path_to_root.iter().skip(1).position(|&v| v == ancestor) {
+        i + 1
+    } else {
+        forest.get_existing_version(ancestor)?;
+        return abort(SnapshotError::NotAncestor {
+            ancestor,
+            descendant,
+        });
+    };
+    let chain = &path_to_root[1..ancestor_index];
+
+    // Check everything first so the error doesn't depend on the order of deletion.
+    let current_version = forest.current_version_of(descendant)?;
+    for &version in chain {
+        if version == current_version {
+            return abort(SnapshotError::CannotDeleteCurrentVersion(version));
+        }
+        if forest.get_existing_version(version)?.num_children() != 1 {
+            return abort(SnapshotError::NonLinearChain(version));
+        }
+        if !tags.tags_of(version)?.is_empty() {
+            return abort(SnapshotError::VersionIsTagged(version));
+        }
+    }
+
+    for &version in chain {
+        delete_snapshot(version, forest, delta_map, tags)?;
+    }
+
+    // Depending on where the current version is, the deltas were moved to one of the endpoints.
+    delta_map.compact_version(ancestor)?;
+    delta_map.compact_version(descendant)?;
+
+    Ok(())
+}
+
+/// Rewrites the delta list of `version` as a single node with exactly one net delta per key. Restoring `version` has the same
+/// effect as before, but it takes less space and less time. Returns whether anything changed; the current version and
+/// versions that are already compact are left alone.
+///
+/// See [`compact_all`](crate::compact_all) to compact every version in the forest.
+pub fn compact_version(
+    version: u64,
+    delta_map: TransactionalDeltaMap,
+) -> ConflictableTransactionResult<bool, SnapshotError> {
+    Ok(delta_map.compact_version(version)?)
+}
+
+/// Deletes every version in the tree rooted at `root` that `policy` doesn't retain, as if by [`delete_snapshot`], so all
+/// retained versions can still be restored. Ages are measured relative to `now`. Returns the deleted versions in ascending
+/// order.
+///
+/// The root, the current version, tagged versions and branch tips are never deleted, no matter what `policy` says.
+///
+/// Aborts the transaction if `root` is not the root of a snapshot tree.
+pub fn prune(
+    root: u64,
+    policy: &RetentionPolicy,
+    now: SystemTime,
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+    tags: TransactionalTagMap,
+) -> ConflictableTransactionResult<Vec<u64>, SnapshotError> {
+    let current_version = forest.current_version(root)?;
+
+    let mut protected: HashSet<u64> = [root, current_version].iter().cloned().collect();
+    protected.extend(forest.list_branches(root)?.into_iter().map(|b| b.tip));
+
+    // Transactions can't scan, so walk the tree from the root.
+    let mut versions = Vec::new();
+    let mut queue = vec![root];
+    while let Some(version) = queue.pop() {
+        let node = forest.get_existing_version(version)?;
+        queue.extend(node.iter_children());
+        if !tags.tags_of(version)?.is_empty() {
+            protected.insert(version);
+        }
+        versions.push(VersionSummary {
+            version,
+            parent: node.parent(),
+            created_at: forest.version_info(version)?.created_at,
+            is_leaf: node.num_children() == 0,
+        });
+    }
+
+    let mut deleted = Vec::new();
+    for version in policy.unretained(&versions, now) {
+        if !protected.contains(&version) {
+            delete_snapshot(version, forest, delta_map, tags)?;
+            deleted.push(version);
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Merges the changes made on the branch leading to `other_version` into the current version, as a new child snapshot that
+/// becomes the new current version.
+///
+/// The changes on each side are computed relative to the nearest common ancestor of `current_version` and `other_version`.
+/// Changes that only happened on the other side are applied. If both sides changed the same key to different values, then
+/// `resolve` decides what happens to it. All such conflicts are reported in the returned [`MergeOutcome`].
+///
+/// Note that a snapshot can only have one parent, so the new snapshot is a child of `current_version` and `other_version` is
+/// left untouched.
+///
+/// Aborts the transaction if:
+/// - `current_version` is not actually the current version
+/// - either version does not exist or they belong to different trees
+/// - `resolve` returns [`Resolution::Abort`]
+pub fn merge(
+    current_version: u64,
+    other_version: u64,
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+    data_tree: &impl DataTree,
+    resolve: impl FnMut(&Conflict) -> Resolution,
+) -> ConflictableTransactionResult<MergeOutcome, SnapshotError> {
+    let base =
+        if let Some(base) = forest.find_nearest_common_ancestor(current_version, other_version)? {
+            base
+        } else {
+            return abort(SnapshotError::NoPathBetweenVersions {
+                start: current_version,
+                finish: other_version,
+            });
+        };
+
+    merge_onto_current_version(
+        current_version,
+        base,
+        other_version,
+        forest,
+        delta_map,
+        data_tree,
+        resolve,
+    )
+}
+
+/// Applies the changes that `version` introduced relative to its parent onto the current version, as a new child snapshot
+/// that becomes the new current version.
+///
+/// A key conflicts if the current version no longer has the value it had in `version`'s parent, and doesn't already have the
+/// value from `version`. Each conflict is passed to `resolve`, where [`Resolution::Ours`] keeps the current value and
+/// [`Resolution::Theirs`] takes the value from `version`. All conflicts are reported in the returned [`MergeOutcome`].
+///
+/// Aborts the transaction if:
+/// - `current_version` is not actually the current version
+/// - either version does not exist or they belong to different trees
+/// - `version` is a root, so it has no parent to compare against
+/// - `resolve` returns [`Resolution::Abort`]
+pub fn cherry_pick(
+    version: u64,
+    current_version: u64,
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+    data_tree: &impl DataTree,
+    resolve: impl FnMut(&Conflict) -> Resolution,
+) -> ConflictableTransactionResult<MergeOutcome, SnapshotError> {
+    let parent = if let Some(parent) = forest.parent_of(version)? {
+        parent
+    } else {
+        return abort(SnapshotError::CannotCherryPickRootVersion(version));
+    };
+
+    merge_onto_current_version(
+        current_version,
+        parent,
+        version,
+        forest,
+        delta_map,
+        data_tree,
+        resolve,
+    )
+}
+
+/// Applies the changes from `base` to `theirs` onto the current version as a new child snapshot.
+fn merge_onto_current_version(
+    current_version: u64,
+    base: u64,
+    theirs: u64,
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+    data_tree: &impl DataTree,
+    mut resolve: impl FnMut(&Conflict) -> Resolution,
+) -> ConflictableTransactionResult<MergeOutcome, SnapshotError> {
+    if !is_current(current_version, forest)? {
+        return abort(SnapshotError::NotCurrentVersion(current_version));
+    }
+
+    let our_changes: BTreeMap<IVec, Delta<IVec>> =
+        diff_versions(base, current_version, forest, delta_map, data_tree)?
+            .into_iter()
+            .map(|delta| (delta.key().clone(), delta))
+            .collect();
+    let their_changes = diff_versions(base, theirs, forest, delta_map, data_tree)?;
+
+    let mut deltas = Vec::new();
+    let mut conflicts = Vec::new();
+    for their_delta in their_changes {
+        let our_delta = match our_changes.get(their_delta.key()) {
+            None => {
+                deltas.push(their_delta);
+                continue;
+            }
+            Some(our_delta) if *our_delta == their_delta => continue,
+            Some(our_delta) => our_delta,
+        };
+
+        let key = their_delta.key().clone();
+        let conflict = Conflict {
+            base: get_at_version(base, &key, forest, delta_map, data_tree)?,
+            ours: our_delta.value().cloned(),
+            theirs: their_delta.value().cloned(),
+            key: key.clone(),
+        };
+        match resolve(&conflict) {
+            Resolution::Ours => (),
+            Resolution::Theirs => deltas.push(their_delta),
+            Resolution::Value(Some(value)) => deltas.push(Delta::Insert(key, value)),
+            Resolution::Value(None) => deltas.push(Delta::Remove(key)),
+            Resolution::Abort => return abort(SnapshotError::UnresolvedConflict(key)),
+        }
+        conflicts.push(conflict);
+    }
+
+    let version =
+        create_child_snapshot_with_deltas(current_version, forest, delta_map, data_tree, &deltas)?;
+
+    Ok(MergeOutcome { version, conflicts })
+}
+
+/// Returns the value of `key` in the `version` snapshot, without modifying anything.
+///
+/// Aborts the transaction if `version` does not exist.
+///
+/// # Implementation Details
+///
+/// The data tree always holds the state of the current version. Starting from the value in `data_tree`, we trace the version
+/// path from the current version to `version` (just like [`set_current_version`]), and the last delta for `key` along the way
+/// determines the value at `version`.
+pub fn get_at_version(
+    version: u64,
+    key: &[u8],
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+    data_tree: &impl DataTree,
+) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+    let mut value = data_tree.get(key)?;
+    let path = find_path_from_current_version(version, forest)?;
+    for_each_delta_along_path(&path, delta_map, |delta| match delta {
+        Delta::Insert(k, v) if k == key => value = Some(IVec::from(v)),
+        Delta::Remove(k) if k == key => value = None,
+        _ => (),
+    })?;
+    Ok(value)
+}
+
+/// Returns the minimal set of deltas that turns the state of snapshot `start` into the state of snapshot `finish`, without
+/// modifying anything.
+///
+/// There is at most one delta per key, and the deltas are sorted by key. Keys that were changed and then changed back along
+/// the way are left out.
+///
+/// Aborts the transaction if either version does not exist or they belong to different snapshot trees.
+pub fn diff_versions(
+    start: u64,
+    finish: u64,
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+    data_tree: &impl DataTree,
+) -> ConflictableTransactionResult<Vec<Delta<IVec>>, SnapshotError> {
+    if forest.root_of(start)? != forest.root_of(finish)? {
+        return abort(SnapshotError::NoPathBetweenVersions { start, finish });
+    }
+
+    let start_deltas = net_deltas_to_version(start, forest, delta_map)?;
+    let finish_deltas = net_deltas_to_version(finish, forest, delta_map)?;
+
+    let changed_keys: BTreeSet<&IVec> = start_deltas.keys().chain(finish_deltas.keys()).collect();
+    let mut diff = Vec::new();
+    for key in changed_keys {
+        let (start_value, finish_value) = match (start_deltas.get(key), finish_deltas.get(key)) {
+            (Some(s), Some(f)) => (s.clone(), f.clone()),
+            (Some(s), None) => (s.clone(), data_tree.get(key)?),
+            (None, Some(f)) => (data_tree.get(key)?, f.clone()),
+            (None, None) => unreachable!(),
+        };
+        if start_value != finish_value {
+            diff.push(match finish_value {
+                Some(value) => Delta::Insert(key.clone(), value),
+                None => Delta::Remove(key.clone()),
+            });
+        }
+    }
+    Ok(diff)
+}
+
+/// Returns the net change that restoring `version` would make to the data tree, without modifying anything.
+///
+/// Each key maps to its value at `version`, or `None` if it's absent at `version`. Keys that aren't in the map have the same
+/// value as in the data tree. Some entries may not actually differ from the data tree, e.g. if a key was changed and then
+/// changed back along the way.
+pub(crate) fn net_deltas_to_version(
+    version: u64,
+    forest: TransactionalVersionForest,
+    delta_map: TransactionalDeltaMap,
+) -> ConflictableTransactionResult<BTreeMap<IVec, Option<IVec>>, SnapshotError> {
+    let mut net_deltas = BTreeMap::new();
+    let path = find_path_from_current_version(version, forest)?;
+    for_each_delta_along_path(&path, delta_map, |delta| match delta {
+        Delta::Insert(k, v) => {
+            net_deltas.insert(IVec::from(k), Some(IVec::from(v)));
+        }
+        Delta::Remove(k) => {
+            net_deltas.insert(IVec::from(k), None);
+        }
+    })?;
+    Ok(net_deltas)
+}
+
+/// Returns the path from the current version of `version`'s tree to `version`.
+fn find_path_from_current_version(
+    version: u64,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<Vec<u64>, SnapshotError> {
+    let current_version = forest.current_version_of(version)?;
+    check_restorable(version, forest)?;
+    match forest.find_path_between_versions(current_version, version)? {
+        VersionPath::PathExists(path) => Ok(path),
+        VersionPath::NoPathExists => {
+            unreachable!("Inconsistent forest: current version is in a different tree")
+        }
+    }
+}
+
+/// Aborts if `version` lost some of its deltas in [`repair_forest`](crate::repair_forest).
+fn check_restorable(
+    version: u64,
+    forest: TransactionalVersionForest,
+) -> ConflictableTransactionResult<(), SnapshotError> {
+    if !forest.is_restorable(version)? {
+        return abort(SnapshotError::UnrestorableVersion(version));
+    }
+    Ok(())
+}
+
+/// Calls `f` on each delta that restoring the last version in `path` would apply to a data tree at the first version in `path`,
+/// in the order they would be applied.
+fn for_each_delta_along_path(
+    path: &[u64],
+    delta_map: TransactionalDeltaMap,
+    mut f: impl FnMut(Delta<&[u8]>),
+) -> Result<(), UnabortableTransactionError> {
+    for &version in path.iter().skip(1) {
+        let raw_delta_nodes = delta_map
+            .get_delta_nodes(version)?
+            .expect("Inconsistent forest: non-current version has no deltas");
+        for node in raw_delta_nodes.iter() {
+            for delta in node.deltas().iter_deltas() {
+                f(Delta::<&[u8]>::from(&delta));
+            }
+        }
+    }
+    Ok(())
+}
+
 // ████████╗███████╗███████╗████████╗
 // ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
 //    ██║   █████╗  ███████╗   ██║
@@ -353,9 +984,14 @@ pub fn delete_snapshot_tree(
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{open_snapshot_forest, DeltaMap, VersionForest};
+    use crate::{open_snapshot_forest, open_tag_map, DeltaMap, VersionForest};
 
-    use sled::{transaction::TransactionError, Transactional};
+    use sled::{
+        transaction::{TransactionError, TransactionalTree},
+        Transactional,
+    };
+    use std::cell::Cell;
+    use std::time::{Duration, SystemTime};
 
     #[test]
     fn initial_snapshot_tree_has_only_v0() {
@@ -373,10 +1009,11 @@ mod test {
     fn delete_current_version_aborts() {
         let fixture = Fixture::open();
         let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
         let data_tree = fixture.db.open_tree("data").unwrap();
 
-        let result =
-            (&data_tree, &*forest, &*delta_map).transaction(|(data_tree, forest, delta_map)| {
+        let result = (&data_tree, &*forest, &*delta_map, &*tags).transaction(
+            |(data_tree, forest, delta_map, tags)| {
                 let forest = TransactionalVersionForest(forest);
                 let delta_map = TransactionalDeltaMap(delta_map);
                 let v0 = create_snapshot_tree(forest)?;
@@ -385,10 +1022,89 @@ mod test {
                 let v1 =
                     create_child_snapshot_with_deltas(v0, forest, delta_map, data_tree, &deltas)?;
 
-                delete_snapshot(v1, forest, delta_map)
-            });
+                delete_snapshot(v1, forest, delta_map, TransactionalTagMap(tags))
+            },
+        );
+
+        assert!(matches!(
+            result,
+            Err(TransactionError::Abort(
+                SnapshotError::CannotDeleteCurrentVersion(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn preconditions_abort_with_specific_errors() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        let transact = |f: &dyn Fn(
+            TransactionalVersionForest,
+            TransactionalDeltaMap,
+            &TransactionalTree,
+        ) -> ConflictableTransactionResult<(), SnapshotError>| {
+            (&data_tree, &*forest, &*delta_map).transaction(|(data_tree, forest, delta_map)| {
+                f(
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    data_tree,
+                )
+            })
+        };
 
-        assert_eq!(result, Err(TransactionError::Abort(())));
+        assert_eq!(
+            transact(&|forest, delta_map, data_tree| set_current_version(
+                v1, v0, forest, delta_map, data_tree
+            )),
+            Err(TransactionError::Abort(SnapshotError::NotCurrentVersion(
+                v1
+            )))
+        );
+        assert_eq!(
+            transact(
+                &|forest, delta_map, data_tree| modify_current_leaf_snapshot(
+                    v1,
+                    forest,
+                    delta_map,
+                    data_tree,
+                    &[]
+                )
+            ),
+            Err(TransactionError::Abort(SnapshotError::NotLeafVersion(v1)))
+        );
+        assert_eq!(
+            transact(&|forest, delta_map, _| modify_leaf_snapshot(v2, forest, delta_map, &[])),
+            Err(TransactionError::Abort(
+                SnapshotError::CannotModifyCurrentVersion(v2)
+            ))
+        );
+        assert_eq!(
+            transact(&|forest, delta_map, data_tree| {
+                let other_root = create_snapshot_tree(forest)?;
+                set_current_version(v2, other_root, forest, delta_map, data_tree)
+            })
+            .map_err(|e| matches!(
+                e,
+                TransactionError::Abort(SnapshotError::NoPathBetweenVersions { .. })
+            )),
+            Err(true)
+        );
+        assert_eq!(
+            (&*forest, &*delta_map, &*tags).transaction(|(forest, delta_map, tags)| {
+                delete_snapshot(
+                    666,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            }),
+            Err(TransactionError::Abort(SnapshotError::VersionNotFound(666)))
+        );
     }
 
     #[test]
@@ -430,21 +1146,63 @@ mod test {
     }
 
     #[test]
-    fn delete_v1_while_v2_and_restore() {
+    fn current_version_is_tracked_by_forest() {
         let fixture = Fixture::open();
         let (v0, v1, v2) = fixture.create_three_snapshots();
 
         let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
-
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
         let data_tree = fixture.db.open_tree("data").unwrap();
 
-        // Delete v1 while current version is v2.
-        (&*forest, &*delta_map)
-            .transaction(|(forest, delta_map)| {
+        assert_eq!(forest.current_version(v0), Ok(Some(v2)));
+        assert_eq!(forest.current_version_of(v1), Ok(Some(v2)));
+
+        restore(v2, v1, &data_tree, &forest, &delta_map);
+        assert_eq!(forest.current_version(v0), Ok(Some(v1)));
+
+        let v3 = (&*forest, &*delta_map)
+            .transaction(|(forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                let v3 = create_child_snapshot(v1, true, forest, delta_map)?;
+                assert_eq!(current_version(v0, forest)?, v3);
+                assert!(!is_current(v1, forest)?);
+                Ok(v3)
+            })
+            .unwrap();
+        assert_eq!(forest.current_version_of(v2), Ok(Some(v3)));
+
+        (&*forest, &*delta_map, &*tags)
+            .transaction(|(forest, delta_map, tags)| {
+                delete_snapshot_tree(
+                    v0,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+            .unwrap();
+        assert_eq!(forest.current_version(v0), Ok(None));
+        assert_eq!(forest.collect_versions(), Ok(vec![]));
+    }
+
+    #[test]
+    fn delete_v1_while_v2_and_restore() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        // Delete v1 while current version is v2.
+        (&*forest, &*delta_map, &*tags)
+            .transaction(|(forest, delta_map, tags)| {
                 let forest = TransactionalVersionForest(forest);
                 let delta_map = TransactionalDeltaMap(delta_map);
 
-                delete_snapshot(v1, forest, delta_map)
+                delete_snapshot(v1, forest, delta_map, TransactionalTagMap(tags))
             })
             .unwrap();
 
@@ -485,18 +1243,19 @@ mod test {
         let (v0, v1, v2) = fixture.create_three_snapshots();
 
         let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
 
         let data_tree = fixture.db.open_tree("data").unwrap();
 
         // Delete v1 while current version is v2.
-        (&data_tree, &*forest, &*delta_map)
-            .transaction(|(data_tree, forest, delta_map)| {
+        (&data_tree, &*forest, &*delta_map, &*tags)
+            .transaction(|(data_tree, forest, delta_map, tags)| {
                 let forest = TransactionalVersionForest(forest);
                 let delta_map = TransactionalDeltaMap(delta_map);
 
                 set_current_version(v2, v0, forest, delta_map, data_tree)?;
 
-                delete_snapshot(v1, forest, delta_map)
+                delete_snapshot(v1, forest, delta_map, TransactionalTagMap(tags))
             })
             .unwrap();
 
@@ -527,6 +1286,732 @@ mod test {
         );
     }
 
+    #[test]
+    fn delete_v1_while_on_sibling_branch_and_restore() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        // Delete v1 while the current version is on a sibling branch of v1, so the deltas of v1 must move to v2 rather than v0.
+        let s1 = (&data_tree, &*forest, &*delta_map, &*tags)
+            .transaction(|(data_tree, forest, delta_map, tags)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+
+                set_current_version(v2, v0, forest, delta_map, data_tree)?;
+                let s1 = create_child_snapshot_with_deltas(
+                    v0,
+                    forest,
+                    delta_map,
+                    data_tree,
+                    &[Delta::Insert(IVec::from(b"key3"), IVec::from(b"value3"))],
+                )?;
+
+                delete_snapshot(v1, forest, delta_map, TransactionalTagMap(tags))?;
+                Ok(s1)
+            })
+            .unwrap();
+
+        // Restore v0.
+        restore(s1, v0, &data_tree, &forest, &delta_map);
+        // Expect state at v0.
+        assert_contents(
+            &data_tree,
+            vec![(IVec::from(b"key0"), IVec::from(b"value0"))],
+        );
+
+        // Restore v2.
+        restore(v0, v2, &data_tree, &forest, &delta_map);
+        // Expect state at v2.
+        assert_contents(
+            &data_tree,
+            vec![
+                (IVec::from(b"key0"), IVec::from(b"value0")),
+                (IVec::from(b"key1"), IVec::from(b"value1")),
+                (IVec::from(b"key2"), IVec::from(b"value2")),
+            ],
+        );
+    }
+
+    #[test]
+    fn get_at_version_reads_history_without_restoring() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        let get = |version: u64, key: &[u8]| {
+            (&data_tree, &*forest, &*delta_map)
+                .transaction(|(data_tree, forest, delta_map)| {
+                    get_at_version(
+                        version,
+                        key,
+                        TransactionalVersionForest(forest),
+                        TransactionalDeltaMap(delta_map),
+                        data_tree,
+                    )
+                })
+                .unwrap()
+        };
+
+        assert_eq!(get(v0, b"key0"), Some(IVec::from(b"value0")));
+        assert_eq!(get(v0, b"key1"), None);
+        assert_eq!(get(v1, b"key1"), Some(IVec::from(b"value1")));
+        assert_eq!(get(v1, b"key2"), None);
+        assert_eq!(get(v2, b"key2"), Some(IVec::from(b"value2")));
+
+        // Nothing was restored.
+        assert_eq!(forest.current_version(v0), Ok(Some(v2)));
+        assert_eq!(data_tree.len(), 3);
+
+        // Also works in the other direction.
+        restore(v2, v0, &data_tree, &forest, &delta_map);
+        assert_eq!(get(v2, b"key2"), Some(IVec::from(b"value2")));
+        assert_eq!(get(v1, b"key2"), None);
+        assert_eq!(get(v0, b"key1"), None);
+    }
+
+    #[test]
+    fn diff_versions_is_minimal() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        let diff = |start: u64, finish: u64| {
+            (&data_tree, &*forest, &*delta_map)
+                .transaction(|(data_tree, forest, delta_map)| {
+                    diff_versions(
+                        start,
+                        finish,
+                        TransactionalVersionForest(forest),
+                        TransactionalDeltaMap(delta_map),
+                        data_tree,
+                    )
+                })
+                .unwrap()
+        };
+
+        // Change key0 and then change it back.
+        let v3 = (&data_tree, &*forest, &*delta_map)
+            .transaction(|(data_tree, forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                let deltas = [
+                    Delta::Insert(IVec::from(b"key0"), IVec::from(b"changed")),
+                    Delta::Remove(IVec::from(b"key1")),
+                ];
+                let v3 =
+                    create_child_snapshot_with_deltas(v2, forest, delta_map, data_tree, &deltas)?;
+                let deltas = [Delta::Insert(IVec::from(b"key0"), IVec::from(b"value0"))];
+                create_child_snapshot_with_deltas(v3, forest, delta_map, data_tree, &deltas)
+            })
+            .unwrap();
+
+        assert_eq!(diff(v1, v1), vec![]);
+        assert_eq!(
+            diff(v0, v2),
+            vec![
+                Delta::Insert(IVec::from(b"key1"), IVec::from(b"value1")),
+                Delta::Insert(IVec::from(b"key2"), IVec::from(b"value2")),
+            ]
+        );
+        assert_eq!(
+            diff(v2, v0),
+            vec![
+                Delta::Remove(IVec::from(b"key1")),
+                Delta::Remove(IVec::from(b"key2")),
+            ]
+        );
+        assert_eq!(diff(v2, v3), vec![Delta::Remove(IVec::from(b"key1"))]);
+        assert_eq!(
+            diff(v0, v3),
+            vec![Delta::Insert(IVec::from(b"key2"), IVec::from(b"value2"))]
+        );
+    }
+
+    #[test]
+    fn squash_chain_and_restore_endpoints() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        let v3 = (&data_tree, &*forest, &*delta_map)
+            .transaction(|(data_tree, forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                let deltas = [
+                    Delta::Insert(IVec::from(b"key1"), IVec::from(b"changed")),
+                    Delta::Insert(IVec::from(b"key3"), IVec::from(b"value3")),
+                ];
+                create_child_snapshot_with_deltas(v2, forest, delta_map, data_tree, &deltas)
+            })
+            .unwrap();
+
+        // Squash with the current version at the bottom of the chain.
+        (&*forest, &*delta_map, &*tags)
+            .transaction(|(forest, delta_map, tags)| {
+                squash(
+                    v0,
+                    v2,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+            .unwrap();
+        assert_eq!(forest.collect_versions().unwrap().len(), 3);
+        assert!(!forest.collect_versions().unwrap().contains(&v1));
+
+        restore(v3, v0, &data_tree, &forest, &delta_map);
+        assert_contents(
+            &data_tree,
+            vec![(IVec::from(b"key0"), IVec::from(b"value0"))],
+        );
+
+        // Squash with the current version at the top of the chain.
+        (&*forest, &*delta_map, &*tags)
+            .transaction(|(forest, delta_map, tags)| {
+                squash(
+                    v0,
+                    v3,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+            .unwrap();
+        assert_eq!(forest.collect_versions(), Ok(vec![v0, v3]));
+
+        restore(v0, v3, &data_tree, &forest, &delta_map);
+        assert_contents(
+            &data_tree,
+            vec![
+                (IVec::from(b"key0"), IVec::from(b"value0")),
+                (IVec::from(b"key1"), IVec::from(b"changed")),
+                (IVec::from(b"key2"), IVec::from(b"value2")),
+                (IVec::from(b"key3"), IVec::from(b"value3")),
+            ],
+        );
+        restore(v3, v0, &data_tree, &forest, &delta_map);
+        assert_contents(
+            &data_tree,
+            vec![(IVec::from(b"key0"), IVec::from(b"value0"))],
+        );
+    }
+
+    #[test]
+    fn squash_branching_chain_aborts() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+
+        let result = (&*forest, &*delta_map, &*tags).transaction(|(forest, delta_map, tags)| {
+            let forest = TransactionalVersionForest(forest);
+            let delta_map = TransactionalDeltaMap(delta_map);
+            create_child_snapshot(v1, false, forest, delta_map)?;
+            squash(v0, v2, forest, delta_map, TransactionalTagMap(tags))
+        });
+        assert_eq!(
+            result,
+            Err(TransactionError::Abort(SnapshotError::NonLinearChain(v1)))
+        );
+
+        let result = (&*forest, &*delta_map, &*tags).transaction(|(forest, delta_map, tags)| {
+            squash(
+                v2,
+                v0,
+                TransactionalVersionForest(forest),
+                TransactionalDeltaMap(delta_map),
+                TransactionalTagMap(tags),
+            )
+        });
+        assert_eq!(
+            result,
+            Err(TransactionError::Abort(SnapshotError::NotAncestor {
+                ancestor: v2,
+                descendant: v0
+            }))
+        );
+    }
+
+    #[test]
+    fn tags_protect_versions_from_deletion() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+
+        let transact = |f: &dyn Fn(
+            TransactionalVersionForest,
+            TransactionalDeltaMap,
+            TransactionalTagMap,
+        ) -> ConflictableTransactionResult<(), SnapshotError>| {
+            (&*forest, &*delta_map, &*tags).transaction(|(forest, delta_map, tags)| {
+                f(
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+        };
+
+        transact(&|forest, _, tags| {
+            tag("release", v1, forest, tags)?;
+            tag("stable", v1, forest, tags)?;
+            tag("release", v1, forest, tags)?;
+            tag("first", v0, forest, tags)?;
+            assert_eq!(resolve_tag("release", tags)?, Some(v1));
+            assert_eq!(resolve_tag("nope", tags)?, None);
+            assert_eq!(tags_of(v1, tags)?, vec!["release", "stable"]);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            tags.iter_tags().collect::<sled::Result<Vec<_>>>().unwrap(),
+            vec![
+                ("first".to_owned(), v0),
+                ("release".to_owned(), v1),
+                ("stable".to_owned(), v1)
+            ]
+        );
+
+        assert_eq!(
+            transact(&|forest, _, tags| tag("release", v2, forest, tags)),
+            Err(TransactionError::Abort(SnapshotError::TagAlreadyExists(
+                "release".to_owned()
+            )))
+        );
+        assert_eq!(
+            transact(&|forest, delta_map, tags| delete_snapshot(v1, forest, delta_map, tags)),
+            Err(TransactionError::Abort(SnapshotError::VersionIsTagged(v1)))
+        );
+        assert_eq!(
+            transact(&|forest, delta_map, tags| squash(v0, v2, forest, delta_map, tags)),
+            Err(TransactionError::Abort(SnapshotError::VersionIsTagged(v1)))
+        );
+
+        transact(&|forest, delta_map, tags| {
+            assert_eq!(untag("release", tags)?, Some(v1));
+            assert_eq!(untag("stable", tags)?, Some(v1));
+            assert_eq!(untag("stable", tags)?, None);
+            delete_snapshot(v1, forest, delta_map, tags)
+        })
+        .unwrap();
+
+        transact(&|forest, delta_map, tags| delete_snapshot_tree(v0, forest, delta_map, tags))
+            .unwrap();
+        assert_eq!(tags.resolve_tag("first"), Ok(None));
+        assert!(tags.is_empty());
+    }
+
+    #[test]
+    fn version_metadata_lives_and_dies_with_version() {
+        let fixture = Fixture::open();
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+
+        let before = SystemTime::now();
+        let (v0, v1) = (&*forest, &*delta_map)
+            .transaction(|(forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                let v0 = create_snapshot_tree(forest)?;
+                let v1 = create_child_snapshot(v0, false, forest, delta_map)?;
+                set_version_metadata(v1, "alice", "first draft", b"\x01\x02", forest)?;
+                Ok((v0, v1))
+            })
+            .unwrap();
+        let after = SystemTime::now();
+
+        let info = forest.version_info(v1).unwrap().unwrap();
+        assert_eq!(info.author, "alice");
+        assert_eq!(info.message, "first draft");
+        assert_eq!(info.user_data, b"\x01\x02");
+        assert!(before <= info.created_at && info.created_at <= after);
+
+        let root_info = forest.version_info(v0).unwrap().unwrap();
+        assert_eq!(root_info.author, "");
+        assert!(root_info.created_at <= info.created_at);
+
+        (&*forest, &*delta_map, &*tags)
+            .transaction(|(forest, delta_map, tags)| {
+                delete_snapshot(
+                    v1,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+            .unwrap();
+        assert_eq!(forest.version_info(v1), Ok(None));
+        assert_eq!(
+            forest.transaction(|forest| version_info(v1, TransactionalVersionForest(forest))),
+            Err(TransactionError::Abort(SnapshotError::VersionNotFound(v1)))
+        );
+
+        (&*forest, &*delta_map, &*tags)
+            .transaction(|(forest, delta_map, tags)| {
+                delete_snapshot_tree(
+                    v0,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+            .unwrap();
+        // Only the format of the forest is left.
+        assert_eq!(forest.len(), 1);
+    }
+
+    #[test]
+    fn prune_skips_protected_versions() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        // v0 -> v1 -> v2 -> v3 -> v4 (current), with v1 tagged and a branch at v2.
+        let (v3, v4) = (&data_tree, &*forest, &*delta_map, &*tags)
+            .transaction(|(data_tree, forest, delta_map, tags)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                tag("keep", v1, forest, TransactionalTagMap(tags))?;
+                let deltas = [Delta::Insert(IVec::from(b"key3"), IVec::from(b"value3"))];
+                let v3 =
+                    create_child_snapshot_with_deltas(v2, forest, delta_map, data_tree, &deltas)?;
+                create_branch("old", v2, forest)?;
+                let v4 = create_child_snapshot(v3, true, forest, delta_map)?;
+                Ok((v3, v4))
+            })
+            .unwrap();
+
+        let prune_at = |now: SystemTime, policy: &RetentionPolicy| {
+            (&*forest, &*delta_map, &*tags).transaction(|(forest, delta_map, tags)| {
+                prune(
+                    v0,
+                    policy,
+                    now,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+        };
+
+        let keep_last = RetentionPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(prune_at(SystemTime::now(), &keep_last), Ok(vec![v3]));
+
+        (&*forest, &*tags)
+            .transaction(|(forest, tags)| {
+                untag("keep", TransactionalTagMap(tags))?;
+                delete_branch(v0, "old", TransactionalVersionForest(forest))
+            })
+            .unwrap();
+
+        // Everything unprotected is too old.
+        let max_age = RetentionPolicy {
+            max_age: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let mut expected = vec![v1, v2];
+        expected.sort_unstable();
+        assert_eq!(
+            prune_at(SystemTime::now() + Duration::from_secs(60), &max_age),
+            Ok(expected)
+        );
+        assert_eq!(forest.collect_versions().unwrap().len(), 2);
+
+        // Both remaining versions are intact.
+        let v4_contents = vec![
+            (IVec::from(b"key0"), IVec::from(b"value0")),
+            (IVec::from(b"key1"), IVec::from(b"value1")),
+            (IVec::from(b"key2"), IVec::from(b"value2")),
+            (IVec::from(b"key3"), IVec::from(b"value3")),
+        ];
+        assert_contents(&data_tree, v4_contents);
+        restore(v4, v0, &data_tree, &forest, &delta_map);
+        assert_contents(
+            &data_tree,
+            vec![(IVec::from(b"key0"), IVec::from(b"value0"))],
+        );
+    }
+
+    #[test]
+    fn deletion_frees_delta_nodes() {
+        let fixture = Fixture::open();
+        let (v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let tags = open_tag_map(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        restore(v2, v0, &data_tree, &forest, &delta_map);
+        (&*forest, &*delta_map, &*tags)
+            .transaction(|(forest, delta_map, tags)| {
+                delete_snapshot(
+                    v1,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+            .unwrap();
+        assert_eq!(delta_map.sweep_orphans(&forest), Ok(0));
+
+        // Simulate a node leaked by an older version of this crate.
+        delta_map
+            .insert(fixture.db.generate_id().unwrap().to_be_bytes(), &[])
+            .unwrap();
+        assert_eq!(delta_map.sweep_orphans(&forest), Ok(1));
+
+        restore(v0, v2, &data_tree, &forest, &delta_map);
+        assert_contents(
+            &data_tree,
+            vec![
+                (IVec::from(b"key0"), IVec::from(b"value0")),
+                (IVec::from(b"key1"), IVec::from(b"value1")),
+                (IVec::from(b"key2"), IVec::from(b"value2")),
+            ],
+        );
+
+        (&*forest, &*delta_map, &*tags)
+            .transaction(|(forest, delta_map, tags)| {
+                delete_snapshot_tree(
+                    v0,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    TransactionalTagMap(tags),
+                )
+            })
+            .unwrap();
+        assert!(delta_map.is_empty());
+    }
+
+    #[test]
+    fn restore_long_path_writes_each_key_once() {
+        let fixture = Fixture::open();
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+        data_tree.insert(b"counter", b"0").unwrap();
+
+        let (v0, leaf) = (&data_tree, &*forest, &*delta_map)
+            .transaction(|(data_tree, forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                let v0 = create_snapshot_tree(forest)?;
+                let mut current = v0;
+                for i in 1..=100u32 {
+                    let deltas = [
+                        Delta::Insert(IVec::from(b"counter"), IVec::from(&i.to_be_bytes())),
+                        Delta::Insert(IVec::from(&i.to_be_bytes()), IVec::from(b"x")),
+                        Delta::Remove(IVec::from(&(i - 1).to_be_bytes())),
+                    ];
+                    current = create_child_snapshot_with_deltas(
+                        current, forest, delta_map, data_tree, &deltas,
+                    )?;
+                }
+                Ok((v0, current))
+            })
+            .unwrap();
+        assert_eq!(data_tree.len(), 2);
+
+        let num_writes = (&data_tree, &*forest, &*delta_map)
+            .transaction(|(data_tree, forest, delta_map)| {
+                let data_tree = CountingDataTree {
+                    tree: data_tree,
+                    num_writes: Cell::new(0),
+                };
+                set_current_version(
+                    leaf,
+                    v0,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    &data_tree,
+                )?;
+                Ok(data_tree.num_writes.get())
+            })
+            .unwrap();
+        assert_contents(&data_tree, vec![(IVec::from(b"counter"), IVec::from(b"0"))]);
+        // "counter" and key 100 change, but none of the intermediate keys are touched.
+        assert_eq!(num_writes, 2);
+
+        restore(v0, leaf, &data_tree, &forest, &delta_map);
+        assert_contents(
+            &data_tree,
+            vec![
+                (IVec::from(&100u32.to_be_bytes()), IVec::from(b"x")),
+                (IVec::from(b"counter"), IVec::from(&100u32.to_be_bytes())),
+            ],
+        );
+    }
+
+    #[test]
+    fn merge_branches_with_conflicts() {
+        let fixture = Fixture::open();
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+        data_tree.insert(b"key0", b"value0").unwrap();
+        data_tree.insert(b"key1", b"value1").unwrap();
+
+        let (ours, theirs) = (&data_tree, &*forest, &*delta_map)
+            .transaction(|(data_tree, forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                let v0 = create_snapshot_tree(forest)?;
+
+                let deltas = [
+                    Delta::Insert(IVec::from(b"key0"), IVec::from(b"theirs")),
+                    Delta::Remove(IVec::from(b"key1")),
+                    Delta::Insert(IVec::from(b"key2"), IVec::from(b"value2")),
+                ];
+                let theirs =
+                    create_child_snapshot_with_deltas(v0, forest, delta_map, data_tree, &deltas)?;
+                set_current_version(theirs, v0, forest, delta_map, data_tree)?;
+
+                let deltas = [
+                    Delta::Insert(IVec::from(b"key0"), IVec::from(b"ours")),
+                    Delta::Remove(IVec::from(b"key1")),
+                    Delta::Insert(IVec::from(b"key3"), IVec::from(b"value3")),
+                ];
+                let ours =
+                    create_child_snapshot_with_deltas(v0, forest, delta_map, data_tree, &deltas)?;
+
+                Ok((ours, theirs))
+            })
+            .unwrap();
+
+        let merge_with = |resolution: Resolution| {
+            (&data_tree, &*forest, &*delta_map).transaction(|(data_tree, forest, delta_map)| {
+                merge(
+                    ours,
+                    theirs,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    data_tree,
+                    |_| resolution.clone(),
+                )
+            })
+        };
+
+        assert_eq!(
+            merge_with(Resolution::Abort),
+            Err(TransactionError::Abort(SnapshotError::UnresolvedConflict(
+                IVec::from(b"key0")
+            )))
+        );
+
+        let outcome = merge_with(Resolution::Theirs).unwrap();
+        assert_eq!(
+            outcome.conflicts,
+            vec![Conflict {
+                key: IVec::from(b"key0"),
+                base: Some(IVec::from(b"value0")),
+                ours: Some(IVec::from(b"ours")),
+                theirs: Some(IVec::from(b"theirs")),
+            }]
+        );
+        assert_eq!(forest.current_version_of(ours), Ok(Some(outcome.version)));
+        assert_contents(
+            &data_tree,
+            vec![
+                (IVec::from(b"key0"), IVec::from(b"theirs")),
+                (IVec::from(b"key2"), IVec::from(b"value2")),
+                (IVec::from(b"key3"), IVec::from(b"value3")),
+            ],
+        );
+
+        // Both branches are unchanged.
+        restore(outcome.version, theirs, &data_tree, &forest, &delta_map);
+        assert_contents(
+            &data_tree,
+            vec![
+                (IVec::from(b"key0"), IVec::from(b"theirs")),
+                (IVec::from(b"key2"), IVec::from(b"value2")),
+            ],
+        );
+    }
+
+    #[test]
+    fn cherry_pick_onto_other_branch() {
+        let fixture = Fixture::open();
+        let (_v0, v1, v2) = fixture.create_three_snapshots();
+
+        let (forest, delta_map) = open_snapshot_forest(&fixture.db, "snaps").unwrap();
+        let data_tree = fixture.db.open_tree("data").unwrap();
+
+        // Branch off of v1, then change key1 so that picking v1 itself conflicts.
+        let branch = (&data_tree, &*forest, &*delta_map)
+            .transaction(|(data_tree, forest, delta_map)| {
+                let forest = TransactionalVersionForest(forest);
+                let delta_map = TransactionalDeltaMap(delta_map);
+                set_current_version(v2, v1, forest, delta_map, data_tree)?;
+                let deltas = [Delta::Insert(IVec::from(b"key1"), IVec::from(b"changed"))];
+                create_child_snapshot_with_deltas(v1, forest, delta_map, data_tree, &deltas)
+            })
+            .unwrap();
+
+        let pick = |version: u64, current_version: u64, resolution: Resolution| {
+            (&data_tree, &*forest, &*delta_map).transaction(|(data_tree, forest, delta_map)| {
+                cherry_pick(
+                    version,
+                    current_version,
+                    TransactionalVersionForest(forest),
+                    TransactionalDeltaMap(delta_map),
+                    data_tree,
+                    |_| resolution.clone(),
+                )
+            })
+        };
+
+        // v2 only added key2, which is untouched on the branch.
+        let outcome = pick(v2, branch, Resolution::Abort).unwrap();
+        assert!(outcome.conflicts.is_empty());
+        assert_contents(
+            &data_tree,
+            vec![
+                (IVec::from(b"key0"), IVec::from(b"value0")),
+                (IVec::from(b"key1"), IVec::from(b"changed")),
+                (IVec::from(b"key2"), IVec::from(b"value2")),
+            ],
+        );
+
+        assert_eq!(
+            pick(v1, outcome.version, Resolution::Abort),
+            Err(TransactionError::Abort(SnapshotError::UnresolvedConflict(
+                IVec::from(b"key1")
+            )))
+        );
+        let outcome = pick(v1, outcome.version, Resolution::Theirs).unwrap();
+        assert_eq!(
+            outcome.conflicts,
+            vec![Conflict {
+                key: IVec::from(b"key1"),
+                base: None,
+                ours: Some(IVec::from(b"changed")),
+                theirs: Some(IVec::from(b"value1")),
+            }]
+        );
+        assert_eq!(data_tree.get(b"key1").unwrap(), Some(IVec::from(b"value1")));
+    }
+
     fn restore(
         current_version: u64,
         target_version: u64,
@@ -561,6 +2046,32 @@ mod test {
         assert_eq!(kvs, expected_kvs);
     }
 
+    /// Counts the writes made to a data tree.
+    struct CountingDataTree<'a> {
+        tree: &'a TransactionalTree,
+        num_writes: Cell<usize>,
+    }
+
+    impl<'a> DataTree for CountingDataTree<'a> {
+        fn get(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+            Ok(self.tree.get(key)?)
+        }
+
+        fn insert(
+            &self,
+            key: &[u8],
+            value: IVec,
+        ) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+            self.num_writes.set(self.num_writes.get() + 1);
+            Ok(self.tree.insert(key, value)?)
+        }
+
+        fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, SnapshotError> {
+            self.num_writes.set(self.num_writes.get() + 1);
+            Ok(self.tree.remove(key)?)
+        }
+    }
+
     struct Fixture {
         pub db: sled::Db,
     }
@@ -1,17 +1,31 @@
 use crate::{
+    branch::{decode_branches, encode_branches, Branch},
     u64_from_be_slice,
+    version_info::{encode_timestamp, VersionInfo},
     version_node::{RawVersionNode, VersionNode, NULL_VERSION},
+    DeltaMap, SnapshotError,
 };
 
 use sled::{
     transaction::{
         abort, ConflictableTransactionResult, TransactionalTree, UnabortableTransactionError,
     },
-    IVec, Tree,
+    Batch, IVec, Tree,
 };
-use std::ops::Deref;
+use std::collections::{BTreeMap, HashSet};
+use std::mem;
+use std::ops::{Bound, Deref};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// A [sled::Tree] that stores a set of versions, each of which is a node in some tree.
+///
+/// Alongside the version nodes, this also stores a pointer from each root version to the current version of its tree, the
+/// [`Branch`]es of each tree and which one is checked out, the name of the data tree of each
+/// [`SnapshotTree`](crate::SnapshotTree), the [`VersionInfo`] of each version, an index of versions by creation time, a skip
+/// pointer from each version to one of its ancestors and marks on the versions that can no longer be restored. Version nodes
+/// are always keyed by the 8 big endian bytes of the version, so the bookkeeping records use other key lengths to stay out of
+/// the way.
+#[derive(Clone)]
 pub struct VersionForest(pub Tree);
 
 impl Deref for VersionForest {
@@ -25,14 +39,252 @@ impl Deref for VersionForest {
 impl VersionForest {
     /// Returns an iterator over all versions in the forest.
     pub fn iter_versions(&self) -> impl Iterator<Item = sled::Result<u64>> {
-        self.iter()
-            .map(|kv_result| kv_result.map(|(k, _v)| u64_from_be_slice(&k)))
+        self.iter().filter_map(|kv_result| match kv_result {
+            Ok((k, _v)) if is_version_key(&k) => Some(Ok(u64_from_be_slice(&k))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Returns an iterator over all versions in the forest that are greater than `version`.
+    pub fn iter_versions_after(&self, version: u64) -> impl Iterator<Item = sled::Result<u64>> {
+        let start = (Bound::Excluded(version.to_be_bytes()), Bound::Unbounded);
+        self.range(start).filter_map(|kv_result| match kv_result {
+            Ok((k, _v)) if is_version_key(&k) => Some(Ok(u64_from_be_slice(&k))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Returns an iterator over all versions in the forest, paired with their encoded [`RawVersionNode`]s.
+    pub(crate) fn iter_version_nodes(&self) -> impl Iterator<Item = sled::Result<(u64, IVec)>> {
+        self.iter().filter_map(|kv_result| match kv_result {
+            Ok((k, v)) if is_version_key(&k) => Some(Ok((u64_from_be_slice(&k), v))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Returns an iterator over the `(root, current_version)` record of every tree in the forest, where the current version is
+    /// `None` if the record is malformed.
+    pub(crate) fn iter_current_versions(
+        &self,
+    ) -> impl Iterator<Item = sled::Result<(u64, Option<u64>)>> {
+        // Version keys can start with the tag byte too, but they are shorter.
+        self.scan_prefix([CURRENT_VERSION_TAG])
+            .filter_map(|kv_result| match kv_result {
+                Ok((k, v)) if k.len() == current_version_key(0).len() => {
+                    let current = if v.len() == mem::size_of::<u64>() {
+                        Some(u64_from_be_slice(&v))
+                    } else {
+                        None
+                    };
+                    Some(Ok((u64_from_be_slice(&k[1..]), current)))
+                }
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
+    }
+
+    /// Returns the name of the data tree versioned by the tree rooted at `root`, or `None` if it was never recorded.
+    pub fn data_tree_name(&self, root: u64) -> sled::Result<Option<IVec>> {
+        self.get(data_tree_key(root))
+    }
+
+    /// Records `name` as the name of the data tree versioned by the tree rooted at `root`.
+    pub(crate) fn set_data_tree_name(&self, root: u64, name: &[u8]) -> sled::Result<()> {
+        self.insert(data_tree_key(root), name)?;
+        Ok(())
+    }
+
+    /// Returns `false` if [`repair_forest`](crate::repair_forest) marked `version` as one that can no longer be restored.
+    pub fn is_restorable(&self, version: u64) -> sled::Result<bool> {
+        Ok(!self.contains_key(unrestorable_key(version))?)
+    }
+
+    /// Marks `version` as one that can no longer be restored.
+    pub(crate) fn mark_unrestorable(&self, version: u64) -> sled::Result<()> {
+        self.insert(unrestorable_key(version), &[])?;
+        Ok(())
+    }
+
+    /// Upgrades a forest written by an older version of this crate to the current [`FORMAT_VERSION`] in one batch, and records
+    /// the format of a new forest. Does nothing if the forest is already up to date.
+    ///
+    /// Before the format was recorded, version nodes didn't store their root, and the current version of each tree was the
+    /// only version without an entry in `delta_map`, so that's what the current version records are built from.
+    pub(crate) fn migrate(&self, delta_map: &DeltaMap) -> sled::Result<()> {
+        match self.get(format_version_key())? {
+            Some(format) if u64_from_be_slice(&format) == FORMAT_VERSION => return Ok(()),
+            Some(format) => {
+                return Err(sled::Error::Unsupported(format!(
+                    "unknown snapshot forest format {}",
+                    u64_from_be_slice(&format)
+                )))
+            }
+            None => (),
+        }
+
+        // The legacy encoding of a version node is `parent`, `num_children` and `children`. Nothing is written unless every node
+        // can be decoded that way.
+        let mut legacy_nodes = BTreeMap::new();
+        for kv in self.iter_version_nodes() {
+            let (version, bytes) = kv?;
+            let header_len = 2 * mem::size_of::<u64>();
+            let is_legacy = bytes.len() >= header_len
+                && (bytes.len() - header_len) as u64
+                    == u64_from_be_slice(&bytes[8..16])
+                        .saturating_mul(mem::size_of::<u64>() as u64);
+            if !is_legacy {
+                return Err(sled::Error::Unsupported(format!(
+                    "version node {} is not in the legacy format",
+                    version
+                )));
+            }
+            let parent = u64_from_be_slice(&bytes[..8]);
+            let children: Vec<u64> = bytes[header_len..]
+                .chunks_exact(mem::size_of::<u64>())
+                .map(u64_from_be_slice)
+                .collect();
+            legacy_nodes.insert(version, (parent, children));
+        }
+
+        let mut batch = Batch::default();
+        for (&version, (parent, children)) in legacy_nodes.iter() {
+            let mut root = version;
+            let mut visited = HashSet::new();
+            while let Some(&(parent, _)) = legacy_nodes.get(&root) {
+                if parent == NULL_VERSION || !visited.insert(root) {
+                    break;
+                }
+                root = parent;
+            }
+            let node = VersionNode {
+                parent: Some(*parent).filter(|&parent| parent != NULL_VERSION),
+                root,
+                children: children.clone(),
+            };
+            batch.insert(&version.to_be_bytes(), &node);
+            if !delta_map.contains_key(version.to_be_bytes())? {
+                batch.insert(&current_version_key(root), &version.to_be_bytes());
+            }
+        }
+        batch.insert(&format_version_key(), &FORMAT_VERSION.to_be_bytes());
+        self.apply_batch(batch)
     }
 
     /// Collects all versions into a `Vec`.
     pub fn collect_versions(&self) -> sled::Result<Vec<u64>> {
         self.iter_versions().collect()
     }
+
+    /// Returns the current version of the tree rooted at `root`, or `None` if `root` is not the root of any tree.
+    pub fn current_version(&self, root: u64) -> sled::Result<Option<u64>> {
+        self.get(current_version_key(root))
+            .map(|result| result.map(|bytes| u64_from_be_slice(&bytes)))
+    }
+
+    /// Returns the branches of the tree rooted at `root`, ordered by name.
+    pub fn list_branches(&self, root: u64) -> sled::Result<Vec<Branch>> {
+        self.get(branches_key(root)).map(|result| {
+            result
+                .map(|bytes| decode_branches(&bytes))
+                .unwrap_or_default()
+        })
+    }
+
+    /// Returns the name of the branch that was last checked out in the tree rooted at `root`, if any.
+    pub fn checked_out_branch(&self, root: u64) -> sled::Result<Option<String>> {
+        self.get(checked_out_branch_key(root))
+            .map(|result| result.map(|name| String::from_utf8_lossy(&name).into_owned()))
+    }
+
+    /// Returns the metadata of `version`, or `None` if `version` does not exist.
+    pub fn version_info(&self, version: u64) -> sled::Result<Option<VersionInfo>> {
+        if let Some(info) = self.get(info_key(version))? {
+            Ok(Some(VersionInfo::decode(&info)))
+        } else if self.get(version.to_be_bytes())?.is_some() {
+            // Versions created before metadata was recorded.
+            Ok(Some(VersionInfo::default()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the latest version created at or before `time` out of `branch_leaf` and its ancestors, or `None` if they were
+    /// all created after `time`. Returns `None` if `branch_leaf` is not in the tree rooted at `root`.
+    ///
+    /// Versions are assumed to be created no earlier than their parents, so the versions created after `time` form an
+    /// unbroken path up from `branch_leaf`. Every version keeps a skip pointer to an ancestor, and the search follows it
+    /// whenever that ancestor was also created after `time`, which takes O(log depth) steps. Versions created before skip
+    /// pointers were recorded are stepped over one parent at a time.
+    pub fn version_at_time(
+        &self,
+        root: u64,
+        time: SystemTime,
+        branch_leaf: u64,
+    ) -> sled::Result<Option<u64>> {
+        let mut node = if let Some(node) = self.get(branch_leaf.to_be_bytes())? {
+            RawVersionNode::new(node)
+        } else {
+            return Ok(None);
+        };
+        if node.root() != root {
+            return Ok(None);
+        }
+        if self.created_at(branch_leaf)? <= time {
+            return Ok(Some(branch_leaf));
+        }
+
+        // Invariant: `version` and all versions below it on the path to `branch_leaf` were created after `time`.
+        let mut version = branch_leaf;
+        while let Some(parent) = node.parent() {
+            let jump = self.skip_pointer(version)?.map(|pointer| pointer.jump);
+            if let Some(jump) = jump.filter(|&jump| jump != version && jump != parent) {
+                if self.created_at(jump)? > time {
+                    if let Some(jump_node) = self.get(jump.to_be_bytes())? {
+                        version = jump;
+                        node = RawVersionNode::new(jump_node);
+                        continue;
+                    }
+                }
+            }
+
+            node = if let Some(parent_node) = self.get(parent.to_be_bytes())? {
+                RawVersionNode::new(parent_node)
+            } else {
+                return Ok(None);
+            };
+            if self.created_at(parent)? <= time {
+                return Ok(Some(parent));
+            }
+            version = parent;
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the creation time of `version`, which is the epoch for versions created before metadata was recorded.
+    fn created_at(&self, version: u64) -> sled::Result<SystemTime> {
+        Ok(self
+            .get(info_key(version))?
+            .map_or(UNIX_EPOCH, |info| VersionInfo::decode(&info).created_at))
+    }
+
+    fn skip_pointer(&self, version: u64) -> sled::Result<Option<SkipPointer>> {
+        Ok(self
+            .get(skip_pointer_key(version))?
+            .and_then(|bytes| SkipPointer::decode(&bytes)))
+    }
+
+    /// Returns the current version of the tree containing `version`, or `None` if `version` does not exist.
+    pub fn current_version_of(&self, version: u64) -> sled::Result<Option<u64>> {
+        if let Some(node) = self.get(version.to_be_bytes())? {
+            self.current_version(RawVersionNode::new(node).root())
+        } else {
+            Ok(None)
+        }
+    }
 }
 
 /// Same as [VersionForest] but used in transactions.
@@ -56,45 +308,126 @@ impl<'a> TransactionalVersionForest<'a> {
             .map(|result| result.map(RawVersionNode::new))
     }
 
+    /// Same as `get_version`, but aborts the transaction if `version` does not exist.
+    pub(crate) fn get_existing_version(
+        &self,
+        version: u64,
+    ) -> ConflictableTransactionResult<RawVersionNode<IVec>, SnapshotError> {
+        if let Some(node) = self.get_version(version)? {
+            Ok(node)
+        } else {
+            abort(SnapshotError::VersionNotFound(version))
+        }
+    }
+
     pub(crate) fn create_version(
         &self,
         parent_version: Option<u64>,
-    ) -> ConflictableTransactionResult<u64> {
+    ) -> ConflictableTransactionResult<u64, SnapshotError> {
         let new_version = self.generate_id()?;
         assert_ne!(new_version, NULL_VERSION);
         let new_version_bytes = new_version.to_be_bytes();
+        let created_at = SystemTime::now();
+        self.set_version_info(new_version, &VersionInfo::new(created_at))?;
 
-        let new_node = VersionNode::new_maybe_with_parent(parent_version);
-        self.insert(&new_version_bytes, &new_node)?;
-
-        if new_node.parent.is_some() {
+        if let Some(parent_version) = parent_version {
             // We also need to add this version as a child in the parent's node.
-            let parent_bytes = new_node.parent_be_bytes();
+            let parent_bytes = parent_version.to_be_bytes();
             if let Some(parent_node_ivec) = self.get(parent_bytes)? {
                 // PERF: can we avoid read-modify-write?
                 let mut parent_node = VersionNode::from(RawVersionNode::new(parent_node_ivec));
                 parent_node.children.push(new_version);
                 self.insert(&parent_bytes, &parent_node)?;
 
+                let new_node = VersionNode::new_with_parent(parent_version, parent_node.root);
+                self.insert(&new_version_bytes, &new_node)?;
+                self.insert(
+                    &time_index_key(parent_node.root, created_at, new_version),
+                    &[],
+                )?;
+                self.add_skip_pointer(new_version, parent_version)?;
+
                 Ok(new_version)
             } else {
                 // Abort so we don't create a dangling pointer in the tree.
-                abort(())
+                abort(SnapshotError::VersionNotFound(parent_version))
             }
         } else {
+            self.insert(&new_version_bytes, &VersionNode::new_orphan(new_version))?;
+            self.insert(&time_index_key(new_version, created_at, new_version), &[])?;
+            self.set_skip_pointer(new_version, &SkipPointer::new_root(new_version))?;
+
             Ok(new_version)
         }
     }
 
+    /// Returns the root version of the tree containing `version`. Aborts the transaction if `version` does not exist.
+    pub fn root_of(&self, version: u64) -> ConflictableTransactionResult<u64, SnapshotError> {
+        Ok(self.get_existing_version(version)?.root())
+    }
+
+    /// Returns the current version of the tree rooted at `root`. Aborts the transaction if `root` is not the root of a tree
+    /// with a current version.
+    pub fn current_version(&self, root: u64) -> ConflictableTransactionResult<u64, SnapshotError> {
+        if let Some(current) = self.get(current_version_key(root))? {
+            Ok(u64_from_be_slice(&current))
+        } else if self.get_version(root)?.is_some() {
+            abort(SnapshotError::NotRootVersion(root))
+        } else {
+            abort(SnapshotError::VersionNotFound(root))
+        }
+    }
+
+    /// Returns the current version of the tree containing `version`. Aborts the transaction if `version` does not exist.
+    pub fn current_version_of(
+        &self,
+        version: u64,
+    ) -> ConflictableTransactionResult<u64, SnapshotError> {
+        self.current_version(self.root_of(version)?)
+    }
+
+    /// Returns `false` if [`repair_forest`](crate::repair_forest) marked `version` as one that can no longer be restored.
+    pub fn is_restorable(&self, version: u64) -> Result<bool, UnabortableTransactionError> {
+        Ok(self.get(unrestorable_key(version))?.is_none())
+    }
+
+    /// Records `name` as the name of the data tree versioned by the tree rooted at `root`.
+    pub(crate) fn set_data_tree_name(
+        &self,
+        root: u64,
+        name: &[u8],
+    ) -> Result<(), UnabortableTransactionError> {
+        self.insert(&data_tree_key(root), name)?;
+        Ok(())
+    }
+
+    /// Records `version` as the current version of the tree rooted at `root`.
+    pub(crate) fn set_current_version(
+        &self,
+        root: u64,
+        version: u64,
+    ) -> Result<(), UnabortableTransactionError> {
+        self.insert(&current_version_key(root), &version.to_be_bytes())?;
+        Ok(())
+    }
+
     /// Deletes `root` version and all versions that have `root` as an ancestor.
     pub(crate) fn delete_tree(
         &self,
         root: u64,
-        mut deleted_version_rx: impl FnMut(u64) -> ConflictableTransactionResult<()>,
-    ) -> ConflictableTransactionResult<()> {
+        mut deleted_version_rx: impl FnMut(u64) -> ConflictableTransactionResult<(), SnapshotError>,
+    ) -> ConflictableTransactionResult<(), SnapshotError> {
+        self.remove(&current_version_key(root))?;
+        self.remove(&branches_key(root))?;
+        self.remove(&checked_out_branch_key(root))?;
+        self.remove(&data_tree_key(root))?;
+
         let mut delete_queue = vec![root];
         while let Some(version) = delete_queue.pop() {
             if let Some(node) = self.remove(&version.to_be_bytes())? {
+                self.remove_version_info(root, version)?;
+                self.remove(&unrestorable_key(version))?;
+                self.remove(&skip_pointer_key(version))?;
                 deleted_version_rx(version)?;
                 let node = RawVersionNode::new(node);
                 delete_queue.extend(node.iter_children());
@@ -113,7 +446,7 @@ impl<'a> TransactionalVersionForest<'a> {
     pub(crate) fn remove_version(
         &self,
         version: u64,
-    ) -> ConflictableTransactionResult<Option<VersionNode>> {
+    ) -> ConflictableTransactionResult<Option<VersionNode>, SnapshotError> {
         // Remove version.
         let rm_node = if let Some(node_ivec) = self.remove(&version.to_be_bytes())? {
             VersionNode::from(RawVersionNode::new(node_ivec))
@@ -124,7 +457,12 @@ impl<'a> TransactionalVersionForest<'a> {
 
         // Cannot delete the root version.
         if rm_node.parent.is_none() {
-            return abort(());
+            return abort(SnapshotError::CannotDeleteRootVersion(version));
+        }
+        self.remove_version_info(rm_node.root, version)?;
+        self.remove(&unrestorable_key(version))?;
+        if let Some(parent) = rm_node.parent {
+            self.remove_skip_pointer(version, parent)?;
         }
 
         // Re-parent the children.
@@ -139,21 +477,244 @@ impl<'a> TransactionalVersionForest<'a> {
         let new_parent_key_bytes = rm_node.parent_be_bytes();
         if let Some(new_parent_node_ivec) = self.get(new_parent_key_bytes)? {
             let mut new_parent_node = VersionNode::from(RawVersionNode::new(new_parent_node_ivec));
+            new_parent_node.children.retain(|&child| child != version);
             for &child in rm_node.children.iter() {
                 new_parent_node.children.push(child);
             }
             self.insert(&new_parent_key_bytes, &new_parent_node)?;
         }
 
+        // Branches can't point to a deleted version.
+        if let Some(parent) = rm_node.parent {
+            self.move_branches(rm_node.root, version, parent)?;
+        }
+
         Ok(Some(rm_node))
     }
 
-    pub fn find_path_to_root(&self, version: u64) -> ConflictableTransactionResult<Vec<u64>> {
-        let mut node = if let Some(node) = self.get_version(version)? {
-            node
+    /// Returns the skip pointer of `version`. Versions without one act like roots, so that new skip pointers never pass them.
+    fn skip_pointer(&self, version: u64) -> Result<SkipPointer, UnabortableTransactionError> {
+        Ok(self
+            .get(skip_pointer_key(version))?
+            .and_then(|bytes| SkipPointer::decode(&bytes))
+            .unwrap_or_else(|| SkipPointer::new_root(version)))
+    }
+
+    fn set_skip_pointer(
+        &self,
+        version: u64,
+        pointer: &SkipPointer,
+    ) -> Result<(), UnabortableTransactionError> {
+        self.insert(&skip_pointer_key(version), pointer.encode())?;
+        Ok(())
+    }
+
+    /// Gives the new child `version` of `parent` a skip pointer. It jumps as far as the parent's pointer and the one after it
+    /// together if those cover the same distance, and to the parent otherwise, so distances grow like skew binary numbers.
+    fn add_skip_pointer(
+        &self,
+        version: u64,
+        parent: u64,
+    ) -> Result<(), UnabortableTransactionError> {
+        let parent_pointer = self.skip_pointer(parent)?;
+        let next_pointer = self.skip_pointer(parent_pointer.jump)?;
+        let (jump, distance) = if parent_pointer.distance == next_pointer.distance {
+            (
+                next_pointer.jump,
+                1 + parent_pointer.distance + next_pointer.distance,
+            )
+        } else {
+            (parent, 1)
+        };
+
+        let mut target = self.skip_pointer(jump)?;
+        target.jumpers.push(version);
+        self.set_skip_pointer(jump, &target)?;
+        self.set_skip_pointer(
+            version,
+            &SkipPointer {
+                jump,
+                distance,
+                jumpers: Vec::new(),
+            },
+        )
+    }
+
+    /// Removes the skip pointer of `version`, which is about to be deleted from under `parent`. The versions that jump to
+    /// `version` jump to where it jumped instead, or to `parent` if it had nowhere to jump.
+    fn remove_skip_pointer(
+        &self,
+        version: u64,
+        parent: u64,
+    ) -> Result<(), UnabortableTransactionError> {
+        let removed = if let Some(bytes) = self.remove(&skip_pointer_key(version))? {
+            SkipPointer::decode(&bytes).unwrap_or_else(|| SkipPointer::new_root(version))
         } else {
-            return abort(());
+            return Ok(());
         };
+        let (jump, distance) = if removed.jump == version {
+            (parent, 1)
+        } else {
+            (removed.jump, removed.distance)
+        };
+
+        let mut target = self.skip_pointer(jump)?;
+        target.jumpers.retain(|&jumper| jumper != version);
+        for &jumper in removed.jumpers.iter() {
+            if let Some(bytes) = self.get(skip_pointer_key(jumper))? {
+                if let Some(mut pointer) = SkipPointer::decode(&bytes) {
+                    pointer.jump = jump;
+                    pointer.distance = (pointer.distance + distance).saturating_sub(1).max(1);
+                    self.set_skip_pointer(jumper, &pointer)?;
+                    target.jumpers.push(jumper);
+                }
+            }
+        }
+        self.set_skip_pointer(jump, &target)
+    }
+
+    /// Returns the metadata of `version`. Aborts the transaction if `version` does not exist.
+    pub fn version_info(
+        &self,
+        version: u64,
+    ) -> ConflictableTransactionResult<VersionInfo, SnapshotError> {
+        if let Some(info) = self.get(info_key(version))? {
+            Ok(VersionInfo::decode(&info))
+        } else {
+            // Versions created before metadata was recorded.
+            self.get_existing_version(version)?;
+            Ok(VersionInfo::default())
+        }
+    }
+
+    pub(crate) fn set_version_info(
+        &self,
+        version: u64,
+        info: &VersionInfo,
+    ) -> Result<(), UnabortableTransactionError> {
+        self.insert(&info_key(version), info.encode())?;
+        Ok(())
+    }
+
+    /// Same as `set_version_info`, but the creation time may change too, so the creation time index is updated.
+    pub(crate) fn replace_version_info(
+        &self,
+        root: u64,
+        version: u64,
+        info: &VersionInfo,
+    ) -> Result<(), UnabortableTransactionError> {
+        self.remove_version_info(root, version)?;
+        self.set_version_info(version, info)?;
+        self.insert(&time_index_key(root, info.created_at, version), &[])?;
+        Ok(())
+    }
+
+    /// Removes the metadata of `version` and its entry in the creation time index.
+    fn remove_version_info(
+        &self,
+        root: u64,
+        version: u64,
+    ) -> Result<(), UnabortableTransactionError> {
+        if let Some(info) = self.remove(&info_key(version))? {
+            let created_at = VersionInfo::decode(&info).created_at;
+            self.remove(&time_index_key(root, created_at, version))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the branches of the tree rooted at `root`, ordered by name.
+    pub fn list_branches(&self, root: u64) -> Result<Vec<Branch>, UnabortableTransactionError> {
+        Ok(self
+            .get(branches_key(root))?
+            .map(|bytes| decode_branches(&bytes))
+            .unwrap_or_default())
+    }
+
+    /// Replaces all branches of the tree rooted at `root`. The branches must already be ordered by name.
+    pub(crate) fn set_branches(
+        &self,
+        root: u64,
+        branches: &[Branch],
+    ) -> Result<(), UnabortableTransactionError> {
+        if branches.is_empty() {
+            self.remove(&branches_key(root))?;
+        } else {
+            self.insert(&branches_key(root), encode_branches(branches))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the branch that was last checked out in the tree rooted at `root`, if any.
+    pub fn checked_out_branch(
+        &self,
+        root: u64,
+    ) -> Result<Option<String>, UnabortableTransactionError> {
+        Ok(self
+            .get(checked_out_branch_key(root))?
+            .map(|name| String::from_utf8_lossy(&name).into_owned()))
+    }
+
+    /// Records `name` as the checked-out branch of the tree rooted at `root`, or forgets it if `name` is `None`.
+    pub(crate) fn set_checked_out_branch(
+        &self,
+        root: u64,
+        name: Option<&str>,
+    ) -> Result<(), UnabortableTransactionError> {
+        if let Some(name) = name {
+            self.insert(&checked_out_branch_key(root), name.as_bytes())?;
+        } else {
+            self.remove(&checked_out_branch_key(root))?;
+        }
+        Ok(())
+    }
+
+    /// Moves the checked-out branch of the tree rooted at `root` over to `to`, if its tip is `from`. Other branches stay put.
+    pub(crate) fn advance_checked_out_branch(
+        &self,
+        root: u64,
+        from: u64,
+        to: u64,
+    ) -> Result<(), UnabortableTransactionError> {
+        let name = if let Some(name) = self.checked_out_branch(root)? {
+            name
+        } else {
+            return Ok(());
+        };
+        let mut branches = self.list_branches(root)?;
+        if let Some(branch) = branches
+            .iter_mut()
+            .find(|b| b.name == name && b.tip == from)
+        {
+            branch.tip = to;
+            self.set_branches(root, &branches)?;
+        }
+        Ok(())
+    }
+
+    /// Moves every branch in the tree rooted at `root` with tip `from` over to `to`.
+    fn move_branches(
+        &self,
+        root: u64,
+        from: u64,
+        to: u64,
+    ) -> Result<(), UnabortableTransactionError> {
+        let mut branches = self.list_branches(root)?;
+        let mut moved = false;
+        for branch in branches.iter_mut().filter(|b| b.tip == from) {
+            branch.tip = to;
+            moved = true;
+        }
+        if moved {
+            self.set_branches(root, &branches)?;
+        }
+        Ok(())
+    }
+
+    pub fn find_path_to_root(
+        &self,
+        version: u64,
+    ) -> ConflictableTransactionResult<Vec<u64>, SnapshotError> {
+        let mut node = self.get_existing_version(version)?;
 
         let mut path = vec![version];
         while let Some(parent_version) = node.parent() {
@@ -170,29 +731,16 @@ impl<'a> TransactionalVersionForest<'a> {
         &self,
         start: u64,
         finish: u64,
-    ) -> ConflictableTransactionResult<VersionPath> {
+    ) -> ConflictableTransactionResult<VersionPath, SnapshotError> {
         let start_to_root = self.find_path_to_root(start)?;
         let mut finish_to_root = self.find_path_to_root(finish)?;
 
-        if start_to_root.last() != finish_to_root.last() {
-            return Ok(VersionPath::NoPathExists);
-        }
-
-        let mut start_join = 0;
-        let mut finish_join = 0;
-        for ((i1, v1), (i2, v2)) in start_to_root
-            .iter()
-            .enumerate()
-            .rev()
-            .zip(finish_to_root.iter().enumerate().rev())
-        {
-            if v1 != v2 {
-                // The previous index held the nearest common ancestor.
-                break;
-            }
-            start_join = i1;
-            finish_join = i2;
-        }
+        let (start_join, finish_join) =
+            if let Some(join) = find_join_indices(&start_to_root, &finish_to_root) {
+                join
+            } else {
+                return Ok(VersionPath::NoPathExists);
+            };
 
         let mut path = start_to_root[..=start_join].to_vec();
         let further_slice = &mut finish_to_root[..finish_join];
@@ -201,20 +749,28 @@ impl<'a> TransactionalVersionForest<'a> {
         Ok(VersionPath::PathExists(path))
     }
 
-    pub fn is_leaf(&self, version: u64) -> ConflictableTransactionResult<bool> {
-        if let Some(node) = self.get_version(version)? {
-            Ok(node.num_children() == 0)
-        } else {
-            abort(())
-        }
+    /// Returns the nearest version that is an ancestor of (or equal to) both `v1` and `v2`, or `None` if they belong to
+    /// different trees.
+    pub fn find_nearest_common_ancestor(
+        &self,
+        v1: u64,
+        v2: u64,
+    ) -> ConflictableTransactionResult<Option<u64>, SnapshotError> {
+        let v1_to_root = self.find_path_to_root(v1)?;
+        let v2_to_root = self.find_path_to_root(v2)?;
+
+        Ok(find_join_indices(&v1_to_root, &v2_to_root).map(|(i1, _i2)| v1_to_root[i1]))
     }
 
-    pub fn parent_of(&self, version: u64) -> ConflictableTransactionResult<Option<u64>> {
-        if let Some(node) = self.get_version(version)? {
-            Ok(node.parent())
-        } else {
-            abort(())
-        }
+    pub fn is_leaf(&self, version: u64) -> ConflictableTransactionResult<bool, SnapshotError> {
+        Ok(self.get_existing_version(version)?.num_children() == 0)
+    }
+
+    pub fn parent_of(
+        &self,
+        version: u64,
+    ) -> ConflictableTransactionResult<Option<u64>, SnapshotError> {
+        Ok(self.get_existing_version(version)?.parent())
     }
 }
 
@@ -224,6 +780,158 @@ pub enum VersionPath {
     NoPathExists,
 }
 
+/// Given two paths to the root, returns the indices of the nearest common ancestor in each path, or `None` if the roots differ.
+fn find_join_indices(start_to_root: &[u64], finish_to_root: &[u64]) -> Option<(usize, usize)> {
+    if start_to_root.last() != finish_to_root.last() {
+        return None;
+    }
+
+    let mut start_join = 0;
+    let mut finish_join = 0;
+    for ((i1, v1), (i2, v2)) in start_to_root
+        .iter()
+        .enumerate()
+        .rev()
+        .zip(finish_to_root.iter().enumerate().rev())
+    {
+        if v1 != v2 {
+            // The previous index held the nearest common ancestor.
+            break;
+        }
+        start_join = i1;
+        finish_join = i2;
+    }
+
+    Some((start_join, finish_join))
+}
+
+/// Tags the keys of the root -> current version records.
+const CURRENT_VERSION_TAG: u8 = 0;
+
+pub(crate) fn current_version_key(root: u64) -> [u8; 9] {
+    let mut key = [CURRENT_VERSION_TAG; 9];
+    key[1..].copy_from_slice(&root.to_be_bytes());
+    key
+}
+
+const BRANCHES_TAG: u8 = 1;
+
+fn branches_key(root: u64) -> [u8; 9] {
+    let mut key = [BRANCHES_TAG; 9];
+    key[1..].copy_from_slice(&root.to_be_bytes());
+    key
+}
+
+const INFO_TAG: u8 = 2;
+
+fn info_key(version: u64) -> [u8; 9] {
+    let mut key = [INFO_TAG; 9];
+    key[1..].copy_from_slice(&version.to_be_bytes());
+    key
+}
+
+const TIME_INDEX_TAG: u8 = 3;
+
+/// Sorts the versions of each tree by creation time, using the version to break ties.
+fn time_index_key(root: u64, created_at: SystemTime, version: u64) -> [u8; 25] {
+    let mut key = [TIME_INDEX_TAG; 25];
+    key[1..9].copy_from_slice(&root.to_be_bytes());
+    key[9..17].copy_from_slice(&encode_timestamp(created_at));
+    key[17..].copy_from_slice(&version.to_be_bytes());
+    key
+}
+
+const UNRESTORABLE_TAG: u8 = 4;
+
+fn unrestorable_key(version: u64) -> [u8; 9] {
+    let mut key = [UNRESTORABLE_TAG; 9];
+    key[1..].copy_from_slice(&version.to_be_bytes());
+    key
+}
+
+/// The version of the encoding of version nodes and bookkeeping records. Forests that don't record it are upgraded by
+/// [`open_snapshot_forest`](crate::open_snapshot_forest).
+pub(crate) const FORMAT_VERSION: u64 = 1;
+
+const FORMAT_VERSION_TAG: u8 = 5;
+
+const DATA_TREE_TAG: u8 = 6;
+
+fn data_tree_key(root: u64) -> [u8; 9] {
+    let mut key = [DATA_TREE_TAG; 9];
+    key[1..].copy_from_slice(&root.to_be_bytes());
+    key
+}
+
+const CHECKED_OUT_BRANCH_TAG: u8 = 7;
+
+fn checked_out_branch_key(root: u64) -> [u8; 9] {
+    let mut key = [CHECKED_OUT_BRANCH_TAG; 9];
+    key[1..].copy_from_slice(&root.to_be_bytes());
+    key
+}
+
+const SKIP_POINTER_TAG: u8 = 8;
+
+fn skip_pointer_key(version: u64) -> [u8; 9] {
+    let mut key = [SKIP_POINTER_TAG; 9];
+    key[1..].copy_from_slice(&version.to_be_bytes());
+    key
+}
+
+/// A pointer from a version to one of its ancestors, used by [`VersionForest::version_at_time`] to skip up the tree.
+///
+/// Encoded as the `jump` target, the `distance` to it in versions, and then the versions that jump here, so they can be
+/// pointed elsewhere when this version is deleted. A root jumps to itself.
+struct SkipPointer {
+    jump: u64,
+    distance: u64,
+    jumpers: Vec<u64>,
+}
+
+impl SkipPointer {
+    fn new_root(version: u64) -> Self {
+        Self {
+            jump: version,
+            distance: 0,
+            jumpers: Vec::new(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((2 + self.jumpers.len()) * mem::size_of::<u64>());
+        bytes.extend_from_slice(&self.jump.to_be_bytes());
+        bytes.extend_from_slice(&self.distance.to_be_bytes());
+        for jumper in self.jumpers.iter() {
+            bytes.extend_from_slice(&jumper.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Returns `None` if `bytes` is too short to hold a pointer.
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 2 * mem::size_of::<u64>() {
+            return None;
+        }
+        Some(Self {
+            jump: u64_from_be_slice(&bytes[..8]),
+            distance: u64_from_be_slice(&bytes[8..16]),
+            jumpers: bytes[16..]
+                .chunks_exact(mem::size_of::<u64>())
+                .map(u64_from_be_slice)
+                .collect(),
+        })
+    }
+}
+
+fn format_version_key() -> [u8; 1] {
+    [FORMAT_VERSION_TAG]
+}
+
+fn is_version_key(key: &[u8]) -> bool {
+    key.len() == mem::size_of::<u64>()
+}
+
 // ████████╗███████╗███████╗████████╗
 // ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
 //    ██║   █████╗  ███████╗   ██║
@@ -234,6 +942,8 @@ pub enum VersionPath {
 mod test {
     use super::*;
 
+    use crate::{check_forest, delta_node::HeadDeltaNode};
+
     use sled::transaction::TransactionError;
 
     #[test]
@@ -244,9 +954,60 @@ mod test {
         let result = vtree.transaction(|t| {
             let forest = TransactionalVersionForest(t);
             let root = forest.create_version(None)?;
-            forest.remove_version(root)
+            forest.remove_version(root).map(|_| root)
         });
-        assert!(matches!(result, Err(TransactionError::Abort(()))));
+        assert!(matches!(
+            result,
+            Err(TransactionError::Abort(
+                SnapshotError::CannotDeleteRootVersion(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn remove_version_reparents_children() {
+        let fixture = Fixture::open();
+        let vtree = fixture.open_version_forest();
+
+        vtree
+            .transaction(|t| {
+                let t = TransactionalVersionForest(t);
+                let root = t.create_version(None)?;
+                let c1 = t.create_version(Some(root))?;
+                let c2 = t.create_version(Some(c1))?;
+                let c3 = t.create_version(Some(c1))?;
+
+                t.remove_version(c1)?;
+
+                let root_node = t.get_version(root)?.unwrap();
+                assert_eq!(root_node.iter_children().collect::<Vec<_>>(), vec![c2, c3]);
+                assert_eq!(t.parent_of(c2)?, Some(root));
+                assert_eq!(t.parent_of(c3)?, Some(root));
+
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn remove_only_child_leaves_parent_a_leaf() {
+        let fixture = Fixture::open();
+        let vtree = fixture.open_version_forest();
+
+        vtree
+            .transaction(|t| {
+                let t = TransactionalVersionForest(t);
+                let root = t.create_version(None)?;
+                let c1 = t.create_version(Some(root))?;
+
+                t.remove_version(c1)?;
+
+                assert!(t.is_leaf(root)?);
+                assert_eq!(t.get_version(root)?.unwrap().num_children(), 0);
+
+                Ok(())
+            })
+            .unwrap();
     }
 
     #[test]
@@ -263,7 +1024,10 @@ mod test {
             Ok(())
         });
 
-        assert_eq!(result, Err(TransactionError::Abort(())));
+        assert_eq!(
+            result,
+            Err(TransactionError::Abort(SnapshotError::VersionNotFound(666)))
+        );
     }
 
     #[test]
@@ -280,7 +1044,10 @@ mod test {
             Ok(())
         });
 
-        assert_eq!(result, Err(TransactionError::Abort(())));
+        assert_eq!(
+            result,
+            Err(TransactionError::Abort(SnapshotError::VersionNotFound(666)))
+        );
     }
 
     #[test]
@@ -388,6 +1155,49 @@ mod test {
             .unwrap();
     }
 
+    #[test]
+    fn migrate_legacy_forest() {
+        let fixture = Fixture::open();
+        let forest = fixture.open_version_forest();
+        let delta_map = DeltaMap(fixture.db.open_tree("deltas").unwrap());
+
+        let legacy_node = |parent: u64, children: &[u64]| {
+            let mut bytes = parent.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&(children.len() as u64).to_be_bytes());
+            for child in children {
+                bytes.extend_from_slice(&child.to_be_bytes());
+            }
+            bytes
+        };
+        let (root, v1, v2) = (1u64, 2, 3);
+        forest
+            .insert(root.to_be_bytes(), legacy_node(NULL_VERSION, &[v1, v2]))
+            .unwrap();
+        forest
+            .insert(v1.to_be_bytes(), legacy_node(root, &[]))
+            .unwrap();
+        forest
+            .insert(v2.to_be_bytes(), legacy_node(root, &[]))
+            .unwrap();
+        for version in [root, v1] {
+            delta_map
+                .insert(
+                    version.to_be_bytes(),
+                    IVec::from(&HeadDeltaNode::new_empty()),
+                )
+                .unwrap();
+        }
+
+        forest.migrate(&delta_map).unwrap();
+        assert_eq!(forest.current_version(root), Ok(Some(v2)));
+        assert_eq!(forest.current_version_of(v1), Ok(Some(v2)));
+        assert!(check_forest(&forest, &delta_map).unwrap().is_consistent());
+
+        // The forest is only migrated once.
+        forest.migrate(&delta_map).unwrap();
+        assert!(check_forest(&forest, &delta_map).unwrap().is_consistent());
+    }
+
     struct Fixture {
         pub db: sled::Db,
     }
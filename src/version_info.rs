@@ -0,0 +1,147 @@
+use crate::u64_from_be_slice;
+
+use sled::IVec;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Metadata about a single version.
+///
+/// The creation time is recorded automatically. The rest can be filled in with
+/// [`set_version_metadata`](crate::transactions::set_version_metadata), usually in the same transaction that creates the
+/// version.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VersionInfo {
+    pub created_at: SystemTime,
+    pub author: String,
+    pub message: String,
+    /// Arbitrary bytes for the application to interpret.
+    pub user_data: Vec<u8>,
+}
+
+impl Default for VersionInfo {
+    fn default() -> Self {
+        Self {
+            created_at: UNIX_EPOCH,
+            author: String::new(),
+            message: String::new(),
+            user_data: Vec::new(),
+        }
+    }
+}
+
+impl VersionInfo {
+    pub(crate) fn new(created_at: SystemTime) -> Self {
+        Self {
+            created_at,
+            ..Default::default()
+        }
+    }
+
+    /// The creation time is encoded as nanoseconds since the UNIX epoch, followed by each of the other fields as a length in
+    /// bytes and then the bytes.
+    pub(crate) fn encode(&self) -> IVec {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&encode_timestamp(self.created_at));
+        for field in [
+            self.author.as_bytes(),
+            self.message.as_bytes(),
+            &self.user_data,
+        ] {
+            bytes.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        IVec::from(bytes)
+    }
+
+    /// Corrupt metadata must not hide the rest of the history, so decoding never fails. Invalid UTF-8 is replaced, a field
+    /// whose length runs past the end is cut short, and fields missing from a truncated record get their default values.
+    pub(crate) fn decode(bytes: &[u8]) -> Self {
+        let (created_at, mut rest) = if bytes.len() >= 8 {
+            (decode_timestamp(&bytes[..8]), &bytes[8..])
+        } else {
+            (UNIX_EPOCH, &[][..])
+        };
+        let mut next_field = || {
+            if rest.len() < 8 {
+                rest = &[];
+                return Vec::new();
+            }
+            let len = (u64_from_be_slice(&rest[..8]) as usize).min(rest.len() - 8);
+            let field = rest[8..8 + len].to_vec();
+            rest = &rest[8 + len..];
+            field
+        };
+        let author = String::from_utf8_lossy(&next_field()).into_owned();
+        let message = String::from_utf8_lossy(&next_field()).into_owned();
+        let user_data = next_field();
+
+        Self {
+            created_at,
+            author,
+            message,
+            user_data,
+        }
+    }
+}
+
+/// Times before the UNIX epoch are clamped to the epoch.
+pub(crate) fn encode_timestamp(time: SystemTime) -> [u8; 8] {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    nanos.to_be_bytes()
+}
+
+pub(crate) fn decode_timestamp(bytes: &[u8]) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(u64_from_be_slice(bytes))
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_replaces_invalid_utf8() {
+        let info = VersionInfo {
+            created_at: UNIX_EPOCH + Duration::from_secs(1),
+            author: "alice".to_owned(),
+            message: "hi".to_owned(),
+            user_data: vec![0xff],
+        };
+        let mut bytes = info.encode().to_vec();
+        // The first byte of the author.
+        bytes[16] = 0xff;
+
+        let decoded = VersionInfo::decode(&bytes);
+        assert_eq!(decoded.author, "\u{fffd}lice");
+        assert_eq!(decoded.message, "hi");
+        assert_eq!(decoded.user_data, vec![0xff]);
+        assert_eq!(decoded.created_at, info.created_at);
+    }
+
+    #[test]
+    fn decode_survives_truncation() {
+        let info = VersionInfo {
+            created_at: UNIX_EPOCH + Duration::from_secs(1),
+            author: "alice".to_owned(),
+            message: "hi".to_owned(),
+            user_data: vec![1, 2],
+        };
+        let bytes = info.encode();
+
+        let decoded = VersionInfo::decode(&bytes[..bytes.len() - 1]);
+        assert_eq!(decoded.message, "hi");
+        assert_eq!(decoded.user_data, vec![1]);
+        let decoded = VersionInfo::decode(&bytes[..18]);
+        assert_eq!(decoded.created_at, info.created_at);
+        assert_eq!(decoded.author, "al");
+        assert_eq!(decoded.message, "");
+        assert_eq!(VersionInfo::decode(&bytes[..5]), VersionInfo::default());
+    }
+}
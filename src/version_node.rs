@@ -7,36 +7,36 @@ use std::ops::{Deref, Range};
 
 pub struct VersionNode {
     pub parent: Option<u64>,
+    pub root: u64,
     pub children: Vec<u64>,
 }
 
 impl VersionNode {
-    pub fn new_orphan() -> Self {
+    /// `version` is the ID of the new node, which is also the root of its own tree.
+    pub fn new_orphan(version: u64) -> Self {
+        assert_ne!(version, NULL_VERSION);
+
         Self {
             parent: None,
+            root: version,
             children: Vec::new(),
         }
     }
 
-    pub fn new_with_parent(parent: u64) -> Self {
+    pub fn new_with_parent(parent: u64, root: u64) -> Self {
         assert_ne!(parent, NULL_VERSION);
+        assert_ne!(root, NULL_VERSION);
 
         Self {
             parent: Some(parent),
+            root,
             children: Vec::new(),
         }
     }
 
-    pub fn new_maybe_with_parent(parent: Option<u64>) -> Self {
-        if let Some(parent) = parent {
-            Self::new_with_parent(parent)
-        } else {
-            Self::new_orphan()
-        }
-    }
-
     pub fn encode(&self, writer: &mut impl io::Write) -> io::Result<()> {
         self.encode_parent(writer)?;
+        writer.write_all(&self.root.to_be_bytes())?;
         self.encode_children(writer)
     }
 
@@ -57,7 +57,7 @@ impl VersionNode {
     }
 
     pub fn encoded_size(&self) -> usize {
-        mem::size_of::<u64>() * (2 + self.children.len())
+        mem::size_of::<u64>() * (3 + self.children.len())
     }
 }
 
@@ -76,6 +76,7 @@ where
     fn from(raw_node: RawVersionNode<B>) -> Self {
         Self {
             parent: raw_node.parent(),
+            root: raw_node.root(),
             children: raw_node.iter_children().collect(),
         }
     }
@@ -86,10 +87,12 @@ where
 /// The on-disk encoding is:
 ///
 /// 0. `parent`: `8` bytes (big endian u64)
-/// 1. `num_children`: `8` bytes (big endian u64)
-/// 2. `children`: `num_children * 8` bytes (sequence of big endian u64)
+/// 1. `root`: `8` bytes (big endian u64)
+/// 2. `num_children`: `8` bytes (big endian u64)
+/// 3. `children`: `num_children * 8` bytes (sequence of big endian u64)
 ///
-/// `parent == NULL_VERSION` means the snapshot is an orphan, i.e. it is the first version in this tree.
+/// `parent == NULL_VERSION` means the snapshot is an orphan, i.e. it is the first version in this tree. An orphan is its own
+/// root.
 #[derive(Clone)]
 pub struct RawVersionNode<B> {
     bytes: B,
@@ -113,6 +116,11 @@ where
         }
     }
 
+    /// The root version of the tree containing this snapshot.
+    pub fn root(&self) -> u64 {
+        u64_from_be_slice(&self.bytes[root_range()])
+    }
+
     /// Needs to be a `usize` for use as an index.
     ///
     /// # Panics
@@ -132,6 +140,18 @@ where
         0..self.children_range().end
     }
 
+    /// Returns `true` if the bytes hold exactly one version node, i.e. the node can be decoded without panicking.
+    pub fn is_well_formed(&self) -> bool {
+        if self.bytes.len() < num_children_range().end {
+            return false;
+        }
+        let num_children = u64_from_be_slice(&self.bytes[num_children_range()]);
+        num_children
+            .checked_mul(mem::size_of::<u64>() as u64)
+            .and_then(|len| len.checked_add(num_children_range().end as u64))
+            == Some(self.bytes.len() as u64)
+    }
+
     fn children_range(&self) -> Range<usize> {
         let start = num_children_range().end;
         start..start + self.num_children() * mem::size_of::<u64>()
@@ -142,10 +162,15 @@ const fn parent_range() -> Range<usize> {
     0..mem::size_of::<u64>()
 }
 
-const fn num_children_range() -> Range<usize> {
+const fn root_range() -> Range<usize> {
     let start = parent_range().end;
     start..start + mem::size_of::<u64>()
 }
 
+const fn num_children_range() -> Range<usize> {
+    let start = root_range().end;
+    start..start + mem::size_of::<u64>()
+}
+
 /// A version that's never valid because it has a special purpose internally.
 pub const NULL_VERSION: u64 = u64::MAX;